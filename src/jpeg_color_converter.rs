@@ -3,63 +3,470 @@
 //
 //========================================================
 
-pub type ColorConvertFunc = fn(i16, i16, i16) -> (u8, u8, u8);
+use crate::jpeg_outbuffer_info::JpegPixelFormat;
+use crate::jpeg_simd;
 
-// No color conversion (pass-through)
+// 4x4 Bayer matrix for ordered dithering
+const DITHER_MATRIX_4X4: [[u8; 4]; 4] =
+[
+    [  0,  8,  2, 10 ],
+    [ 12,  4, 14,  6 ],
+    [  3, 11,  1,  9 ],
+    [ 15,  7, 13,  5 ],
+];
+
+// Fixed-point precision of the integer conversion
+pub(crate) const JPEG_COLOR_SCALE_BITS: i32 = 16;
+pub(crate) const JPEG_COLOR_ONE_HALF: i32 = 1 << (JPEG_COLOR_SCALE_BITS - 1);
+
+// YCbCr -> RGB matrix (ITU-R recommendations)
 #[allow(dead_code)]
-pub fn pass_through_components(y: i16, cb: i16, cr: i16) -> (u8, u8, u8)
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegColorMatrix
 {
-    (y as u8, cb as u8, cr as u8)
+    Bt601,      // JFIF, SD video
+    Bt709,      // HD video
+    Bt2020,     // UHD video
 }
 
-// YCbCr -> RGB : CCITT T.81 recommendation
-//
-// R = Y + 1.402 * (Cr - 128)
-// G = Y - 0.34414 * (Cb - 128) - 0.71414 * (Cr - 128)
-// B = Y + 1.772 * (Cb - 128)
+// Range of the YCbCr samples
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegColorRange
+{
+    Full,       // 0-255 (JFIF)
+    Limited,    // Y: 16-235, Cb/Cr: 16-240 (video)
+}
+
+// Arithmetic used for the conversion
 #[allow(dead_code)]
-pub fn ycbcr_to_rgb(y: i16, cb: i16, cr: i16) -> (u8, u8, u8)
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegColorPrecision
 {
-    let ly = (y as i32) << 16;
-    let lcb = ((cb as i32) << 8) - 0x8000;
-    let lcr = ((cr as i32) << 8) - 0x8000;
+    Integer,    // 16-bit fixed point
+    Float,
+}
 
-    let mut r = ly + 359 * lcr;
-    let mut g = ly - 88 * lcb - 183 * lcr;
-    let mut b = ly + 454 * lcb;
+// Conversion of a row of component samples into RGB triplets
+//
+// `components` has a row for each component (Y, Cb, Cr; only Y for grayscale images)
+// upsampled to the full resolution, and `out` has 3 bytes per pixel for the same width.
+// The result is packed into the output pixel format afterwards (Gray8 takes the first channel).
+pub trait JpegColorConvert: Send + Sync
+{
+    fn convert_row(&self, components: &[&[u8]], out: &mut [u8]);
+}
 
-    r = r.clamp(0, 0xFFFFFF);
-    g = g.clamp(0, 0xFFFFFF);
-    b = b.clamp(0, 0xFFFFFF);
-       
-    ((r >> 16) as u8, (g >> 16) as u8, (b >> 16) as u8)
+// Color conversion options
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct JpegColorConversion
+{
+    matrix: JpegColorMatrix,
+    range: JpegColorRange,
+    precision: JpegColorPrecision,
 }
 
-// YCbCr -> RGB : ITU-R BT.601 precise FP ver
+// YCbCr -> RGB converter with precomputed coefficients
 //
-// R = Y + 1.402   * (Cr - 128)
-// G = Y - 0.344136 * (Cb - 128) - 0.714136 * (Cr - 128)
-// B = Y + 1.772   * (Cb - 128)
-#[allow(dead_code)]
-pub fn ycbcr_to_rgb_bt601_fp(y: i16, cb: i16, cr: i16) -> (u8, u8, u8)
+// R = Ys + Kcr_r * (Cr - 128)
+// G = Ys - Kcb_g * (Cb - 128) - Kcr_g * (Cr - 128)
+// B = Ys + Kcb_b * (Cb - 128)
+//
+// where Ys = Ky * (Y - Yoffset), and the coefficients are scaled by
+// 255/219 (Y) and 255/224 (Cb/Cr) for the limited range.
+pub struct JpegYCbCrConverter
 {
-    let fy = y as f32;
-    let fcb = cb as f32;
-    let fcr = cr as f32;
+    precision: JpegColorPrecision,
+    y_offset: i32,
+    coef: [f32; 5],
+    coef_fixed: [i32; 5],
+}
 
-    let r = fy + 1.402 * (fcr - 128.0);
-    let g = fy - 0.344136 * (fcb - 128.0) - 0.714136 * (fcr - 128.0);
-    let b = fy + 1.772 * (fcb - 128.0);
-        
-    (r as u8, g as u8, b as u8)
+// Luminance only (gray triplets) with the range of the YCbCr conversion
+pub struct JpegGrayConverter
+{
+    ycc: JpegYCbCrConverter,
 }
 
-// For limited range
+// No color conversion (component samples are output as they are)
 //
-// Y_limited = Y * 219 + 16
-// Cb_limited = Cb * 224 + 128
-// Cr_limited = Cr * 224 + 128
+// Useful for the JPEG files coded in RGB, or to get Y/Cb/Cr triplets.
+#[allow(dead_code)]
+pub struct JpegPassThroughConverter;
+
+// Per-pixel conversion by a function or closure
+pub struct JpegPixelConverter<F>
+    where F: Fn(u8, u8, u8) -> (u8, u8, u8) + Send + Sync
+{
+    func: F,
+}
+
+#[allow(dead_code)]
+impl JpegColorMatrix
+{
+    // Coefficients (Kcr_r, Kcb_g, Kcr_g, Kcb_b) derived from Kr/Kb of each recommendation
+    fn get_coefficients(&self) -> (f32, f32, f32, f32)
+    {
+        match self
+        {
+            JpegColorMatrix::Bt601 => (1.402, 0.34414, 0.71414, 1.772),
+            JpegColorMatrix::Bt709 => (1.5748, 0.18732, 0.46812, 1.8556),
+            JpegColorMatrix::Bt2020 => (1.4746, 0.16455, 0.57135, 1.8814),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl JpegColorConversion
+{
+    // Constructor (BT.601 full range as defined by JFIF)
+    pub fn new() -> Self
+    {
+        JpegColorConversion
+        {
+            matrix: JpegColorMatrix::Bt601,
+            range: JpegColorRange::Full,
+            precision: JpegColorPrecision::Integer,
+        }
+    }
+
+    pub fn set_matrix(&mut self, matrix: JpegColorMatrix)
+    {
+        self.matrix = matrix;
+    }
+
+    pub fn set_range(&mut self, range: JpegColorRange)
+    {
+        self.range = range;
+    }
+
+    pub fn set_precision(&mut self, precision: JpegColorPrecision)
+    {
+        self.precision = precision;
+    }
+
+    pub fn get_matrix(&self) -> JpegColorMatrix
+    {
+        self.matrix
+    }
+
+    pub fn get_range(&self) -> JpegColorRange
+    {
+        self.range
+    }
+
+    pub fn get_precision(&self) -> JpegColorPrecision
+    {
+        self.precision
+    }
+}
+
+#[allow(dead_code)]
+impl JpegYCbCrConverter
+{
+    // Constructor
+    pub fn new(conversion: &JpegColorConversion) -> Self
+    {
+        let (cr_r, cb_g, cr_g, cb_b) = conversion.get_matrix().get_coefficients();
+        let (y_scale, c_scale, y_offset) = match conversion.get_range()
+        {
+            JpegColorRange::Full => (1.0, 1.0, 0),
+            JpegColorRange::Limited => (255.0 / 219.0, 255.0 / 224.0, 16),
+        };
+        let coef = [y_scale, cr_r * c_scale, cb_g * c_scale, cr_g * c_scale, cb_b * c_scale];
+
+        JpegYCbCrConverter
+        {
+            precision: conversion.get_precision(),
+            y_offset,
+            coef,
+            coef_fixed: coef.map(|c| (c * (1 << JPEG_COLOR_SCALE_BITS) as f32 + 0.5) as i32),
+        }
+    }
+
+    // Integer version (rounded and clamped)
+    fn convert_int(&self, y: u8, cb: u8, cr: u8) -> (u8, u8, u8)
+    {
+        let k = &self.coef_fixed;
+        let ly = k[0] * (y as i32 - self.y_offset) + JPEG_COLOR_ONE_HALF;
+        let lcb = cb as i32 - 128;
+        let lcr = cr as i32 - 128;
 
+        let r = (ly + k[1] * lcr) >> JPEG_COLOR_SCALE_BITS;
+        let g = (ly - k[2] * lcb - k[3] * lcr) >> JPEG_COLOR_SCALE_BITS;
+        let b = (ly + k[4] * lcb) >> JPEG_COLOR_SCALE_BITS;
 
+        (r.clamp(0, 255) as u8, g.clamp(0, 255) as u8, b.clamp(0, 255) as u8)
+    }
+
+    // Floating point version (rounded and clamped)
+    fn convert_float(&self, y: u8, cb: u8, cr: u8) -> (u8, u8, u8)
+    {
+        let k = &self.coef;
+        let fy = k[0] * (y as i32 - self.y_offset) as f32;
+        let fcb = cb as f32 - 128.0;
+        let fcr = cr as f32 - 128.0;
+
+        let r = fy + k[1] * fcr;
+        let g = fy - k[2] * fcb - k[3] * fcr;
+        let b = fy + k[4] * fcb;
+
+        (Self::round_and_clamp(r), Self::round_and_clamp(g), Self::round_and_clamp(b))
+    }
+
+    fn round_and_clamp(val: f32) -> u8
+    {
+        val.round().clamp(0.0, 255.0) as u8
+    }
+
+    // Converts a row of Y/Cb/Cr samples into RGB triplets
+    //
+    // The integer version converts with SIMD as far as possible and finishes the rest.
+    pub fn convert_row_ycc(&self, y: &[u8], cb: &[u8], cr: &[u8], out: &mut [u8])
+    {
+        match self.precision
+        {
+            JpegColorPrecision::Integer =>
+            {
+                let x = jpeg_simd::ycc_to_rgb_row(&self.coef_fixed, self.y_offset, y, cb, cr, out);
+                let iter_ycc = y[x..].iter().zip(cb[x..].iter().zip(&cr[x..]));
+                for (d, (y, (cb, cr))) in out[x * 3..].chunks_exact_mut(3).zip(iter_ycc)
+                {
+                    (d[0], d[1], d[2]) = self.convert_int(*y, *cb, *cr);
+                }
+            },
+            JpegColorPrecision::Float =>
+                for (d, (y, (cb, cr))) in out.chunks_exact_mut(3).zip(y.iter().zip(cb.iter().zip(cr)))
+                {
+                    (d[0], d[1], d[2]) = self.convert_float(*y, *cb, *cr);
+                },
+        }
+    }
+
+    // Converts a row of Y samples into gray triplets (luminance only)
+    pub fn convert_row_gray(&self, y: &[u8], out: &mut [u8])
+    {
+        for (d, y) in out.chunks_exact_mut(3).zip(y)
+        {
+            let (l, _, _) = match self.precision
+            {
+                JpegColorPrecision::Integer => self.convert_int(*y, 128, 128),
+                JpegColorPrecision::Float => self.convert_float(*y, 128, 128),
+            };
+            d.fill(l);
+        }
+    }
+}
+
+impl JpegColorConvert for JpegYCbCrConverter
+{
+    fn convert_row(&self, components: &[&[u8]], out: &mut [u8])
+    {
+        match components
+        {
+            [y, cb, cr, ..] => self.convert_row_ycc(y, cb, cr, out),
+            [y, ..] => self.convert_row_gray(y, out),
+            [] => (),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl JpegGrayConverter
+{
+    // Constructor
+    pub fn new(conversion: &JpegColorConversion) -> Self
+    {
+        JpegGrayConverter
+        {
+            ycc: JpegYCbCrConverter::new(conversion),
+        }
+    }
+}
+
+impl JpegColorConvert for JpegGrayConverter
+{
+    fn convert_row(&self, components: &[&[u8]], out: &mut [u8])
+    {
+        if let Some(y) = components.first()
+        {
+            self.ycc.convert_row_gray(y, out);
+        }
+    }
+}
+
+impl JpegColorConvert for JpegPassThroughConverter
+{
+    fn convert_row(&self, components: &[&[u8]], out: &mut [u8])
+    {
+        for (c, row) in components.iter().take(3).enumerate()
+        {
+            for (d, s) in out.chunks_exact_mut(3).zip(row.iter())
+            {
+                d[c] = *s;
+            }
+        }
+        // A grayscale image gives gray triplets.
+        if components.len() == 1
+        {
+            for d in out.chunks_exact_mut(3)
+            {
+                (d[1], d[2]) = (d[0], d[0]);
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<F> JpegPixelConverter<F>
+    where F: Fn(u8, u8, u8) -> (u8, u8, u8) + Send + Sync
+{
+    // Constructor
+    pub fn new(func: F) -> Self
+    {
+        JpegPixelConverter
+        {
+            func,
+        }
+    }
+}
+
+impl<F> JpegColorConvert for JpegPixelConverter<F>
+    where F: Fn(u8, u8, u8) -> (u8, u8, u8) + Send + Sync
+{
+    fn convert_row(&self, components: &[&[u8]], out: &mut [u8])
+    {
+        match components
+        {
+            [y, cb, cr, ..] =>
+                for (d, (y, (cb, cr))) in out.chunks_exact_mut(3).zip(y.iter().zip(cb.iter().zip(cr.iter())))
+                {
+                    (d[0], d[1], d[2]) = (self.func)(*y, *cb, *cr);
+                },
+            [y, ..] =>
+                for (d, y) in out.chunks_exact_mut(3).zip(y.iter())
+                {
+                    (d[0], d[1], d[2]) = (self.func)(*y, 128, 128);
+                },
+            [] => (),
+        }
+    }
+}
+
+// Packs a row of RGB triplets into the output pixel format
+//
+// `x0`/`y` are the position of the row in the image, used for ordered dithering.
+// (Gray8 takes the first channel, as the converter has put the luminance in all channels.)
+pub fn pack_pixels(
+    format: JpegPixelFormat,
+    dithering: bool,
+    src: &[u8],
+    dst: &mut [u8],
+    x0: usize,
+    y: usize
+)
+{
+    let bpp = format.get_bpp();
+    for (i, (s, d)) in src.chunks_exact(3).zip(dst.chunks_exact_mut(bpp)).enumerate()
+    {
+        let x = x0 + i;
+        match format
+        {
+            JpegPixelFormat::Rgb => d.copy_from_slice(s),
+            JpegPixelFormat::Rgba => d.copy_from_slice(&[s[0], s[1], s[2], 0xFF]),
+            JpegPixelFormat::Bgr => d.copy_from_slice(&[s[2], s[1], s[0]]),
+            JpegPixelFormat::Bgra => d.copy_from_slice(&[s[2], s[1], s[0], 0xFF]),
+            JpegPixelFormat::Gray8 => d[0] = s[0],
+            JpegPixelFormat::Rgb565 =>
+            {
+                // Threshold is scaled to the quantization step (8 for R/B, 4 for G)
+                let t = if dithering { DITHER_MATRIX_4X4[y & 3][x & 3] as u16 } else { 0 };
+                let r = (s[0] as u16 + (t >> 1)).min(255) >> 3;
+                let g = (s[1] as u16 + (t >> 2)).min(255) >> 2;
+                let b = (s[2] as u16 + (t >> 1)).min(255) >> 3;
+                let p = r << 11 | g << 5 | b;
+                d.copy_from_slice(&p.to_le_bytes());
+            }
+        }
+    }
+}
 
 //========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn rgb565_is_little_endian()
+    {
+        let src = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let mut dst = [0; 8];
+        pack_pixels(JpegPixelFormat::Rgb565, false, &src, &mut dst, 0, 0);
+        assert!(dst == [0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00, 0xFF, 0xFF]);
+
+        // Thresholds 0, 8, 2, 10 (R/B: 1/2, G: 1/4) in the first row, no overflow at 255
+        let src = [4, 2, 4, 4, 2, 4, 6, 3, 6, 255, 255, 255];
+        pack_pixels(JpegPixelFormat::Rgb565, true, &src, &mut dst, 0, 0);
+        assert!(dst == [0x00, 0x00, 0x21, 0x08, 0x00, 0x00, 0xFF, 0xFF]);
+        // The row and the column in the image select the threshold (15 at (0, 3)).
+        let src = [1, 1, 1];
+        pack_pixels(JpegPixelFormat::Rgb565, true, &src, &mut dst[..2], 4, 3);
+        assert!(dst[..2] == [0x21, 0x08]);
+    }
+
+    #[test]
+    fn matrices_and_ranges()
+    {
+        // (Y, Cb, Cr) -> (R, G, B), calculated from Kr/Kb of each recommendation
+        let cases = [
+            (JpegColorMatrix::Bt601, JpegColorRange::Full, [(128, 128, 192, (218, 82, 128)), (128, 64, 192, (218, 104, 15))]),
+            (JpegColorMatrix::Bt709, JpegColorRange::Full, [(128, 128, 192, (229, 98, 128)), (128, 64, 192, (229, 110, 9))]),
+            (JpegColorMatrix::Bt2020, JpegColorRange::Full, [(128, 128, 192, (222, 91, 128)), (128, 64, 192, (222, 102, 8))]),
+            (JpegColorMatrix::Bt601, JpegColorRange::Limited, [(126, 128, 184, (217, 83, 128)), (126, 72, 128, (128, 150, 15))]),
+            (JpegColorMatrix::Bt709, JpegColorRange::Limited, [(126, 128, 184, (228, 98, 128)), (126, 72, 128, (128, 140, 10))]),
+            (JpegColorMatrix::Bt2020, JpegColorRange::Limited, [(126, 128, 184, (222, 92, 128)), (126, 72, 128, (128, 139, 8))]),
+        ];
+        for (matrix, range, pixels) in cases
+        {
+            for precision in [JpegColorPrecision::Integer, JpegColorPrecision::Float]
+            {
+                let mut conversion = JpegColorConversion::new();
+                conversion.set_matrix(matrix);
+                conversion.set_range(range);
+                conversion.set_precision(precision);
+                let converter = JpegYCbCrConverter::new(&conversion);
+                let convert = |y: u8, cb: u8, cr: u8|
+                {
+                    let mut out = [0; 3];
+                    converter.convert_row_ycc(&[y], &[cb], &[cr], &mut out);
+                    (out[0], out[1], out[2])
+                };
+                for (y, cb, cr, rgb) in pixels
+                {
+                    assert!(convert(y, cb, cr) == rgb, "{:?} {:?} {:?} {:?}", matrix, range, precision, (y, cb, cr));
+                }
+
+                // Black and white levels (16 and 235 for the limited range), clamped beyond them
+                let (black, white) = if range == JpegColorRange::Limited { (16, 235) } else { (0, 255) };
+                assert!(convert(black, 128, 128) == (0, 0, 0));
+                assert!(convert(white, 128, 128) == (255, 255, 255));
+                assert!(convert(0, 128, 128) == (0, 0, 0));
+                assert!(convert(255, 128, 128) == (255, 255, 255));
+            }
+        }
+    }
+}
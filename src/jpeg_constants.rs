@@ -5,6 +5,28 @@
 
 pub const JPEG_SAMPLE_BLOCK_SIZE: usize = 64;
 pub const JPEG_MAX_NUM_OF_COMPONENTS: usize = 4;
+// Blocks in an MCU of an interleaved scan (B.2.3)
+pub const JPEG_MCU_MAX_NUM_BLOCKS: usize = 10;
+
+#[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum JpegMarker
+{
+    SOF0  = 0xFFC0,
+    SOF15 = 0xFFCF,
+    DHT   = 0xFFC4,
+    JPG   = 0xFFC8,
+    DAC   = 0xFFCC,
+    SOI   = 0xFFD8,
+    EOI   = 0xFFD9,
+    SOS   = 0xFFDA,
+    DQT   = 0xFFDB,
+    DRI   = 0xFFDD,
+    APP0  = 0xFFE0,
+    APP1  = 0xFFE1,
+    APP2  = 0xFFE2,
+    APP14 = 0xFFEE,
+}
 
 // Reversed zigzag table for reading coefficients
 pub const JPEG_REV_ZIGZAG_TABLE: [u8; JPEG_SAMPLE_BLOCK_SIZE] =
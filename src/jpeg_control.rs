@@ -2,38 +2,115 @@
 //  jpeg_control.rs
 //
 //========================================================
+use crate::jpeg_constants::JpegMarker;
+use crate::jpeg_constants::JPEG_MAX_NUM_OF_COMPONENTS;
 use crate::jpeg_raw_data;
 use crate::jpeg_frame_info;
 use crate::jpeg_sample_block;
 use crate::jpeg_huffman_table;
+use crate::jpeg_huffman_table::JpegDhtError;
+use crate::jpeg_frame_info::JpegFrameError;
 use crate::jpeg_quantization_table;
 use crate::jpeg_outbuffer_info;
+use crate::jpeg_row_sink::JpegRowSink;
+use crate::jpeg_row_sink::JpegBufferSink;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use crate::jpeg_color_converter;
+use crate::jpeg_color_converter::JpegColorConvert;
+use crate::jpeg_sampler::JpegSampler;
+use crate::jpeg_sampler::JpegComponentPlane;
+use crate::jpeg_sampler::JpegUpsampling;
+use crate::jpeg_planar_image::JpegPlanarImage;
+use crate::jpeg_planar_image::JpegPlanarLayout;
+use crate::jpeg_planar_image::JpegPlane;
+use crate::jpeg_idct::JpegIdctMethod;
+use crate::jpeg_idct::JpegIdct;
+use crate::jpeg_idct::JpegScale;
+use crate::jpeg_pipeline::JpegCoefficientRing;
 
-#[allow(dead_code)]
-enum JpegMarker
+// Bytes per pixel of the working area (RGB triplets before packing)
+const JPEG_WORK_BPP: usize = 3;
+
+// MCU rows of a band of the pipelined decoding
+const JPEG_PIPELINE_BAND_ROWS: usize = 8;
+
+// Working buffers for one MCU row
+//
+// `planes` receives the MCU row being decoded, and `pending` keeps the completed
+// one until it is output. (With the fancy upsampling of vertically subsampled
+// chroma, the output waits for the first row of the next MCU row.)
+pub struct JpegMcuRowBuffer
 {
-    SOF0 = 0xFFC0,
-    DHT  = 0xFFC4,
-    SOI  = 0xFFD8,
-    EOI  = 0xFFD9,
-    SOS  = 0xFFDA,
-    DQT  = 0xFFDB,
-    APP0 = 0xFFE0,
-    APP1 = 0xFFE1,
+    planes: Vec<JpegComponentPlane>,
+    pending: Vec<JpegComponentPlane>,
+    sampler: JpegSampler,
+    work: Vec<u8>,
+    out: Vec<u8>,
+    height: usize,
+    needs_context: bool,
 }
 
-pub struct JpegControl
+impl JpegMcuRowBuffer
 {
-    rawdata: jpeg_raw_data::JpegRawData,
+    // Constructor (empty; see JpegControl::create_mcu_row_buffer())
+    pub fn new() -> Self
+    {
+        JpegMcuRowBuffer
+        {
+            planes: Vec::new(),
+            pending: Vec::new(),
+            sampler: JpegSampler::new(),
+            work: Vec::new(),
+            out: Vec::new(),
+            height: 0,
+            needs_context: false,
+        }
+    }
+
+    // Sets the context rows between the decoded MCU row and the pending (upper) one
+    fn set_context_rows(&mut self, mcu_y: usize)
+    {
+        for (plane, above) in self.planes.iter_mut().zip(&self.pending)
+        {
+            plane.set_context_above(if mcu_y == 0 { None } else { Some(above) });
+        }
+        if mcu_y > 0
+        {
+            for (plane, below) in self.pending.iter_mut().zip(&self.planes)
+            {
+                plane.set_context_below(below);
+            }
+        }
+    }
+}
+
+pub struct JpegControl<'a>
+{
+    rawdata: jpeg_raw_data::JpegRawData<'a>,
     frame_header_info: jpeg_frame_info::JpegFrameHeaderInfo,
+    scan_header_info: jpeg_frame_info::JpegScanHeaderInfo,
     dht_mgr: jpeg_huffman_table::JpegDhtManager,
     dqt_mgr: jpeg_quantization_table::JpegDqtManager,
     out_buffer_info: jpeg_outbuffer_info::JpegOutBufferInfo,
+    work_buffer_info: jpeg_outbuffer_info::JpegOutBufferInfo,
+    color_converter: Option<Arc<dyn JpegColorConvert>>,
+    idct_method: JpegIdctMethod,
+    idct: Option<Arc<dyn JpegIdct>>,
+    dht_error: Option<JpegDhtError>,
+    frame_error: Option<JpegFrameError>,
+    uses_default_dht: bool,
+    restart_interval: usize,
+    num_threads: usize,
     img_start: usize,
+    header_pos: usize,
+    luma_only: bool,
 }
 
 #[allow(dead_code)]
-impl JpegControl
+impl<'a> JpegControl<'a>
 {
     // Constructor
     pub fn new() -> Self
@@ -42,97 +119,347 @@ impl JpegControl
         {
             rawdata: jpeg_raw_data::JpegRawData::new(),
             frame_header_info: jpeg_frame_info::JpegFrameHeaderInfo::new(),
+            scan_header_info: jpeg_frame_info::JpegScanHeaderInfo::new(),
             dht_mgr: jpeg_huffman_table::JpegDhtManager::new(),
             dqt_mgr: jpeg_quantization_table::JpegDqtManager::new(),
             out_buffer_info: jpeg_outbuffer_info::JpegOutBufferInfo::new(),
+            work_buffer_info: jpeg_outbuffer_info::JpegOutBufferInfo::new(),
+            color_converter: None,
+            idct_method: JpegIdctMethod::Integer,
+            idct: None,
+            dht_error: None,
+            frame_error: None,
+            uses_default_dht: false,
+            restart_interval: 0,
+            num_threads: 1,
             img_start: 0,
+            header_pos: 0,
+            luma_only: false,
         }
     }
 
     // Round up + alignment
     fn round_up(num: usize, base: usize) -> usize
     {
-        ((num + base - 1) / base) * base
+        num.div_ceil(base) * base
     }
 
     // ファイル読み込み
     pub fn read_from_file(&mut self, infilename: &String)
     {
         self.rawdata.read_from_file(infilename);
+        self.header_pos = 0;
+    }
+
+    // In-memory data (borrowed without copy)
+    pub fn read_from_slice(&mut self, data: &'a [u8])
+    {
+        self.rawdata = jpeg_raw_data::JpegRawData::from_slice(data);
+        self.header_pos = 0;
+    }
+
+    // In-memory data (ownership is moved)
+    pub fn read_from_vec(&mut self, data: Vec<u8>)
+    {
+        self.rawdata = jpeg_raw_data::JpegRawData::from_vec(data);
+        self.header_pos = 0;
+    }
+
+    // Any std::io::Read source (stdin, network, archive entry, ...)
+    pub fn read_from_reader<R: std::io::Read>(&mut self, reader: R) -> std::io::Result<()>
+    {
+        self.rawdata = jpeg_raw_data::JpegRawData::from_reader(reader)?;
+        self.header_pos = 0;
+        Ok(())
+    }
+
+    // Appends data (for decoding a stream which arrives piece by piece)
+    pub fn append_data(&mut self, data: &[u8])
+    {
+        self.rawdata.append(data);
     }
 
     // JPEG マーカーの parse
     pub fn parse_markers(&mut self)
     {
-        let mut reader = jpeg_raw_data::JpegReader::new(&mut self.rawdata);
+        self.dump_markers();
+        if !self.parse_headers()
+        {
+            match (self.dht_error, self.frame_error)
+            {
+                (Some(e), _) => println!("parse_markers: {}", e),
+                (_, Some(e)) => println!("parse_markers: {}", e),
+                _ => println!("parse_markers: Unexpected end of data."),
+            }
+        }
+    }
 
-        while !reader.is_end()
+    // Prints the markers and the segment sizes up to SOS
+    pub fn dump_markers(&self)
+    {
+        let mut reader = jpeg_raw_data::JpegReader::new(&self.rawdata);
+        while reader.get_remaining_size() >= 2
         {
-            let seg_size: usize;
-            let marker_name: &'static str;
             let m = reader.read_u16be();
-            if m == JpegMarker::SOI as u16
+            let mut seg_size = 0;
+            if m != JpegMarker::SOI as u16 && m != JpegMarker::EOI as u16
             {
-                seg_size = 0;
-                marker_name = "SOI ";
+                if reader.get_remaining_size() < 2
+                {
+                    break;
+                }
+                seg_size = reader.read_u16be() as usize;
+                if seg_size < 2 || reader.get_remaining_size() < seg_size - 2
+                {
+                    break;
+                }
+                reader.move_pos(seg_size as isize - 2);
             }
-            else if m == JpegMarker::EOI as u16
+            println!("{} {:04x} ", Self::get_marker_name(m), seg_size);
+            if m == JpegMarker::SOS as u16
             {
-                seg_size = 0;
-                marker_name = "EOI ";
+                break;
             }
-            else
+        }
+    }
+
+    fn get_marker_name(m: u16) -> &'static str
+    {
+        match m
+        {
+            m if m == JpegMarker::SOI as u16 => "SOI ",
+            m if m == JpegMarker::EOI as u16 => "EOI ",
+            m if m == JpegMarker::DHT as u16 => "DHT ",
+            m if m == JpegMarker::DQT as u16 => "DQT ",
+            m if m == JpegMarker::SOF0 as u16 => "SOF0",
+            m if m == JpegMarker::DRI as u16 => "DRI ",
+            m if m == JpegMarker::APP0 as u16 => "APP0",
+            m if m == JpegMarker::APP1 as u16 => "APP1",
+            m if m == JpegMarker::SOS as u16 => "SOS ",
+            _ => "....",
+        }
+    }
+
+    // Parses the header segments up to SOS.
+    // Returns false if the data ends before SOS or if a DHT or SOF segment is invalid
+    // (see get_dht_error() and get_frame_error()).
+    // On the next call after more data is appended, the parsing resumes from the first
+    // incomplete segment.
+    pub fn parse_headers(&mut self) -> bool
+    {
+        let mut reader = jpeg_raw_data::JpegReader::new(&self.rawdata);
+        reader.set_pos(self.header_pos);
+        let mut is_complete = false;
+        self.dht_error = None;
+        self.frame_error = None;
+
+        while reader.get_remaining_size() >= 2
+        {
+            let m = reader.read_u16be();
+            if m != JpegMarker::SOI as u16 && m != JpegMarker::EOI as u16
             {
-                seg_size = reader.read_u16be() as usize;
+                if reader.get_remaining_size() < 2
+                {
+                    break;
+                }
+                let seg_size = reader.read_u16be() as usize;
+                if seg_size < 2 || reader.get_remaining_size() < seg_size - 2
+                {
+                    break;
+                }
                 let mut reader2 = reader.copy();
                 if m == JpegMarker::DHT as u16
                 {
-                    marker_name = "DHT ";
-                    self.dht_mgr.parse_segment(&mut reader2);
+                    if let Err(e) = self.dht_mgr.parse_segment(&mut reader2)
+                    {
+                        self.dht_error = Some(e);
+                        break;
+                    }
                 }
                 else if m == JpegMarker::DQT as u16
                 {
-                    marker_name = "DQT ";
                     self.dqt_mgr.read_table(&mut reader2);
                 }
                 else if m == JpegMarker::SOF0 as u16
                 {
-                    marker_name = "SOF0";
-                    self.frame_header_info.parse_segment(&mut reader2);
-                }
-                else if m == JpegMarker::APP0 as u16
-                {
-                    marker_name = "APP0";
+                    if let Err(e) = self.frame_header_info.parse_segment(&mut reader2, seg_size - 2)
+                    {
+                        self.frame_error = Some(e);
+                        break;
+                    }
                 }
-                else if m == JpegMarker::APP1 as u16
+                else if m == JpegMarker::DRI as u16
                 {
-                    marker_name = "APP1";
+                    if seg_size >= 4
+                    {
+                        self.restart_interval = reader2.read_u16be() as usize;
+                    }
                 }
                 else if m == JpegMarker::SOS as u16
                 {
-                    marker_name = "SOS ";
+                    if !self.scan_header_info.parse_segment(&mut reader2, seg_size - 2)
+                    {
+                        break;
+                    }
+                    // The SOS segment is parsed again if this is called again.
                     self.img_start = reader.get_pos() + seg_size - 2;
-                }
-                else
-                {
-                    marker_name = "....";
+                    is_complete = true;
+                    break;
                 }
                 reader.move_pos(seg_size as isize - 2);
             }
-            print!("{} {:04x} \n", marker_name, seg_size);
+            self.header_pos = reader.get_pos();
+        }
+        if !is_complete
+        {
+            return false;
+        }
+        if let Err(e) = self.load_default_dht()
+        {
+            self.dht_error = Some(e);
+            return false;
         }
-        
-        let (mut wd, mut ht) = self.frame_header_info.get_dimension();
-        let sf = self.frame_header_info.get_sampling_factor(0);
-        wd = Self::round_up(wd, sf.get_num_mcu_pixels_h());
-        ht = Self::round_up(ht, sf.get_num_mcu_pixels_v());
-        self.out_buffer_info.set_parameters(wd, ht, 3);
+
+        // The caller gets the real image size (scaled), while decoding is done in
+        // the working area rounded up to the MCU size.
+        let (wd, ht) = self.frame_header_info.get_dimension();
+        let scale = self.out_buffer_info.get_scale();
+        let (wd, ht) = (scale.get_scaled_size(wd), scale.get_scaled_size(ht));
+        let mcu = self.create_mcu();
+        let bpp = self.out_buffer_info.get_pixel_format().get_bpp();
+        self.out_buffer_info.set_parameters(wd, ht, bpp);
+        self.work_buffer_info.set_parameters(
+            Self::round_up(wd, mcu.get_width()),
+            Self::round_up(ht, mcu.get_height()),
+            JPEG_WORK_BPP
+        );
 
         /*
         self.frame_header_info.dump();       
         self.dht_mgr.dump();
         self.dqt_mgr.dump();
         */
+        true
+    }
+
+    // Pre-loads the Annex K tables into the Huffman table slots of the scan
+    // which no DHT segment defined (Motion-JPEG)
+    //
+    // Only the tables used by the scan are loaded. A slot without a default table (2/3)
+    // is an error.
+    fn load_default_dht(&mut self) -> Result<(), JpegDhtError>
+    {
+        self.uses_default_dht = false;
+        for i in 0..self.scan_header_info.get_num_components()
+        {
+            for (is_ac, is_used, table_id) in [
+                (false, self.scan_header_info.uses_dc_table(), self.scan_header_info.get_dc_table_id(i)),
+                (true, self.scan_header_info.uses_ac_table(), self.scan_header_info.get_ac_table_id(i))]
+            {
+                if !is_used
+                {
+                    continue;
+                }
+                if !self.dht_mgr.load_default_table(is_ac, table_id)
+                {
+                    return Err(JpegDhtError::UndefinedTable { table_id: ((is_ac as u8) << 4) | table_id as u8 });
+                }
+                self.uses_default_dht |= self.dht_mgr.is_default_table(is_ac, table_id);
+            }
+        }
+        Ok(())
+    }
+
+    // True if the scan uses default Huffman tables (no DHT segment for some of the slots)
+    pub fn uses_default_huffman_tables(&self) -> bool
+    {
+        self.uses_default_dht
+    }
+
+    // Error of the DHT segment which stopped the header parsing
+    pub fn get_dht_error(&self) -> Option<JpegDhtError>
+    {
+        self.dht_error
+    }
+
+    // Error of the SOF segment which stopped the header parsing
+    pub fn get_frame_error(&self) -> Option<JpegFrameError>
+    {
+        self.frame_error
+    }
+
+    // Set pixel format of the output buffer
+    pub fn set_pixel_format(&mut self, format: jpeg_outbuffer_info::JpegPixelFormat)
+    {
+        self.out_buffer_info.set_pixel_format(format);
+    }
+
+    // Set luma-only decoding into Gray8 (for grayscale output from color images)
+    //
+    // The Cb/Cr blocks are only entropy-decoded, and the gray levels are given from the Y
+    // samples by the color converter (the same output as the full decoding into Gray8).
+    // It is in effect while the pixel format is Gray8, and planar images have only the Y plane.
+    pub fn set_luma_only(&mut self, luma_only: bool)
+    {
+        self.luma_only = luma_only;
+        if luma_only
+        {
+            self.set_pixel_format(jpeg_outbuffer_info::JpegPixelFormat::Gray8);
+        }
+    }
+
+    fn is_luma_only(&self) -> bool
+    {
+        self.luma_only && self.out_buffer_info.get_pixel_format() == jpeg_outbuffer_info::JpegPixelFormat::Gray8
+    }
+
+    // Enable ordered dithering (RGB565)
+    pub fn set_dithering(&mut self, dithering: bool)
+    {
+        self.out_buffer_info.set_dithering(dithering);
+    }
+
+    // Set YCbCr -> RGB conversion (e.g. BT.709 limited range for MJPEG from capture devices)
+    pub fn set_color_conversion(&mut self, conversion: jpeg_color_converter::JpegColorConversion)
+    {
+        self.out_buffer_info.set_color_conversion(conversion);
+    }
+
+    // Install a user color converter (3D LUT, false color map, ...)
+    // instead of the built-in one selected by the pixel format and the color conversion
+    pub fn set_color_converter<C: JpegColorConvert + 'static>(&mut self, converter: C)
+    {
+        self.color_converter = Some(Arc::new(converter));
+    }
+
+    // Built-in color converter again
+    pub fn reset_color_converter(&mut self)
+    {
+        self.color_converter = None;
+    }
+
+    // Color converter used for the RGB (packed) output
+    fn get_color_converter(&self) -> Arc<dyn JpegColorConvert>
+    {
+        if let Some(converter) = &self.color_converter
+        {
+            return converter.clone();
+        }
+        let conversion = self.out_buffer_info.get_color_conversion();
+        match self.out_buffer_info.get_pixel_format()
+        {
+            jpeg_outbuffer_info::JpegPixelFormat::Gray8 =>
+                Arc::new(jpeg_color_converter::JpegGrayConverter::new(conversion)),
+            _ => Arc::new(jpeg_color_converter::JpegYCbCrConverter::new(conversion)),
+        }
+    }
+
+    // Set layout of the output buffer (origin and row pitch in bytes)
+    //
+    // A negative row pitch with the origin at the last row gives a bottom-up image.
+    pub fn set_output_layout(&mut self, origin: usize, row_pitch: isize)
+    {
+        self.out_buffer_info.set_layout(origin, row_pitch);
     }
 
     // Get total size of output buffer
@@ -151,42 +478,1319 @@ impl JpegControl
     pub fn decode_image(&mut self, out_buf: &mut [u8])
     {
         // Buffer size check
+        if !self.out_buffer_info.is_valid_layout()
+        {
+            println!("decode_image: Invalid buffer layout.");
+            return;
+        }
         if out_buf.len() < self.out_buffer_info.get_total_buffer_size()
         {
             println!("decode_image: Not enough buffer size.");
             return;
         }
 
-        let mut bsreader = jpeg_raw_data::JpegBitStreamReader::new(&mut self.rawdata);
-        let mut mcu = jpeg_sample_block::JpegMinimumCodedUnit::new();
-        mcu.set_mode(&self.frame_header_info);
+        // Nothing to decode (empty crop rectangle)
+        let (width, height) = self.out_buffer_info.get_dimension();
+        if width == 0 || height == 0
+        {
+            return;
+        }
+
+        // Restart intervals are decoded in parallel if enabled (otherwise pipelined).
+        if self.num_threads > 1
+        {
+            let offsets = if self.restart_interval > 0 { self.get_restart_offsets() } else { None };
+            match offsets
+            {
+                Some(offsets) => self.decode_image_parallel(out_buf, &offsets),
+                None => self.decode_image_pipelined(out_buf),
+            }
+            return;
+        }
+
+        let mut sink = JpegBufferSink::with_layout(
+            out_buf,
+            self.out_buffer_info.get_origin(),
+            self.out_buffer_info.get_row_pitch()
+        );
+        self.decode_image_rows(&mut sink);
+    }
+
+    // Set number of threads of decode_image() (1 by default)
+    //
+    // The image is split across the threads at restart markers (RSTn). Files
+    // without a restart interval are decoded with a pipeline of the Huffman
+    // decoding and the IDCT/color conversion.
+    pub fn set_num_threads(&mut self, num_threads: usize)
+    {
+        self.num_threads = num_threads.max(1);
+    }
+
+    // Restart interval in MCUs (0 if the file has no DRI segment)
+    pub fn get_restart_interval(&self) -> usize
+    {
+        self.restart_interval
+    }
+
+    // Positions of the entropy-coded data of every restart interval (the first one is the scan start)
+    //
+    // Returns None if the scan has fewer RSTn markers than the intervals of the image (corrupted).
+    fn get_restart_offsets(&self) -> Option<Vec<usize>>
+    {
+        let data = self.rawdata.get_slice();
+        let mut offsets = vec![self.img_start];
+        let mut pos = self.img_start;
+        while pos + 1 < data.len()
+        {
+            if data[pos] != 0xFF
+            {
+                pos += 1;
+                continue;
+            }
+            match data[pos + 1]
+            {
+                // Stuffed byte / fill byte
+                0x00 => pos += 2,
+                0xFF => pos += 1,
+                0xD0..=0xD7 =>
+                {
+                    pos += 2;
+                    offsets.push(pos);
+                }
+                // End of the scan
+                _ => break,
+            }
+        }
+
+        let mcu = self.create_mcu();
+        let num_intervals = self.get_num_mcus(&mcu).div_ceil(self.restart_interval);
+        if offsets.len() < num_intervals
+        {
+            return None;
+        }
+        offsets.truncate(num_intervals);
+        Some(offsets)
+    }
+
+    // Splits the output buffer into the disjoint parts of bands of MCU rows ([first, last) each)
+    //
+    // The part of a band without rows inside the image is None.
+    fn split_output_buffer<'b>(&self, out_buf: &'b mut [u8], bands: &[(usize, usize)], mcu_height: usize)
+        -> Vec<Option<JpegBufferSink<'b>>>
+    {
+        let height = self.out_buffer_info.get_height();
+        let (_, offset_y) = self.out_buffer_info.get_crop_offset();
+        let origin = self.out_buffer_info.get_origin() as isize;
+        let pitch = self.out_buffer_info.get_row_pitch();
+        let row_size = self.out_buffer_info.get_row_size() as isize;
+        let mut ranges = Vec::new();
+        for (i, &(r0, r1)) in bands.iter().enumerate()
+        {
+            let to_output_row = |mcu_y: usize| (mcu_y * mcu_height).saturating_sub(offset_y).min(height);
+            let (y0, y1) = (to_output_row(r0), to_output_row(r1));
+            if y0 == y1
+            {
+                continue;
+            }
+            let first = origin + y0 as isize * pitch;
+            let last = origin + (y1 - 1) as isize * pitch;
+            ranges.push((i, y0, first.min(last) as usize, (first.max(last) + row_size) as usize));
+        }
+
+        // Splits the buffer in the memory order of the parts (reversed for a bottom-up buffer)
+        ranges.sort_by_key(|range| range.2);
+        let mut parts: Vec<Option<JpegBufferSink<'b>>> = bands.iter().map(|_| None).collect();
+        let mut rest = out_buf;
+        let mut rest_start = 0;
+        for (i, y0, start, end) in ranges
+        {
+            let (_, tail) = rest.split_at_mut(start - rest_start);
+            let (part, tail) = tail.split_at_mut(end - start);
+            let part_origin = (origin + y0 as isize * pitch) as usize - start;
+            parts[i] = Some(JpegBufferSink::with_first_row(part, part_origin, pitch, y0));
+            rest = tail;
+            rest_start = end;
+        }
+        parts
+    }
+
+    // Decodes the MCU rows in parallel into the disjoint parts of the output buffer
+    //
+    // Each thread starts at the restart interval which contains the MCU row above its rows
+    // (needed as the context of the fancy upsampling), and also decodes the MCU row below them.
+    fn decode_image_parallel(&self, out_buf: &mut [u8], offsets: &[usize])
+    {
+        let mcu = self.create_mcu();
+        let num_mcus_h = self.get_num_mcus_h(&mcu);
+        let num_mcu_rows = self.work_buffer_info.get_height() / mcu.get_height();
+        let output_rows = self.get_output_mcu_rows(&mcu);
+        let num_threads = self.num_threads.min(output_rows.len());
+        let bands: Vec<(usize, usize)> = (0..num_threads)
+            .map(|t| (output_rows.len() * t / num_threads, output_rows.len() * (t + 1) / num_threads))
+            .map(|(r0, r1)| (output_rows.start + r0, output_rows.start + r1))
+            .collect();
+        let parts = self.split_output_buffer(out_buf, &bands, mcu.get_height());
+
+        std::thread::scope(|scope|
+        {
+            for (&(r0, r1), part) in bands.iter().zip(parts)
+            {
+                let Some(mut sink) = part else { continue };
+                scope.spawn(move ||
+                {
+                    let first_mcu = (r0.saturating_sub(1) * num_mcus_h) / self.restart_interval * self.restart_interval;
+                    let pos = offsets[first_mcu / self.restart_interval];
+                    let last_row = r1.min(num_mcu_rows - 1);
+                    self.decode_mcu_range(first_mcu, pos, last_row, Some(offsets), |row_buf, mcu_y|
+                    {
+                        if (r0..r1).contains(&mcu_y)
+                        {
+                            self.write_mcu_row(row_buf, mcu_y, &mut sink);
+                        }
+                    });
+                });
+            }
+        });
+    }
+
+    // Decodes the image with a pipeline (for files without restart intervals)
+    //
+    // One thread does the Huffman decoding into a ring buffer of MCU rows, and the others
+    // take bands of JPEG_PIPELINE_BAND_ROWS MCU rows for the IDCT, the upsampling and the
+    // color conversion. The MCU rows around a band are transformed again by its thread
+    // when they are needed as the context of the fancy upsampling.
+    fn decode_image_pipelined(&self, out_buf: &mut [u8])
+    {
+        let mcu = self.create_mcu();
+        let num_mcus_h = self.get_num_mcus_h(&mcu);
+        let num_mcu_rows = self.work_buffer_info.get_height() / mcu.get_height();
+        let num_workers = (self.num_threads - 1).max(1);
+        let needs_context = self.create_mcu_row_buffer(&mcu).needs_context;
+        let context = if needs_context { 1 } else { 0 };
+        let (_, columns) = self.get_transformed_mcus(&mcu, needs_context);
+        let output_rows = self.get_output_mcu_rows(&mcu);
+        if output_rows.is_empty()
+        {
+            return;
+        }
+
+        // MCU rows of each band, and the range of the rows transformed for it
+        let bands: Vec<(usize, usize)> = output_rows.clone().step_by(JPEG_PIPELINE_BAND_ROWS)
+            .map(|r0| (r0, (r0 + JPEG_PIPELINE_BAND_ROWS).min(output_rows.end)))
+            .collect();
+        let decoded_rows = |k: usize|
+        {
+            let (r0, r1) = bands[k];
+            (r0.saturating_sub(context), (r1 - 1 + context).min(num_mcu_rows - 1))
+        };
+        let num_uses = |mcu_y: usize|
+        {
+            let k = mcu_y.saturating_sub(output_rows.start) / JPEG_PIPELINE_BAND_ROWS;
+            (k.saturating_sub(1)..(k + 2).min(bands.len()))
+                .filter(|&j| { let (first, last) = decoded_rows(j); (first..=last).contains(&mcu_y) })
+                .count()
+        };
+
+        let parts = Mutex::new(self.split_output_buffer(out_buf, &bands, mcu.get_height()));
+        let ring = JpegCoefficientRing::new((num_workers + 1) * (JPEG_PIPELINE_BAND_ROWS + 2));
+        let next_band = AtomicUsize::new(0);
+
+        std::thread::scope(|scope|
+        {
+            // Entropy decoding
+            scope.spawn(||
+            {
+                let _guard = ring.panic_guard();
+                let mut bsreader = self.get_bit_stream_reader();
+                bsreader.set_pos(self.img_start, 0);
+                let mut mcu = self.create_mcu();
+                let mut blocks = Vec::new();
+                let (_, last_row) = decoded_rows(bands.len() - 1);
+                for i in 0..(last_row + 1) * num_mcus_h
+                {
+                    if i > 0 && self.is_restart_mcu(i)
+                    {
+                        self.process_restart(&mut mcu, &mut bsreader);
+                    }
+                    mcu.fill_coefficients(&self.dht_mgr, &mut bsreader);
+                    mcu.save_blocks(&mut blocks);
+                    if i % num_mcus_h == num_mcus_h - 1
+                    {
+                        let mcu_y = i / num_mcus_h;
+                        match num_uses(mcu_y)
+                        {
+                            0 => blocks.clear(),
+                            n => if !ring.put(mcu_y, std::mem::take(&mut blocks), n)
+                            {
+                                return;
+                            },
+                        }
+                    }
+                }
+            });
+
+            // IDCT, upsampling and color conversion
+            for _ in 0..num_workers
+            {
+                scope.spawn(||
+                {
+                    let _guard = ring.panic_guard();
+                    let mut mcu = self.create_mcu();
+                    let mut row_buf = self.create_mcu_row_buffer(&mcu);
+                    let num_blocks = mcu.get_num_blocks();
+                    loop
+                    {
+                        let k = next_band.fetch_add(1, Ordering::Relaxed);
+                        if k >= bands.len()
+                        {
+                            break;
+                        }
+                        let (r0, r1) = bands[k];
+                        let (first, last) = decoded_rows(k);
+                        let mut sink = parts.lock().unwrap_or_else(|e| e.into_inner())[k].take();
+                        for mcu_y in first..=last
+                        {
+                            let Some(blocks) = ring.take(mcu_y) else { return };
+                            for mcu_x in columns.clone()
+                            {
+                                mcu.load_blocks(&blocks[mcu_x * num_blocks..]);
+                                mcu.transform(&self.dqt_mgr);
+                                mcu.store_blocks(&mut row_buf.planes, mcu_x);
+                            }
+                            self.complete_mcu_row(&mcu, &mut row_buf, mcu_y, &mut |row_buf, y|
+                            {
+                                if let Some(sink) = sink.as_mut().filter(|_| (r0..r1).contains(&y))
+                                {
+                                    self.write_mcu_row(row_buf, y, sink);
+                                }
+                            });
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    // Decoding image row by row (only one MCU row of pixels is kept in memory)
+    pub fn decode_image_rows(&mut self, sink: &mut dyn JpegRowSink)
+    {
+        self.decode_mcu_rows(|row_buf, mcu_y| self.write_mcu_row(row_buf, mcu_y, sink));
+    }
+
+    // Decoding image into Y/Cb/Cr planes without color conversion
+    //
+    // With JpegPlanarLayout::Native, the chroma planes keep the subsampled
+    // resolution of the file and the upsampling step is skipped.
+    pub fn decode_planar(&mut self, layout: JpegPlanarLayout) -> JpegPlanarImage
+    {
+        let mut image = self.create_planar_image(layout);
+        self.decode_mcu_rows(|row_buf, mcu_y| self.write_mcu_row_planar(row_buf, mcu_y, &mut image));
+        image
+    }
+
+    // Decodes all MCUs and passes each completed MCU row to `output`
+    fn decode_mcu_rows<F>(&self, output: F)
+        where F: FnMut(&mut JpegMcuRowBuffer, usize)
+    {
+        let mcu = self.create_mcu();
+        let needs_context = self.create_mcu_row_buffer(&mcu).needs_context;
+        let (rows, _) = self.get_transformed_mcus(&mcu, needs_context);
+        if rows.is_empty()
+        {
+            return;
+        }
+        // The restart intervals are skipped only for the crop
+        let offsets = if self.restart_interval > 0 && self.is_cropped() { self.get_restart_offsets() } else { None };
+        self.decode_mcu_range(0, self.img_start, rows.end - 1, offsets.as_deref(), output);
+    }
+
+    // Decodes the MCUs from `first_mcu` (the beginning of a restart interval, whose data
+    // starts at `pos`) to the end of the MCU row `last_row`, and passes each completed
+    // MCU row to `output`
+    //
+    // The MCUs outside get_transformed_mcus() are only entropy-decoded, or skipped
+    // with the restart intervals if their `offsets` are given.
+    fn decode_mcu_range<F>(&self, first_mcu: usize, pos: usize, last_row: usize, offsets: Option<&[usize]>, mut output: F)
+        where F: FnMut(&mut JpegMcuRowBuffer, usize)
+    {
+        let mut bsreader = self.get_bit_stream_reader();
+        bsreader.set_pos(pos, 0);
+        let mut mcu = self.create_mcu();
+        let mut row_buf = self.create_mcu_row_buffer(&mcu);
+        let num_mcus_h = self.get_num_mcus_h(&mcu);
+        let end = ((last_row + 1) * num_mcus_h).min(self.get_num_mcus(&mcu));
+        let (rows, columns) = self.get_transformed_mcus(&mcu, row_buf.needs_context);
+        let is_transformed = |i: usize| rows.contains(&(i / num_mcus_h)) && columns.contains(&(i % num_mcus_h));
 
         //self.dht_mgr.set_log_control(0xFF);
 
         // Iteration of each MCU decode
-        let mut out_pos: usize = 0;
-        let num_iter_x = self.out_buffer_info.get_width() / mcu.get_width();
-        let num_iter_y = self.out_buffer_info.get_height() / mcu.get_height();
-        let stride_h = mcu.get_width() * self.out_buffer_info.get_bpp();
-        let stride_v = self.out_buffer_info.get_width() * self.out_buffer_info.get_bpp()
-                     * (mcu.get_height() - 1);
-        bsreader.set_pos(self.img_start, 0);
-        for _y in 0..num_iter_y
+        // (`start` is the MCU where the bit stream reader has been positioned)
+        let (mut i, mut start) = (first_mcu, first_mcu);
+        while i < end
         {
-            for _x in 0..num_iter_x
+            if let Some(offsets) = offsets.filter(|_| self.is_restart_mcu(i))
+            {
+                // Skips the restart interval (the MCU rows are still completed)
+                let next = (i + self.restart_interval).min(end);
+                if !(i..next).any(is_transformed)
+                {
+                    for j in (i..next).filter(|j| j % num_mcus_h == num_mcus_h - 1)
+                    {
+                        self.complete_mcu_row(&mcu, &mut row_buf, j / num_mcus_h, &mut output);
+                    }
+                    (i, start) = (next, next);
+                    if let Some(&pos) = offsets.get(i / self.restart_interval)
+                    {
+                        bsreader.set_pos(pos, 0);
+                    }
+                    mcu.set_dc_predictors([0; JPEG_MAX_NUM_OF_COMPONENTS]);
+                    continue;
+                }
+            }
+            if i > start && self.is_restart_mcu(i)
+            {
+                self.process_restart(&mut mcu, &mut bsreader);
+            }
+            if is_transformed(i)
+            {
+                self.decode_mcu(&mut mcu, &mut bsreader, &mut row_buf, i % num_mcus_h);
+            }
+            else
             {
                 mcu.fill_coefficients(&self.dht_mgr, &mut bsreader);
-                mcu.dequantize(&self.dqt_mgr);
-                // mcu.dump();
-                mcu.transform();
-                // mcu.dump();
-                mcu.upsampling(out_buf, &self.out_buffer_info, out_pos);
+            }
+            if i % num_mcus_h == num_mcus_h - 1
+            {
+                self.complete_mcu_row(&mcu, &mut row_buf, i / num_mcus_h, &mut output);
+            }
+            i += 1;
+        }
+    }
+
+    // Whether a crop rectangle smaller than the image is set
+    fn is_cropped(&self) -> bool
+    {
+        self.out_buffer_info.get_dimension() != self.out_buffer_info.get_image_dimension()
+    }
+
+    // MCU rows which have output rows (inside the crop rectangle)
+    fn get_output_mcu_rows(&self, mcu: &jpeg_sample_block::JpegMinimumCodedUnit) -> std::ops::Range<usize>
+    {
+        let (_, offset_y) = self.out_buffer_info.get_crop_offset();
+        let height = self.out_buffer_info.get_height();
+        if height == 0
+        {
+            return 0..0;
+        }
+        offset_y / mcu.get_height()..(offset_y + height).div_ceil(mcu.get_height())
+    }
+
+    // MCU rows and columns to be transformed
+    //
+    // They cover the crop rectangle and the adjacent MCUs referred by the upsampling.
+    // (The columns on both sides are always included.)
+    fn get_transformed_mcus(&self, mcu: &jpeg_sample_block::JpegMinimumCodedUnit, needs_context: bool)
+        -> (std::ops::Range<usize>, std::ops::Range<usize>)
+    {
+        let (offset_x, _) = self.out_buffer_info.get_crop_offset();
+        let width = self.out_buffer_info.get_width();
+        let output_rows = self.get_output_mcu_rows(mcu);
+        if width == 0 || output_rows.is_empty()
+        {
+            return (0..0, 0..0);
+        }
+        let num_mcu_rows = self.work_buffer_info.get_height() / mcu.get_height();
+        let num_mcus_h = self.get_num_mcus_h(mcu);
+        let context = if needs_context { 1 } else { 0 };
+        let rows = output_rows.start.saturating_sub(context)..(output_rows.end + context).min(num_mcu_rows);
+        let first_column = (offset_x / mcu.get_width()).saturating_sub(1);
+        let end_column = ((offset_x + width - 1) / mcu.get_width() + 2).min(num_mcus_h);
+        (rows, first_column..end_column)
+    }
+
+    // Passes the MCU rows which become ready for output to `output`,
+    // after the MCU row `mcu_y` is decoded into the MCU row buffer
+    pub fn complete_mcu_row<F>(
+        &self,
+        mcu: &jpeg_sample_block::JpegMinimumCodedUnit,
+        row_buf: &mut JpegMcuRowBuffer,
+        mcu_y: usize,
+        output: &mut F
+    )
+        where F: FnMut(&mut JpegMcuRowBuffer, usize)
+    {
+        let is_last = mcu_y == self.work_buffer_info.get_height() / mcu.get_height() - 1;
+        if row_buf.needs_context
+        {
+            row_buf.set_context_rows(mcu_y);
+            if mcu_y > 0
+            {
+                output(row_buf, mcu_y - 1);
+            }
+        }
+        std::mem::swap(&mut row_buf.planes, &mut row_buf.pending);
+        if is_last
+        {
+            for plane in row_buf.pending.iter_mut()
+            {
+                plane.set_bottom_edge(mcu_y);
+            }
+        }
+        if !row_buf.needs_context || is_last
+        {
+            output(row_buf, mcu_y);
+        }
+    }
+
+    // MCU set up for the current frame
+    pub fn create_mcu(&self) -> jpeg_sample_block::JpegMinimumCodedUnit
+    {
+        let mut mcu = jpeg_sample_block::JpegMinimumCodedUnit::new();
+        mcu.set_mode(&self.frame_header_info);
+        mcu.set_scan(&self.frame_header_info, &self.scan_header_info);
+        mcu.set_idct(self.get_idct());
+        mcu.set_scale(self.out_buffer_info.get_scale());
+        mcu.set_luma_only(self.is_luma_only());
+        mcu
+    }
+
+    // Set IDCT algorithm (fixed-point by default; the float one is for validation)
+    pub fn set_idct_method(&mut self, method: JpegIdctMethod)
+    {
+        self.idct_method = method;
+    }
+
+    // Install a user IDCT instead of the built-in one selected by the IDCT algorithm
+    pub fn set_idct<I: JpegIdct + 'static>(&mut self, idct: I)
+    {
+        self.idct = Some(Arc::new(idct));
+    }
+
+    // Built-in IDCT again
+    pub fn reset_idct(&mut self)
+    {
+        self.idct = None;
+    }
+
+    // IDCT used for the 8x8 blocks
+    fn get_idct(&self) -> Arc<dyn JpegIdct>
+    {
+        match &self.idct
+        {
+            Some(idct) => idct.clone(),
+            None => self.idct_method.create_idct(),
+        }
+    }
+
+    // Set output scale (1/2, 1/4 or 1/8 for thumbnails; to be set before parsing the headers)
+    //
+    // The blocks are decoded with the reduced-size IDCT, and get_dimension() gives the scaled size.
+    pub fn set_scale(&mut self, scale: JpegScale)
+    {
+        self.out_buffer_info.set_scale(scale);
+    }
+
+    // Set crop rectangle in pixels of the (scaled) image (to be set before parsing the headers)
+    //
+    // get_dimension() gives the size of the rectangle. Only the MCUs around it are
+    // transformed, and the restart intervals without them are skipped.
+    pub fn set_crop(&mut self, x: usize, y: usize, width: usize, height: usize)
+    {
+        self.out_buffer_info.set_crop(Some((x, y, width, height)));
+    }
+
+    // Set chroma upsampling method (fancy by default)
+    pub fn set_upsampling(&mut self, upsampling: JpegUpsampling)
+    {
+        self.out_buffer_info.set_upsampling(upsampling);
+    }
+
+    // libjpeg-turbo compatible decoding (byte for byte the same output as djpeg/jpeg_read_scanlines())
+    //
+    // Selects the integer IDCT (jidctint.c, and jidctred.c for the scaled decoding), the
+    // BT.601 full range fixed-point conversion (jdcolor.c) and the built-in converter.
+    // `fancy_upsampling` corresponds to do_fancy_upsampling; without it, the box upsampling
+    // gives the same result as the merged upsampling (jdmerge.c) of libjpeg.
+    pub fn set_libjpeg_compatible(&mut self, fancy_upsampling: bool)
+    {
+        self.set_idct_method(JpegIdctMethod::Integer);
+        self.reset_idct();
+        self.set_color_conversion(jpeg_color_converter::JpegColorConversion::new());
+        self.reset_color_converter();
+        self.set_upsampling(if fancy_upsampling { JpegUpsampling::Fancy } else { JpegUpsampling::Box });
+    }
+
+    // Whether the current settings give the same output as libjpeg-turbo
+    //
+    // The dithering of RGB565 differs from the one of libjpeg-turbo.
+    pub fn is_libjpeg_compatible(&self) -> bool
+    {
+        self.idct_method == JpegIdctMethod::Integer
+            && self.idct.is_none()
+            && *self.out_buffer_info.get_color_conversion() == jpeg_color_converter::JpegColorConversion::new()
+            && self.color_converter.is_none()
+            && self.out_buffer_info.get_pixel_format() != jpeg_outbuffer_info::JpegPixelFormat::Rgb565
+    }
+
+    // Empty planes of the real image size (or the crop rectangle) for the planar output
+    fn create_planar_image(&self, layout: JpegPlanarLayout) -> JpegPlanarImage
+    {
+        let mcu = self.create_mcu();
+        let (width, height) = self.out_buffer_info.get_dimension();
+        let (offset_x, offset_y) = self.out_buffer_info.get_crop_offset();
+        let mut image = JpegPlanarImage::new(layout);
+        let num_planes = if self.is_luma_only() { mcu.get_num_components().min(1) } else { mcu.get_num_components() };
+        for cid in 0..num_planes
+        {
+            let (ratio_h, ratio_v) = match layout
+            {
+                JpegPlanarLayout::Native => mcu.get_upsampling_ratio(cid),
+                JpegPlanarLayout::FullResolution => (1, 1),
+            };
+            // Samples covering the crop rectangle
+            image.add_plane(JpegPlane::new(
+                (offset_x + width).div_ceil(ratio_h) - offset_x / ratio_h,
+                (offset_y + height).div_ceil(ratio_v) - offset_y / ratio_v
+            ));
+        }
+        image
+    }
+
+    // Bit stream reader pointing to the beginning of the scan
+    pub fn get_bit_stream_reader(&self) -> jpeg_raw_data::JpegBitStreamReader<'_>
+    {
+        let mut bsreader = jpeg_raw_data::JpegBitStreamReader::new(&self.rawdata);
+        bsreader.set_pos(self.img_start, 0);
+        bsreader
+    }
+
+    // Number of MCUs in horizontal direction
+    pub fn get_num_mcus_h(&self, mcu: &jpeg_sample_block::JpegMinimumCodedUnit) -> usize
+    {
+        self.work_buffer_info.get_width() / mcu.get_width()
+    }
+
+    // Number of MCUs in the image
+    pub fn get_num_mcus(&self, mcu: &jpeg_sample_block::JpegMinimumCodedUnit) -> usize
+    {
+        self.get_num_mcus_h(mcu) * (self.work_buffer_info.get_height() / mcu.get_height())
+    }
+
+    // Working buffer for one MCU row
+    pub fn create_mcu_row_buffer(&self, mcu: &jpeg_sample_block::JpegMinimumCodedUnit) -> JpegMcuRowBuffer
+    {
+        let num_mcus_h = self.get_num_mcus_h(mcu);
+        let (width, height) = self.out_buffer_info.get_image_dimension();
+        let num_planes = if self.is_luma_only() { mcu.get_num_components().min(1) } else { mcu.get_num_components() };
+        let create_planes = ||
+        {
+            let mut planes = Vec::new();
+            for cid in 0..num_planes
+            {
+                let (nh, nv) = mcu.get_num_blocks_hv(cid);
+                let (ratio_h, ratio_v) = mcu.get_upsampling_ratio(cid);
+                let size = mcu.get_block_size(cid);
+                planes.push(JpegComponentPlane::new(
+                    num_mcus_h * nh * size,
+                    nv * size,
+                    ratio_h,
+                    ratio_v,
+                    width.div_ceil(ratio_h),
+                    height.div_ceil(ratio_v)
+                ));
+            }
+            planes
+        };
+        let planes = create_planes();
+
+        let mut sampler = JpegSampler::new();
+        sampler.set_color_converter(self.get_color_converter());
+        sampler.set_luma_only(self.is_luma_only());
+        // No fancy upsampling for 1x1 blocks (as libjpeg)
+        let upsampling = match self.out_buffer_info.get_scale()
+        {
+            JpegScale::Eighth => JpegUpsampling::Box,
+            _ => self.out_buffer_info.get_upsampling(),
+        };
+        sampler.set_upsampling(upsampling);
+        sampler.set_row_width(self.work_buffer_info.get_width());
+        let needs_context = sampler.needs_context_rows(&planes);
+        let (_, columns) = self.get_transformed_mcus(mcu, needs_context);
+        sampler.set_first_column(columns.start * mcu.get_width());
 
-                out_pos += stride_h;
+        JpegMcuRowBuffer
+        {
+            needs_context,
+            pending: create_planes(),
+            planes,
+            sampler,
+            work: vec![0; self.work_buffer_info.get_row_size()],
+            out: vec![0; self.out_buffer_info.get_row_size() * mcu.get_height()],
+            height: mcu.get_height(),
+        }
+    }
+
+    // True if a restart interval begins at the MCU
+    pub fn is_restart_mcu(&self, mcu_index: usize) -> bool
+    {
+        self.restart_interval > 0 && mcu_index.is_multiple_of(self.restart_interval)
+    }
+
+    // Skips the RSTn marker at the end of a restart interval and resets the DC predictors
+    //
+    // Returns false if the data ended before the marker.
+    pub fn process_restart(
+        &self,
+        mcu: &mut jpeg_sample_block::JpegMinimumCodedUnit,
+        bsreader: &mut jpeg_raw_data::JpegBitStreamReader
+    ) -> bool
+    {
+        match bsreader.read_marker()
+        {
+            Some(0xD0..=0xD7) => {}
+            Some(m) => println!("Unexpected marker {:02x} instead of RSTn.", m),
+            None => return false,
+        }
+        mcu.set_dc_predictors([0; JPEG_MAX_NUM_OF_COMPONENTS]);
+        true
+    }
+
+    // Decodes one MCU and stores it into the MCU row buffer at the horizontal MCU position
+    pub fn decode_mcu(
+        &self,
+        mcu: &mut jpeg_sample_block::JpegMinimumCodedUnit,
+        bsreader: &mut jpeg_raw_data::JpegBitStreamReader,
+        row_buf: &mut JpegMcuRowBuffer,
+        mcu_x: usize
+    )
+    {
+        mcu.fill_coefficients(&self.dht_mgr, bsreader);
+        // mcu.dump();
+        mcu.transform(&self.dqt_mgr);
+        // mcu.dump();
+        mcu.store_blocks(&mut row_buf.planes, mcu_x);
+    }
+
+    // Rows of a completed MCU row which are inside the crop rectangle (the whole image by default)
+    fn get_output_rows(&self, row_buf: &JpegMcuRowBuffer, mcu_y: usize) -> std::ops::Range<usize>
+    {
+        let y = mcu_y * row_buf.height;
+        let (_, offset_y) = self.out_buffer_info.get_crop_offset();
+        let end = (offset_y + self.out_buffer_info.get_height()).saturating_sub(y).min(row_buf.height);
+        offset_y.saturating_sub(y).min(end)..end
+    }
+
+    // Passes a completed MCU row to the sink
+    // (upsampled, color-converted, cropped to the real image size and packed into the output pixel format)
+    pub fn write_mcu_row(
+        &self,
+        row_buf: &mut JpegMcuRowBuffer,
+        mcu_y: usize,
+        sink: &mut dyn JpegRowSink
+    )
+    {
+        let y = mcu_y * row_buf.height;
+        let rows = self.get_output_rows(row_buf, mcu_y);
+        if rows.is_empty()
+        {
+            return;
+        }
+
+        // The rows are converted from the first column of the sampler, and the pixels
+        // outside the crop rectangle (or the padding of the right edge MCU) are dropped.
+        let (offset_x, offset_y) = self.out_buffer_info.get_crop_offset();
+        let first_column = row_buf.sampler.get_first_column();
+        let row_size = self.out_buffer_info.get_row_size();
+        let left = (offset_x - first_column) * JPEG_WORK_BPP;
+        let num_pixels = (offset_x + self.out_buffer_info.get_width() - first_column) * JPEG_WORK_BPP;
+        if row_buf.sampler.is_luma_only()
+        {
+            // Gray8 directly from the Y plane
+            let (left, right) = (left / JPEG_WORK_BPP, num_pixels / JPEG_WORK_BPP);
+            for (n, i) in rows.clone().enumerate()
+            {
+                row_buf.sampler.convert_row_luma(&row_buf.pending, i, &mut row_buf.work[0..right]);
+                row_buf.out[n * row_size..(n + 1) * row_size].copy_from_slice(&row_buf.work[left..right]);
+            }
+            sink.write_rows(y + rows.start - offset_y, &row_buf.out[0..rows.len() * row_size], row_size);
+            return;
+        }
+        for (n, i) in rows.clone().enumerate()
+        {
+            row_buf.sampler.convert_row(&row_buf.pending, i, &mut row_buf.work[0..num_pixels]);
+            jpeg_color_converter::pack_pixels(
+                self.out_buffer_info.get_pixel_format(),
+                self.out_buffer_info.is_dithering_enabled(),
+                &row_buf.work[left..num_pixels],
+                &mut row_buf.out[n * row_size..(n + 1) * row_size],
+                offset_x,
+                y + i
+            );
+        }
+        sink.write_rows(y + rows.start - offset_y, &row_buf.out[0..rows.len() * row_size], row_size);
+    }
+
+    // Copies a completed MCU row into the planes of a planar image
+    fn write_mcu_row_planar(
+        &self,
+        row_buf: &mut JpegMcuRowBuffer,
+        mcu_y: usize,
+        image: &mut JpegPlanarImage
+    )
+    {
+        let y = mcu_y * row_buf.height;
+        let rows = self.get_output_rows(row_buf, mcu_y);
+        let (offset_x, offset_y) = self.out_buffer_info.get_crop_offset();
+        let layout = image.get_layout();
+        for (cid, plane) in row_buf.pending.iter().enumerate()
+        {
+            let dst = image.get_plane_mut(cid);
+            match layout
+            {
+                JpegPlanarLayout::Native =>
+                {
+                    // The crop rectangle in the samples of the plane
+                    let (ratio_h, ratio_v) = plane.get_ratio();
+                    let (x0, y0) = (offset_x / ratio_h, offset_y / ratio_v);
+                    let top = y / ratio_v;
+                    let first = y0.saturating_sub(top).min(plane.get_height());
+                    let end = (y0 + dst.get_height()).saturating_sub(top).min(plane.get_height());
+                    for i in first..end
+                    {
+                        let width = dst.get_width();
+                        dst.get_row_mut(top + i - y0).copy_from_slice(&plane.get_row(i)[x0..x0 + width]);
+                    }
+                }
+                JpegPlanarLayout::FullResolution =>
+                {
+                    let first_column = row_buf.sampler.get_first_column();
+                    let (left, right) = (offset_x - first_column, offset_x + dst.get_width() - first_column);
+                    for i in rows.clone()
+                    {
+                        row_buf.sampler.upsample_row(plane, i, &mut row_buf.work[0..right]);
+                        dst.get_row_mut(y + i - offset_y).copy_from_slice(&row_buf.work[left..right]);
+                    }
+                }
             }
-            out_pos += stride_v;
         }
     }
 }
 
 //========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // 120x300 4:2:0 image without restart markers (19 MCU rows)
+    const P420: &[u8] = include_bytes!("../testdata/p420.jpg");
+    // 40x90 (3x6 MCUs) with restart intervals of 6 MCUs (the last interval ends at the
+    // last MCU) and of 4 MCUs
+    const P420_RESTART6: &[u8] = include_bytes!("../testdata/p420_restart6.jpg");
+    const P420_RESTART4: &[u8] = include_bytes!("../testdata/p420_restart4.jpg");
+
+    fn decode(data: &[u8], num_threads: usize, scale: JpegScale, upsampling: JpegUpsampling) -> Vec<u8>
+    {
+        let mut jpeg = JpegControl::new();
+        jpeg.set_scale(scale);
+        jpeg.set_upsampling(upsampling);
+        jpeg.set_num_threads(num_threads);
+        jpeg.read_from_slice(data);
+        jpeg.parse_markers();
+        let mut buf = vec![0; jpeg.get_total_buffer_size()];
+        jpeg.decode_image(&mut buf);
+        buf
+    }
+
+    // Decodes with the options set by `setup` (before the headers are parsed)
+    fn decode_with(data: &[u8], setup: impl Fn(&mut JpegControl)) -> Vec<u8>
+    {
+        let mut jpeg = JpegControl::new();
+        setup(&mut jpeg);
+        jpeg.read_from_slice(data);
+        jpeg.parse_markers();
+        let mut buf = vec![0; jpeg.get_total_buffer_size()];
+        jpeg.decode_image(&mut buf);
+        buf
+    }
+
+    // decode_planar() with the options set by `setup`
+    fn decode_planar_with(data: &[u8], layout: JpegPlanarLayout, setup: impl Fn(&mut JpegControl)) -> JpegPlanarImage
+    {
+        let mut jpeg = JpegControl::new();
+        setup(&mut jpeg);
+        jpeg.read_from_slice(data);
+        jpeg.parse_markers();
+        jpeg.decode_planar(layout)
+    }
+
+    // Rows of the plane without the stride padding
+    fn plane_rows(plane: &JpegPlane) -> Vec<u8>
+    {
+        (0..plane.get_height()).flat_map(|y| plane.get_row(y).to_vec()).collect()
+    }
+
+    // Narrow images (1 to 5 pixels wide) decoded by libjpeg-turbo
+    // (`w<width>_<h><v>.<fancy|box|half>.rgb`, "half" is fancy at 1/2 scale)
+    #[test]
+    fn narrow_images_match_libjpeg()
+    {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/narrow");
+        let mut names: Vec<String> = Vec::new();
+        for hv in ["22", "21"]
+        {
+            for width in 1..=5
+            {
+                names.push(format!("w{}_{}", width, hv));
+            }
+        }
+        // 4:1:1 is upsampled 2x horizontally at 1/2 scale (2 and 3 chroma samples wide)
+        names.push("w8_41".to_string());
+        names.push("w9_41".to_string());
+
+        for name in &names
+        {
+            let data = std::fs::read(dir.join(format!("{}.jpg", name))).unwrap();
+            for (suffix, scale, fancy) in [("fancy", JpegScale::Full, true),
+                                           ("box", JpegScale::Full, false),
+                                           ("half", JpegScale::Half, true)]
+            {
+                let expected = std::fs::read(dir.join(format!("{}.{}.rgb", name, suffix))).unwrap();
+                let mut jpeg = JpegControl::new();
+                jpeg.set_libjpeg_compatible(fancy);
+                jpeg.set_scale(scale);
+                jpeg.read_from_slice(&data);
+                jpeg.parse_markers();
+                assert!(jpeg.is_libjpeg_compatible());
+                let mut buf = vec![0; jpeg.get_total_buffer_size()];
+                jpeg.decode_image(&mut buf);
+                assert!(buf == expected, "{}.{}", name, suffix);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_sof_is_rejected()
+    {
+        let pos = P420.windows(2).position(|m| m == [0xFF, 0xC0]).unwrap();
+        // SOF0 segment of 6 bytes (without the component info) at the end of the data
+        let mut data = P420[..pos + 10].to_vec();
+        data[pos + 3] = 8;
+        let mut jpeg = JpegControl::new();
+        jpeg.read_from_slice(&data);
+        assert!(!jpeg.parse_headers());
+        assert!(jpeg.get_frame_error() == Some(JpegFrameError::SegmentLength { seg_size: 6 }));
+
+        // 5 components
+        let mut data = P420.to_vec();
+        data[pos + 9] = 5;
+        let mut jpeg = JpegControl::new();
+        jpeg.read_from_slice(&data);
+        assert!(!jpeg.parse_headers());
+        assert!(jpeg.get_frame_error() == Some(JpegFrameError::InvalidNumComponents { num_components: 5 }));
+
+        // Sampling factors of the first component (H = 0, V = 5, and 11 blocks in an MCU)
+        for (sampling_factor, is_rejected) in [(0x02, true), (0x05, true), (0x33, true), (0x42, false)]
+        {
+            let mut data = P420.to_vec();
+            data[pos + 11] = sampling_factor;
+            let mut jpeg = JpegControl::new();
+            jpeg.read_from_slice(&data);
+            assert!(jpeg.parse_headers() != is_rejected);
+            assert!(jpeg.get_frame_error().is_some() == is_rejected);
+        }
+
+        // Quantization table selector of the first component
+        let mut data = P420.to_vec();
+        data[pos + 12] = 4;
+        let mut jpeg = JpegControl::new();
+        jpeg.read_from_slice(&data);
+        assert!(!jpeg.parse_headers());
+        assert!(jpeg.get_frame_error() == Some(JpegFrameError::InvalidTableSelector { component_id: data[pos + 10], table_selector: 4 }));
+
+        // Y 3x2 and Cb 2x1 (the maximum H is not a multiple of 2)
+        let mut data = P420.to_vec();
+        data[pos + 11] = 0x32;
+        data[pos + 14] = 0x21;
+        let mut jpeg = JpegControl::new();
+        jpeg.read_from_slice(&data);
+        assert!(!jpeg.parse_headers());
+        assert!(jpeg.get_frame_error() == Some(JpegFrameError::NonIntegralSamplingRatio { component_id: data[pos + 13], sampling_factor: 0x21 }));
+    }
+
+    // P420 with the first DHT segment replaced by one with the tables in `body`
+    fn replace_first_dht(body: &[u8]) -> Vec<u8>
+    {
+        let pos = P420.windows(2).position(|m| m == [0xFF, 0xC4]).unwrap();
+        let end = pos + 2 + ((P420[pos + 2] as usize) << 8 | P420[pos + 3] as usize);
+        let seg_size = body.len() + 2;
+        let mut data = P420[..pos].to_vec();
+        data.extend_from_slice(&[0xFF, 0xC4, (seg_size >> 8) as u8, seg_size as u8]);
+        data.extend_from_slice(body);
+        data.extend_from_slice(&P420[end..]);
+        data
+    }
+
+    // Table class/ID, the numbers of codes of each length (1 to 16 bits), and the symbols
+    fn dht_table(table_id: u8, counts: &[(usize, u8)], symbols: &[u8]) -> Vec<u8>
+    {
+        let mut table = vec![table_id];
+        let mut tree = [0; 16];
+        for &(length, count) in counts
+        {
+            tree[length - 1] = count;
+        }
+        table.extend_from_slice(&tree);
+        table.extend_from_slice(symbols);
+        table
+    }
+
+    #[test]
+    fn malformed_dht_is_rejected()
+    {
+        let cases = [
+            // 3 codes of 1 bit
+            (dht_table(0x00, &[(1, 3)], &[0, 1, 2]),
+                JpegDhtError::OverSubscribed { table_id: 0x00, code_length: 1 }),
+            // 0, 10, 11 (the all-ones code)
+            (dht_table(0x01, &[(1, 1), (2, 2)], &[0, 1, 2]),
+                JpegDhtError::OverSubscribed { table_id: 0x01, code_length: 2 }),
+            (dht_table(0x10, &[(15, 255), (16, 2)], &[]),
+                JpegDhtError::TooManySymbols { table_id: 0x10, num_symbols: 257 }),
+            // Run 1 without a magnitude / SSSS above 10 / DC SSSS above 11
+            (dht_table(0x10, &[(2, 2)], &[0x01, 0x10]),
+                JpegDhtError::InvalidSymbol { table_id: 0x10, symbol: 0x10 }),
+            (dht_table(0x11, &[(2, 2)], &[0x01, 0x0B]),
+                JpegDhtError::InvalidSymbol { table_id: 0x11, symbol: 0x0B }),
+            (dht_table(0x00, &[(2, 2)], &[0x00, 0x0C]),
+                JpegDhtError::InvalidSymbol { table_id: 0x00, symbol: 0x0C }),
+            // Class 2 / ID 4
+            (dht_table(0x20, &[(2, 1)], &[0]),
+                JpegDhtError::InvalidTableId { table_id: 0x20 }),
+            (dht_table(0x14, &[(2, 1)], &[0]),
+                JpegDhtError::InvalidTableId { table_id: 0x14 }),
+            // 12 symbols with 5 of them in the segment / the counts cut off
+            (dht_table(0x00, &[(3, 5), (4, 7)], &[0, 1, 2, 3, 4]),
+                JpegDhtError::SegmentLength { table_id: 0x00, num_symbols: 12, remaining: 5 }),
+            (vec![0x00, 1, 2, 3],
+                JpegDhtError::SegmentLength { table_id: 0x00, num_symbols: 0, remaining: 4 }),
+        ];
+        for (body, error) in cases
+        {
+            let data = replace_first_dht(&body);
+            let mut jpeg = JpegControl::new();
+            jpeg.read_from_slice(&data);
+            assert!(!jpeg.parse_headers(), "{:?}", error);
+            assert!(jpeg.get_dht_error() == Some(error), "{:?} {:?}", jpeg.get_dht_error(), error);
+        }
+
+        // A valid table followed by a truncated one in the same segment
+        let mut body = dht_table(0x00, &[(2, 3)], &[0, 1, 2]);
+        body.extend_from_slice(&[0x01, 0, 1]);
+        let data = replace_first_dht(&body);
+        let mut jpeg = JpegControl::new();
+        jpeg.read_from_slice(&data);
+        assert!(!jpeg.parse_headers());
+        assert!(jpeg.get_dht_error() == Some(JpegDhtError::SegmentLength { table_id: 0x01, num_symbols: 0, remaining: 3 }));
+    }
+
+    #[test]
+    fn invalid_scan_table_selector_is_rejected()
+    {
+        // DC table 2 and AC table 3 for the Y component of the scan (no DHT defines them)
+        let pos = P420.windows(2).position(|m| m == [0xFF, 0xDA]).unwrap();
+        for (table_selector, table_id) in [(0x20, 0x02), (0x03, 0x13)]
+        {
+            let mut data = P420.to_vec();
+            data[pos + 6] = table_selector;
+            let mut jpeg = JpegControl::new();
+            jpeg.read_from_slice(&data);
+            assert!(!jpeg.parse_headers());
+            assert!(jpeg.get_dht_error() == Some(JpegDhtError::UndefinedTable { table_id }));
+        }
+
+        // Td/Ta above 3
+        for table_selector in [0x40, 0x04]
+        {
+            let mut data = P420.to_vec();
+            data[pos + 6] = table_selector;
+            let mut jpeg = JpegControl::new();
+            jpeg.read_from_slice(&data);
+            assert!(!jpeg.parse_headers());
+        }
+    }
+
+    #[test]
+    fn pipelined_equals_serial()
+    {
+        for scale in [JpegScale::Full, JpegScale::Half, JpegScale::Quarter, JpegScale::Eighth]
+        {
+            for upsampling in [JpegUpsampling::Box, JpegUpsampling::Fancy]
+            {
+                let serial = decode(P420, 1, scale, upsampling);
+                for num_threads in [2, 3, 5]
+                {
+                    assert!(decode(P420, num_threads, scale, upsampling) == serial,
+                        "{:?} {:?} {} threads", scale, upsampling, num_threads);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_equals_serial()
+    {
+        let decode_cropped = |data: &[u8], num_threads: usize, upsampling: JpegUpsampling, crop: Option<(usize, usize, usize, usize)>|
+        {
+            let mut jpeg = JpegControl::new();
+            jpeg.set_upsampling(upsampling);
+            jpeg.set_num_threads(num_threads);
+            if let Some((x, y, width, height)) = crop
+            {
+                jpeg.set_crop(x, y, width, height);
+            }
+            jpeg.read_from_slice(data);
+            jpeg.parse_markers();
+            // The image is split at the restart markers (not pipelined).
+            assert!(jpeg.get_restart_offsets().is_some());
+            let mut buf = vec![0; jpeg.get_total_buffer_size()];
+            jpeg.decode_image(&mut buf);
+            buf
+        };
+
+        for data in [P420_RESTART6, P420_RESTART4]
+        {
+            for crop in [None, Some((5, 17, 30, 50)), Some((0, 40, 40, 9))]
+            {
+                for upsampling in [JpegUpsampling::Box, JpegUpsampling::Fancy]
+                {
+                    let serial = decode_cropped(data, 1, upsampling, crop);
+                    for num_threads in [2, 3, 6]
+                    {
+                        assert!(decode_cropped(data, num_threads, upsampling, crop) == serial,
+                            "{:?} {:?} {} threads", crop, upsampling, num_threads);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_formats_equal_rgb()
+    {
+        use jpeg_outbuffer_info::JpegPixelFormat;
+        let rgb = decode(P420, 1, JpegScale::Full, JpegUpsampling::Fancy);
+        let decode_as = |format: JpegPixelFormat, dithering: bool|
+            decode_with(P420, |jpeg| { jpeg.set_pixel_format(format); jpeg.set_dithering(dithering); });
+
+        // The channels of each pixel (R, G, B / alpha), in the order of the format
+        for (format, order) in [(JpegPixelFormat::Rgba, [0, 1, 2, 3]), (JpegPixelFormat::Bgr, [2, 1, 0, 4]),
+                                (JpegPixelFormat::Bgra, [2, 1, 0, 3])]
+        {
+            let bpp = format.get_bpp();
+            let expected: Vec<u8> = rgb.chunks_exact(3)
+                .flat_map(|s| order[..bpp].iter().map(|&i| if i < 3 { s[i] } else { 0xFF }).collect::<Vec<_>>())
+                .collect();
+            assert!(decode_as(format, false) == expected, "{:?}", format);
+        }
+
+        // RGB565 (little endian), truncated or with the 4x4 ordered dithering
+        let width = 120;
+        const DITHER: [[u16; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+        for dithering in [false, true]
+        {
+            let expected: Vec<u8> = rgb.chunks_exact(3).enumerate().flat_map(|(i, s)|
+            {
+                let t = if dithering { DITHER[(i / width) % 4][(i % width) % 4] } else { 0 };
+                let r = (s[0] as u16 + t / 2).min(255) >> 3;
+                let g = (s[1] as u16 + t / 4).min(255) >> 2;
+                let b = (s[2] as u16 + t / 2).min(255) >> 3;
+                [(g << 5 | b) as u8, (r << 3 | g >> 3) as u8]
+            }).collect();
+            assert!(decode_as(JpegPixelFormat::Rgb565, dithering) == expected, "dithering {}", dithering);
+        }
+    }
+
+    #[test]
+    fn crop_equals_rectangle_of_full_image()
+    {
+        for (data, width, height) in [(P420, 120, 300), (P420_RESTART4, 40, 90)]
+        {
+            for scale in [JpegScale::Full, JpegScale::Half, JpegScale::Quarter, JpegScale::Eighth]
+            {
+                let (width, height) = (scale.get_scaled_size(width), scale.get_scaled_size(height));
+                let full = decode(data, 1, scale, JpegUpsampling::Fancy);
+                // Inside, at the bottom-right corner, a single pixel and the whole image
+                let rects = [(width / 5, height / 7, width / 2, height / 3),
+                             (width / 3, height / 2, width - width / 3, height - height / 2),
+                             (width - 1, height / 2, 1, 1),
+                             (0, 0, width, height)];
+                for (x, y, w, h) in rects
+                {
+                    let expected: Vec<u8> = (y..y + h)
+                        .flat_map(|row| full[(row * width + x) * 3..(row * width + x + w) * 3].to_vec())
+                        .collect();
+                    for num_threads in [1, 2, 3]
+                    {
+                        let cropped = decode_with(data, |jpeg|
+                        {
+                            jpeg.set_scale(scale);
+                            jpeg.set_num_threads(num_threads);
+                            jpeg.set_crop(x, y, w, h);
+                        });
+                        assert!(cropped == expected, "{:?} {:?} {} threads", scale, (x, y, w, h), num_threads);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn padded_and_bottom_up_layouts()
+    {
+        for data in [P420, P420_RESTART4]
+        {
+            let packed = decode(data, 1, JpegScale::Full, JpegUpsampling::Fancy);
+            for num_threads in [1, 3]
+            {
+                let mut jpeg = JpegControl::new();
+                jpeg.set_num_threads(num_threads);
+                jpeg.read_from_slice(data);
+                jpeg.parse_markers();
+                let (width, height) = jpeg.get_dimension();
+                let row_size = width * 3;
+
+                // (origin, pitch): padded top-down rows, bottom-up rows
+                let bottom_up_pitch = row_size + 9;
+                for (origin, pitch) in [(5, (row_size + 13) as isize),
+                                        (7 + (height - 1) * bottom_up_pitch, -(bottom_up_pitch as isize))]
+                {
+                    jpeg.set_output_layout(origin, pitch);
+                    let mut buf = vec![0xA5; jpeg.get_total_buffer_size() + 11];
+                    jpeg.decode_image(&mut buf);
+
+                    let mut is_pixel = vec![false; buf.len()];
+                    for y in 0..height
+                    {
+                        let pos = (origin as isize + y as isize * pitch) as usize;
+                        assert!(buf[pos..pos + row_size] == packed[y * row_size..(y + 1) * row_size],
+                            "{} threads, pitch {}, row {}", num_threads, pitch, y);
+                        is_pixel[pos..pos + row_size].fill(true);
+                    }
+                    // The padding is untouched.
+                    assert!(buf.iter().zip(&is_pixel).all(|(b, is_pixel)| *is_pixel || *b == 0xA5),
+                        "{} threads, pitch {}", num_threads, pitch);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn luma_only_equals_gray8()
+    {
+        use jpeg_outbuffer_info::JpegPixelFormat;
+        for data in [P420, P420_RESTART4]
+        {
+            for scale in [JpegScale::Full, JpegScale::Half]
+            {
+                for num_threads in [1, 3]
+                {
+                    let setup = |jpeg: &mut JpegControl|
+                    {
+                        jpeg.set_scale(scale);
+                        jpeg.set_num_threads(num_threads);
+                        jpeg.set_pixel_format(JpegPixelFormat::Gray8);
+                    };
+                    let gray = decode_with(data, setup);
+                    let luma_only = decode_with(data, |jpeg| { setup(jpeg); jpeg.set_luma_only(true); });
+                    assert!(luma_only == gray, "{:?} {} threads", scale, num_threads);
+
+                    // Only the Y plane, with the same samples as the gray levels (full range BT.601)
+                    let image = decode_planar_with(data, JpegPlanarLayout::Native, |jpeg| { setup(jpeg); jpeg.set_luma_only(true); });
+                    assert!(image.get_num_planes() == 1);
+                    assert!(plane_rows(image.get_plane(0)) == gray, "{:?} {} threads", scale, num_threads);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn planar_layouts()
+    {
+        use jpeg_outbuffer_info::JpegPixelFormat;
+        let gray = decode_with(P420, |jpeg| jpeg.set_pixel_format(JpegPixelFormat::Gray8));
+
+        // 120x300 4:2:0: Y, Cb, Cr
+        for (layout, sizes) in [(JpegPlanarLayout::Native, [(120, 300), (60, 150), (60, 150)]),
+                                (JpegPlanarLayout::FullResolution, [(120, 300); 3])]
+        {
+            let image = decode_planar_with(P420, layout, |_| ());
+            assert!(image.get_layout() == layout);
+            assert!(image.get_num_planes() == 3);
+            for (i, &(width, height)) in sizes.iter().enumerate()
+            {
+                let plane = image.get_plane(i);
+                assert!((plane.get_width(), plane.get_height()) == (width, height), "{:?} plane {}", layout, i);
+                assert!(plane.get_stride() == width && plane.get_data().len() == width * height);
+            }
+            // The Y plane is not resampled.
+            assert!(plane_rows(image.get_plane(0)) == gray, "{:?}", layout);
+        }
+
+        // Odd crop rectangle: the chroma samples covering it (x 7..=68 -> 3..=34, y 5..=45 -> 2..=22)
+        let image = decode_planar_with(P420, JpegPlanarLayout::Native, |jpeg| jpeg.set_crop(7, 5, 62, 41));
+        assert!((image.get_plane(0).get_width(), image.get_plane(0).get_height()) == (62, 41));
+        assert!((image.get_plane(1).get_width(), image.get_plane(1).get_height()) == (32, 21));
+    }
+
+    // Converter which records the rows it is given, and outputs the inverted Y samples
+    struct JpegRecordingConverter
+    {
+        calls: Arc<Mutex<Vec<(usize, usize, usize)>>>,   // (components, width, output size)
+    }
+
+    impl JpegColorConvert for JpegRecordingConverter
+    {
+        fn convert_row(&self, components: &[&[u8]], out: &mut [u8])
+        {
+            self.calls.lock().unwrap().push((components.len(), components[0].len(), out.len()));
+            for (d, y) in out.chunks_exact_mut(3).zip(components[0])
+            {
+                d.fill(255 - y);
+            }
+        }
+    }
+
+    #[test]
+    fn user_color_converter_is_called()
+    {
+        use jpeg_outbuffer_info::JpegPixelFormat;
+        let rgb = decode(P420, 1, JpegScale::Full, JpegUpsampling::Fancy);
+        let gray = decode_with(P420, |jpeg| jpeg.set_pixel_format(JpegPixelFormat::Gray8));
+        let inverted: Vec<u8> = gray.iter().flat_map(|y| [255 - y; 3]).collect();
+        for num_threads in [1, 3]
+        {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let mut jpeg = JpegControl::new();
+            jpeg.set_num_threads(num_threads);
+            jpeg.set_color_converter(JpegRecordingConverter { calls: calls.clone() });
+            jpeg.read_from_slice(P420);
+            jpeg.parse_markers();
+            let mut buf = vec![0; jpeg.get_total_buffer_size()];
+            jpeg.decode_image(&mut buf);
+            assert!(buf == inverted, "{} threads", num_threads);
+
+            // A call for each row of the image, with the 3 components upsampled to its width
+            let calls = calls.lock().unwrap();
+            assert!(calls.len() == 300);
+            assert!(calls.iter().all(|&call| call == (3, 120, 120 * 3)));
+
+            // The built-in converter again
+            jpeg.reset_color_converter();
+            jpeg.decode_image(&mut buf);
+            assert!(buf == rgb);
+        }
+    }
+}
@@ -3,9 +3,59 @@
 //
 //========================================================
 use crate::jpeg_constants::JPEG_MAX_NUM_OF_COMPONENTS;
+use crate::jpeg_constants::JPEG_MCU_MAX_NUM_BLOCKS;
+use crate::jpeg_quantization_table::JPEG_NUM_DQT;
 use crate::jpeg_raw_data;
 
 const JPEG_MCU_NUM_PIXELS_MIN: usize = 8;
+const JPEG_SOF_SEGMENT_MIN_SIZE: usize = 6;
+const JPEG_SOF_COMPONENT_SIZE: usize = 3;
+const JPEG_MAX_SAMPLING_FACTOR: usize = 4;
+
+// Reason why a SOF segment is rejected
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegFrameError
+{
+    // The segment is shorter than the fields (or the components) it describes
+    SegmentLength { seg_size: usize },
+    InvalidNumComponents { num_components: usize },
+    // H or V is outside 1..=4 (`sampling_factor` is the H/V byte)
+    InvalidSamplingFactor { component_id: u8, sampling_factor: u8 },
+    // More than 10 blocks in an MCU of the interleaved components
+    TooManyBlocks { num_blocks: usize },
+    // Tq is outside 0..=3
+    InvalidTableSelector { component_id: u8, table_selector: u8 },
+    // The maximum H or V is not a multiple of the factor of a component
+    NonIntegralSamplingRatio { component_id: u8, sampling_factor: u8 },
+}
+
+impl std::fmt::Display for JpegFrameError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match *self
+        {
+            JpegFrameError::SegmentLength { seg_size } =>
+                write!(f, "Frame header: segment too short ({} bytes)", seg_size),
+            JpegFrameError::InvalidNumComponents { num_components } =>
+                write!(f, "Frame header: invalid number of components ({})", num_components),
+            JpegFrameError::InvalidSamplingFactor { component_id, sampling_factor } =>
+                write!(f, "Frame header: invalid sampling factor {:02x} of component {}", sampling_factor, component_id),
+            JpegFrameError::TooManyBlocks { num_blocks } =>
+                write!(f, "Frame header: too many blocks in an MCU ({})", num_blocks),
+            JpegFrameError::InvalidTableSelector { component_id, table_selector } =>
+                write!(f, "Frame header: invalid quantization table selector {} of component {}", table_selector, component_id),
+            JpegFrameError::NonIntegralSamplingRatio { component_id, sampling_factor } =>
+                write!(f, "Frame header: sampling factor {:02x} of component {} does not divide the maximum", sampling_factor, component_id),
+        }
+    }
+}
+
+impl std::error::Error for JpegFrameError {}
 
 #[derive(Copy)]
 #[derive(Clone)]
@@ -14,12 +64,24 @@ pub struct JpegSamplingFactor
     val: u8,
 }
 
+// Scan header (SOS): components of the scan and their Huffman tables
+pub struct JpegScanHeaderInfo
+{
+    num_components: u8,
+    component_selector: [u8; JPEG_MAX_NUM_OF_COMPONENTS],
+    table_selector: [u8; JPEG_MAX_NUM_OF_COMPONENTS],   // DC table (upper 4bit) / AC table (lower 4bit)
+    spectral_start: u8,
+    spectral_end: u8,
+    approximation: u8,
+}
+
 pub struct JpegFrameHeaderInfo
 {
     height: u16,
     width: u16,
     precision: u8,
     num_components: u8,
+    component_id: [u8; JPEG_MAX_NUM_OF_COMPONENTS],
     sampling_factor: [JpegSamplingFactor; JPEG_MAX_NUM_OF_COMPONENTS],
     qt_selector: [u8; JPEG_MAX_NUM_OF_COMPONENTS],
 }
@@ -56,6 +118,13 @@ impl JpegSamplingFactor
         (self.val & 0x0F) as usize
     }
 
+    // Whether H and V are in 1..=4
+    pub fn is_valid(&self) -> bool
+    {
+        (1..=JPEG_MAX_SAMPLING_FACTOR).contains(&self.get_num_h())
+            && (1..=JPEG_MAX_SAMPLING_FACTOR).contains(&self.get_num_v())
+    }
+
     pub fn get_num_blocks(&self) -> usize
     {
         self.get_num_v() * self.get_num_h()
@@ -84,31 +153,84 @@ impl JpegFrameHeaderInfo
             width: 0,
             precision: 0,
             num_components: 0,
+            component_id: [0; JPEG_MAX_NUM_OF_COMPONENTS],
             sampling_factor: [JpegSamplingFactor::new(); JPEG_MAX_NUM_OF_COMPONENTS],
             qt_selector: [0; JPEG_MAX_NUM_OF_COMPONENTS],
         }
     }
 
     // セグメント内容の parse と読み込み
-    pub fn parse_segment(&mut self, reader: &mut jpeg_raw_data::JpegReader)
+    //
+    // `seg_size` is the size of the segment without the size field.
+    // The frame header is left unchanged if the segment is rejected.
+    pub fn parse_segment(&mut self, reader: &mut jpeg_raw_data::JpegReader, seg_size: usize) -> Result<(), JpegFrameError>
     {
+        if seg_size < JPEG_SOF_SEGMENT_MIN_SIZE
+        {
+            return Err(JpegFrameError::SegmentLength { seg_size });
+        }
+
         // Precision
-        self.precision = reader.read_u8();
+        let precision = reader.read_u8();
 
         // Height / Width
-        self.height = reader.read_u16be();
-        self.width = reader.read_u16be();
+        let height = reader.read_u16be();
+        let width = reader.read_u16be();
 
         // Number of components
-        self.num_components = reader.read_u8();
+        let num_components = reader.read_u8() as usize;
+        if num_components == 0 || num_components > JPEG_MAX_NUM_OF_COMPONENTS
+        {
+            return Err(JpegFrameError::InvalidNumComponents { num_components });
+        }
+        if seg_size < JPEG_SOF_SEGMENT_MIN_SIZE + num_components * JPEG_SOF_COMPONENT_SIZE
+        {
+            return Err(JpegFrameError::SegmentLength { seg_size });
+        }
 
-        // Component info
-        for _i in 0..self.num_components as usize
+        // Component info (stored in the order of appearance, as IDs are not always 1, 2, 3)
+        let mut component_id = [0; JPEG_MAX_NUM_OF_COMPONENTS];
+        let mut sampling_factor = [JpegSamplingFactor::new(); JPEG_MAX_NUM_OF_COMPONENTS];
+        let mut qt_selector = [0; JPEG_MAX_NUM_OF_COMPONENTS];
+        for i in 0..num_components
+        {
+            component_id[i] = reader.read_u8();
+            let val = reader.read_u8();
+            sampling_factor[i].set(val);
+            qt_selector[i] = reader.read_u8();
+            if !sampling_factor[i].is_valid()
+            {
+                return Err(JpegFrameError::InvalidSamplingFactor { component_id: component_id[i], sampling_factor: val });
+            }
+            if qt_selector[i] as usize >= JPEG_NUM_DQT
+            {
+                return Err(JpegFrameError::InvalidTableSelector { component_id: component_id[i], table_selector: qt_selector[i] });
+            }
+        }
+        // The upsampling only handles integral ratios
+        let max_h = sampling_factor[..num_components].iter().map(|f| f.get_num_h()).max().unwrap();
+        let max_v = sampling_factor[..num_components].iter().map(|f| f.get_num_v()).max().unwrap();
+        for i in 0..num_components
+        {
+            if max_h % sampling_factor[i].get_num_h() != 0 || max_v % sampling_factor[i].get_num_v() != 0
+            {
+                return Err(JpegFrameError::NonIntegralSamplingRatio { component_id: component_id[i], sampling_factor: sampling_factor[i].get_raw() as u8 });
+            }
+        }
+        let num_blocks = sampling_factor[..num_components].iter().map(|f| f.get_num_blocks()).sum();
+        if num_components > 1 && num_blocks > JPEG_MCU_MAX_NUM_BLOCKS
         {
-            let component_id = reader.read_u8() as usize - 1;
-            self.sampling_factor[component_id].set(reader.read_u8());
-            self.qt_selector[component_id] = reader.read_u8();
+            return Err(JpegFrameError::TooManyBlocks { num_blocks });
         }
+
+        self.precision = precision;
+        self.height = height;
+        self.width = width;
+        self.num_components = num_components as u8;
+        self.component_id = component_id;
+        self.sampling_factor = sampling_factor;
+        self.qt_selector = qt_selector;
+        Ok(())
     }
 
     // Number of components
@@ -129,6 +251,13 @@ impl JpegFrameHeaderInfo
         (self.width as usize, self.height as usize)
     }
 
+    // Component identifier
+    pub fn get_component_id(&self, index: usize) -> u8
+    {
+        assert!(index < self.num_components as usize);
+        self.component_id[index]
+    }
+
     // Sampling factor
     pub fn get_sampling_factor(&self, index: usize) -> JpegSamplingFactor
     {
@@ -157,7 +286,7 @@ impl JpegFrameHeaderInfo
         {
             println!(
                 "C={} / HV={},{} / TQ={}",
-                i+1,
+                self.component_id[i],
                 self.sampling_factor[i].get_num_h(),
                 self.sampling_factor[i].get_num_v(),
                 self.qt_selector[i]
@@ -167,4 +296,113 @@ impl JpegFrameHeaderInfo
     }
 }
 
+#[allow(dead_code)]
+impl JpegScanHeaderInfo
+{
+    // Constructor
+    pub fn new() -> Self
+    {
+        JpegScanHeaderInfo
+        {
+            num_components: 0,
+            component_selector: [0; JPEG_MAX_NUM_OF_COMPONENTS],
+            table_selector: [0; JPEG_MAX_NUM_OF_COMPONENTS],
+            spectral_start: 0,
+            spectral_end: 0,
+            approximation: 0,
+        }
+    }
+
+    // セグメント内容の parse と読み込み
+    //
+    // `seg_size` is the size of the segment without the size field.
+    // Returns false if the segment is too short for the number of components.
+    pub fn parse_segment(&mut self, reader: &mut jpeg_raw_data::JpegReader, seg_size: usize) -> bool
+    {
+        // Number of components
+        if seg_size < 1
+        {
+            return false;
+        }
+        let num_components = reader.read_u8() as usize;
+        if num_components > JPEG_MAX_NUM_OF_COMPONENTS || seg_size < 1 + num_components * 2 + 3
+        {
+            return false;
+        }
+        self.num_components = num_components as u8;
+
+        // Component selector and table selectors (Td/Ta, 0..=3)
+        for i in 0..num_components
+        {
+            self.component_selector[i] = reader.read_u8();
+            self.table_selector[i] = reader.read_u8();
+            if self.table_selector[i] & 0xCC != 0
+            {
+                return false;
+            }
+        }
+
+        // Spectral selection / successive approximation (progressive only)
+        self.spectral_start = reader.read_u8();
+        self.spectral_end = reader.read_u8();
+        self.approximation = reader.read_u8();
+        true
+    }
+
+    // Number of components of the scan
+    pub fn get_num_components(&self) -> usize
+    {
+        self.num_components as usize
+    }
+
+    // DC/AC Huffman table index of the scan component
+    pub fn get_dc_table_id(&self, index: usize) -> usize
+    {
+        assert!(index < self.num_components as usize);
+        (self.table_selector[index] >> 4) as usize
+    }
+
+    pub fn get_ac_table_id(&self, index: usize) -> usize
+    {
+        assert!(index < self.num_components as usize);
+        (self.table_selector[index] & 0x0F) as usize
+    }
+
+    // Whether the scan decodes the DC/AC coefficients with Huffman tables
+    //
+    // A progressive scan has either the DC or the AC coefficients (Ss = 0 or not),
+    // and the DC refinement (Ah != 0) reads the bits without a table.
+    pub fn uses_dc_table(&self) -> bool
+    {
+        self.spectral_start == 0 && (self.approximation >> 4) == 0
+    }
+
+    pub fn uses_ac_table(&self) -> bool
+    {
+        self.spectral_end != 0
+    }
+
+    // DC/AC Huffman table index of the component with the identifier (None if not in the scan)
+    pub fn get_table_ids(&self, component_id: u8) -> Option<(usize, usize)>
+    {
+        let index = self.component_selector[..self.num_components as usize].iter()
+            .position(|&c| c == component_id)?;
+        Some((self.get_dc_table_id(index), self.get_ac_table_id(index)))
+    }
+
+    // 構造体内容のダンプ
+    pub fn dump(&self)
+    {
+        println!("\n---- Scan Header Info. ----");
+        println!("Num components= {} / Ss= {} / Se= {} / AhAl= {:02x}",
+                 self.num_components, self.spectral_start, self.spectral_end, self.approximation);
+        for i in 0..self.num_components as usize
+        {
+            println!("C={} / TD={} / TA={}",
+                     self.component_selector[i], self.get_dc_table_id(i), self.get_ac_table_id(i));
+        }
+        println!("----------------");
+    }
+}
+
 //========================================================
@@ -9,12 +9,158 @@ use crate::jpeg_raw_data::JpegBitStreamReader;
 const JPEG_NUM_DHT_TREE_BITS: usize = 16;
 const JPEG_DHT_LOG_DETAIL: u8 = 0x01;
 
+// Number of table slots of each class (Th = 0..3)
+const JPEG_NUM_DHT: usize = 4;
+
+// Limits of the symbols (8-bit precision, sequential DCT)
+const JPEG_DHT_MAX_NUM_SYMBOLS: usize = 256;
+const JPEG_DHT_MAX_DC_SSSS: u8 = 11;
+const JPEG_DHT_MAX_AC_SSSS: u8 = 10;
+const JPEG_DHT_AC_EOB: u8 = 0x00;
+const JPEG_DHT_AC_ZRL: u8 = 0xF0;
+
+// Default tables of ITU-T T.81 Annex K.3 (luminance and chrominance), used for the
+// DC/AC table slots 0 and 1 when a scan starts without DHT (e.g. Motion-JPEG frames)
+const JPEG_DHT_DEFAULT_DC_LUMINANCE_BITS: [u8; JPEG_NUM_DHT_TREE_BITS] =
+[
+    0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+const JPEG_DHT_DEFAULT_DC_CHROMINANCE_BITS: [u8; JPEG_NUM_DHT_TREE_BITS] =
+[
+    0x00, 0x03, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+const JPEG_DHT_DEFAULT_DC_VALUES: [u8; 12] =
+[
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+];
+const JPEG_DHT_DEFAULT_AC_LUMINANCE_BITS: [u8; JPEG_NUM_DHT_TREE_BITS] =
+[
+    0x00, 0x02, 0x01, 0x03, 0x03, 0x02, 0x04, 0x03, 0x05, 0x05, 0x04, 0x04,
+    0x00, 0x00, 0x01, 0x7d,
+];
+const JPEG_DHT_DEFAULT_AC_LUMINANCE_VALUES: [u8; 162] =
+[
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06,
+    0x13, 0x51, 0x61, 0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08,
+    0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72,
+    0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59,
+    0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75,
+    0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3,
+    0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+    0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9,
+    0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4,
+    0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+const JPEG_DHT_DEFAULT_AC_CHROMINANCE_BITS: [u8; JPEG_NUM_DHT_TREE_BITS] =
+[
+    0x00, 0x02, 0x01, 0x02, 0x04, 0x04, 0x03, 0x04, 0x07, 0x05, 0x04, 0x04,
+    0x00, 0x01, 0x02, 0x77,
+];
+const JPEG_DHT_DEFAULT_AC_CHROMINANCE_VALUES: [u8; 162] =
+[
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41,
+    0x51, 0x07, 0x61, 0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91,
+    0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1,
+    0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44,
+    0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58,
+    0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74,
+    0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a,
+    0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+    0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+    0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4,
+    0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+// Reason why a table of a DHT segment is rejected
+//
+// `table_id` is the Tc/Th byte of the table (0x00-0x03: DC, 0x10-0x13: AC).
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegDhtError
+{
+    InvalidTableId { table_id: u8 },
+    // The counts of the table need more bytes than left in the segment (or a partial table is left)
+    SegmentLength { table_id: u8, num_symbols: usize, remaining: usize },
+    TooManySymbols { table_id: u8, num_symbols: usize },
+    // Kraft inequality: more codes of the length than the code space left
+    OverSubscribed { table_id: u8, code_length: usize },
+    InvalidSymbol { table_id: u8, symbol: u8 },
+    // The scan uses a slot which no DHT defined and which has no default table (slot 2/3)
+    UndefinedTable { table_id: u8 },
+}
+
+impl std::fmt::Display for JpegDhtError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        let table_name = |id: u8| format!("{} table {}", if id & 0x10 == 0 { "DC" } else { "AC" }, id & 0x0F);
+        match *self
+        {
+            JpegDhtError::InvalidTableId { table_id } =>
+                write!(f, "Invalid Huffman table class/ID {:02x}", table_id),
+            JpegDhtError::SegmentLength { table_id, num_symbols, remaining } =>
+                write!(f, "Huffman {}: {} symbols with {} bytes left in the segment",
+                       table_name(table_id), num_symbols, remaining),
+            JpegDhtError::TooManySymbols { table_id, num_symbols } =>
+                write!(f, "Huffman {}: too many symbols ({})", table_name(table_id), num_symbols),
+            JpegDhtError::OverSubscribed { table_id, code_length } =>
+                write!(f, "Huffman {}: too many codes of length {}", table_name(table_id), code_length),
+            JpegDhtError::InvalidSymbol { table_id, symbol } =>
+                write!(f, "Huffman {}: invalid symbol {:02x}", table_name(table_id), symbol),
+            JpegDhtError::UndefinedTable { table_id } =>
+                write!(f, "Huffman {}: not defined", table_name(table_id)),
+        }
+    }
+}
+
+impl std::error::Error for JpegDhtError {}
+
+// Number of bits resolved by one access to the lookup table
+const JPEG_DHT_LOOKUP_BITS: usize = 9;
+const JPEG_DHT_LOOKUP_SIZE: usize = 1 << JPEG_DHT_LOOKUP_BITS;
+
+// Entry of the first-level lookup table, indexed by the next 9 bits of the stream
+//
+// `length` is 0 when the code is longer than 9 bits. When the code and its magnitude
+// bits fit together in 9 bits, `total_length` covers both and `value` is the decoded
+// coefficient; otherwise `total_length` is 0 and the magnitude is read separately.
+#[derive(Copy)]
+#[derive(Clone)]
+struct JpegHuffmanLookup
+{
+    length: u8,
+    symbol: u8,
+    total_length: u8,
+    value: i16,
+}
+
 struct JpegHuffmanTable
 {
     tree: [u8; JPEG_NUM_DHT_TREE_BITS],
     encoding: Vec<u8>,
     bit_pattern: Vec<u16>,
     bit_length: Vec<u8>,
+    lookup: Vec<JpegHuffmanLookup>,
+    // Largest code, smallest code and index of the first symbol of each code length
+    // (Annex F.2.2.3), for the codes longer than the lookup table
+    maxcode: [i32; JPEG_NUM_DHT_TREE_BITS + 1],
+    mincode: [i32; JPEG_NUM_DHT_TREE_BITS + 1],
+    valptr: [usize; JPEG_NUM_DHT_TREE_BITS + 1],
+    is_ac: bool,
+    is_defined: bool,
+    is_default: bool,
     table_id: u8,
     log_control: u8,
 }
@@ -22,8 +168,8 @@ struct JpegHuffmanTable
 #[allow(dead_code)]
 pub struct JpegDhtManager
 {
-    dc: [JpegHuffmanTable; 2],
-    ac: [JpegHuffmanTable; 2],
+    dc: [JpegHuffmanTable; JPEG_NUM_DHT],
+    ac: [JpegHuffmanTable; JPEG_NUM_DHT],
     previous_dc: [i16; JPEG_MAX_NUM_OF_COMPONENTS],
 }
 
@@ -39,39 +185,123 @@ impl JpegHuffmanTable
             encoding: Vec::new(),
             bit_pattern: Vec::new(),
             bit_length:Vec::new(),
+            lookup: Vec::new(),
+            maxcode: [-1; JPEG_NUM_DHT_TREE_BITS + 1],
+            mincode: [0; JPEG_NUM_DHT_TREE_BITS + 1],
+            valptr: [0; JPEG_NUM_DHT_TREE_BITS + 1],
+            is_ac: false,
+            is_defined: false,
+            is_default: false,
             table_id: 0,
             log_control: 0,
         }
     }
 
-    // セグメント内容の parse と読み込み
-    fn parse_segment(&mut self, reader: &mut JpegReader)
+    // テーブル内容の parse と読み込み
+    //
+    // Reads one table of a DHT segment, where `remaining` bytes are left in the segment,
+    // and returns the number of bytes read.
+    fn parse_table(&mut self, reader: &mut JpegReader, remaining: usize) -> Result<usize, JpegDhtError>
     {
-        // Segment size
-        let seg_size = reader.read_u16be();
-
         // Table ID, AD/DC flag
         self.table_id = reader.read_u8();
+        self.is_ac = self.table_id & 0x10 != 0;
+        if self.table_id & 0xEF >= JPEG_NUM_DHT as u8
+        {
+            return Err(JpegDhtError::InvalidTableId { table_id: self.table_id });
+        }
+        if remaining < JPEG_NUM_DHT_TREE_BITS + 1
+        {
+            return Err(JpegDhtError::SegmentLength { table_id: self.table_id, num_symbols: 0, remaining });
+        }
 
         // Huffman tree info
         for i in 0..JPEG_NUM_DHT_TREE_BITS
         {
             self.tree[i] = reader.read_u8();
         }
+        self.validate_tree(remaining - (JPEG_NUM_DHT_TREE_BITS + 1))?;
 
         // Encoding info
-        let encoding_size = seg_size as usize - (JPEG_NUM_DHT_TREE_BITS + 3);
+        let encoding_size = self.tree.iter().map(|&n| n as usize).sum::<usize>();
         self.encoding = Vec::<u8>::with_capacity(encoding_size);
         for _i in 0..encoding_size
         {
             let e = reader.read_u8();
             self.encoding.push(e);
         }
+        self.validate_symbols()?;
 
         // Bit pattern
         self.bit_pattern = Vec::<u16>::with_capacity(encoding_size);
         self.bit_length = Vec::<u8>::with_capacity(encoding_size);
         self.create_bit_pattern();
+        self.is_defined = true;
+        Ok(JPEG_NUM_DHT_TREE_BITS + 1 + encoding_size)
+    }
+
+    // Table from the code counts and the symbols (for the default tables)
+    fn from_spec(table_id: u8, tree: &[u8; JPEG_NUM_DHT_TREE_BITS], encoding: &[u8]) -> Self
+    {
+        let mut table = JpegHuffmanTable::new();
+        table.table_id = table_id;
+        table.is_ac = table_id & 0x10 != 0;
+        table.tree = *tree;
+        table.encoding = encoding.to_vec();
+        table.create_bit_pattern();
+        table.is_defined = true;
+        table.is_default = true;
+        table
+    }
+
+    // Checks the number of codes of each length against the bytes left and the
+    // code space (Kraft inequality; the all-ones code is not allowed, as libjpeg)
+    fn validate_tree(&self, remaining: usize) -> Result<(), JpegDhtError>
+    {
+        let table_id = self.table_id;
+        let num_symbols = self.tree.iter().map(|&n| n as usize).sum::<usize>();
+        if num_symbols > JPEG_DHT_MAX_NUM_SYMBOLS
+        {
+            return Err(JpegDhtError::TooManySymbols { table_id, num_symbols });
+        }
+        if num_symbols > remaining
+        {
+            return Err(JpegDhtError::SegmentLength { table_id, num_symbols, remaining });
+        }
+
+        let mut code: u32 = 0;
+        for i in 0..JPEG_NUM_DHT_TREE_BITS
+        {
+            code += self.tree[i] as u32;
+            if code >= 1 << (i + 1)
+            {
+                return Err(JpegDhtError::OverSubscribed { table_id, code_length: i + 1 });
+            }
+            code <<= 1;
+        }
+        Ok(())
+    }
+
+    // Checks the symbols: SSSS for DC, RRRR/SSSS for AC
+    fn validate_symbols(&self) -> Result<(), JpegDhtError>
+    {
+        for &symbol in self.encoding.iter()
+        {
+            let is_valid = if self.is_ac
+            {
+                let ssss = symbol & 0x0F;
+                (ssss > 0 && ssss <= JPEG_DHT_MAX_AC_SSSS) || symbol == JPEG_DHT_AC_EOB || symbol == JPEG_DHT_AC_ZRL
+            }
+            else
+            {
+                symbol <= JPEG_DHT_MAX_DC_SSSS
+            };
+            if !is_valid
+            {
+                return Err(JpegDhtError::InvalidSymbol { table_id: self.table_id, symbol });
+            }
+        }
+        Ok(())
     }
 
     // Log control
@@ -101,107 +331,164 @@ impl JpegHuffmanTable
             }
             base >>= 1;
         }
+        self.create_lookup();
     }
 
-    // SSSS ビット数に応じた係数値の展開
-    fn unpack_coefficient(&self, n_bits_ssss: u8, packed_data: u16) -> i16
+    // Lookup table and maxcode/valptr tables from the bit patterns
+    fn create_lookup(&mut self)
     {
-        let bs = packed_data >> (16 - n_bits_ssss);
-        if (packed_data & 0x8000) == 0
+        self.maxcode = [-1; JPEG_NUM_DHT_TREE_BITS + 1];
+        let mut k = 0;
+        for len in 1..=JPEG_NUM_DHT_TREE_BITS
         {
-            // MSB=0 -> negative
-            - (1 << n_bits_ssss) + 1 + bs as i16
+            let n = self.tree[len - 1] as usize;
+            if n > 0 && k + n <= self.bit_pattern.len()
+            {
+                self.valptr[len] = k;
+                self.mincode[len] = (self.bit_pattern[k] >> (16 - len)) as i32;
+                self.maxcode[len] = (self.bit_pattern[k + n - 1] >> (16 - len)) as i32;
+            }
+            k += n;
         }
-        else
+
+        let empty = JpegHuffmanLookup { length: 0, symbol: 0, total_length: 0, value: 0 };
+        self.lookup = vec![empty; JPEG_DHT_LOOKUP_SIZE];
+        let num_codes = self.bit_pattern.len().min(self.encoding.len());
+        for i in 0..num_codes
         {
-            // MSB=1 -> positive
-            bs as i16
-        }       
+            let len = self.bit_length[i] as usize;
+            if len > JPEG_DHT_LOOKUP_BITS
+            {
+                break;
+            }
+            let symbol = self.encoding[i];
+            let ssss = (if self.is_ac { symbol & 0x0F } else { symbol }) as usize;
+            let first = (self.bit_pattern[i] >> (16 - JPEG_DHT_LOOKUP_BITS)) as usize;
+            for index in first..first + (1 << (JPEG_DHT_LOOKUP_BITS - len))
+            {
+                let mut entry = JpegHuffmanLookup { length: len as u8, symbol, total_length: 0, value: 0 };
+                // Short code with its magnitude bits: the coefficient is resolved here.
+                if len + ssss <= JPEG_DHT_LOOKUP_BITS
+                {
+                    entry.total_length = (len + ssss) as u8;
+                    if ssss > 0
+                    {
+                        let packed = (index << (16 - JPEG_DHT_LOOKUP_BITS + len)) as u16;
+                        entry.value = self.unpack_coefficient(ssss as u8, packed);
+                    }
+                }
+                self.lookup[index] = entry;
+            }
+        }
     }
 
-    // ビット列 Decode (DC)
-    fn decode_dc(&self, bsreader: &mut JpegBitStreamReader) -> i16
+    // Code length and symbol of the code at the top of the 16 peeked bits
+    //
+    // Returns a zero length for a bit sequence which is not a code of the table.
+    fn lookup_long_code(&self, bh: u16) -> (u8, u8)
+    {
+        for len in JPEG_DHT_LOOKUP_BITS + 1..=JPEG_NUM_DHT_TREE_BITS
+        {
+            let code = (bh >> (16 - len)) as i32;
+            if code <= self.maxcode[len]
+            {
+                let k = self.valptr[len] + (code - self.mincode[len]) as usize;
+                return (len as u8, self.encoding.get(k).copied().unwrap_or(0));
+            }
+        }
+        (0, 0)
+    }
+
+    // Decodes a symbol and its coefficient (the magnitude bits of SSSS)
+    //
+    // Returns the symbol and the coefficient value.
+    fn decode_symbol(&self, bsreader: &mut JpegBitStreamReader) -> (u8, i16)
     {
         let bh = bsreader.read_bits16();
-        let mut n_bits_huff: u8 = 0;
-        let mut n_bits_ssss: u8 = 0;
-        for i in 0..self.bit_pattern.len()
+        let entry = match self.lookup.get((bh >> (16 - JPEG_DHT_LOOKUP_BITS)) as usize)
+        {
+            Some(e) => *e,
+            None => return (0, 0),
+        };
+
+        // Code and magnitude in one access
+        if entry.total_length > 0
         {
-            if self.bit_pattern[i] > bh
+            bsreader.move_bitpos(entry.total_length as usize);
+            if self.is_log_enabled(JPEG_DHT_LOG_DETAIL)
             {
-                n_bits_ssss = self.encoding[i - 1];
-                n_bits_huff = self.bit_length[i - 1];
-                if self.is_log_enabled(JPEG_DHT_LOG_DETAIL)
-                {
-                    println!("DC: Match {} @ {:016b} {:016b} {} -> {}",
-                            i, bh, self.bit_pattern[i-1], n_bits_huff, n_bits_ssss);
-                }
-                break;
+                println!("Lookup {:016b} {} -> {:02x} [{}]", bh, entry.total_length, entry.symbol, entry.value);
             }
+            return (entry.symbol, entry.value);
+        }
+
+        let (n_bits_huff, symbol) = if entry.length > 0
+        {
+            (entry.length, entry.symbol)
         }
+        else
+        {
+            self.lookup_long_code(bh)
+        };
         bsreader.move_bitpos(n_bits_huff as usize);
+
+        let n_bits_ssss = if self.is_ac { symbol & 0x0F } else { symbol };
         let mut bs: u16 = 0;
-        let mut dc_diff: i16 = 0;
-        // DC coding table conversion
+        let mut value: i16 = 0;
         if n_bits_ssss > 0
         {
             bs = bsreader.read_bits16();
             bsreader.move_bitpos(n_bits_ssss as usize);
-            dc_diff = self.unpack_coefficient(n_bits_ssss, bs);
+            value = self.unpack_coefficient(n_bits_ssss, bs);
         }
         if self.is_log_enabled(JPEG_DHT_LOG_DETAIL)
         {
-            println!("SSSS Unpacked data: {:016b} @ {} -> [{}]", bs, n_bits_ssss, dc_diff);
+            println!("Match {:016b} {} -> {:02x}", bh, n_bits_huff, symbol);
+            println!("SSSS Unpacked data: {:016b} @ {} -> [{}]", bs, n_bits_ssss, value);
+        }
+        (symbol, value)
+    }
+
+    // SSSS ビット数に応じた係数値の展開
+    fn unpack_coefficient(&self, n_bits_ssss: u8, packed_data: u16) -> i16
+    {
+        let bs = packed_data >> (16 - n_bits_ssss);
+        if (packed_data & 0x8000) == 0
+        {
+            // MSB=0 -> negative
+            - (1 << n_bits_ssss) + 1 + bs as i16
         }
+        else
+        {
+            // MSB=1 -> positive
+            bs as i16
+        }       
+    }
+
+    // ビット列 Decode (DC)
+    fn decode_dc(&self, bsreader: &mut JpegBitStreamReader) -> i16
+    {
+        let (_, dc_diff) = self.decode_symbol(bsreader);
         dc_diff
     }
 
     // ビット列 Decode (AC)
     fn decode_ac(&self, bsreader: &mut JpegBitStreamReader) -> (i16, usize)
     {
-        let bh = bsreader.read_bits16();
-        let mut n_bits_huff: u8 = 0;
-        let mut n_bits_ssss: u8 = 0;
-        let mut n_zero_run: u8 = 0;
-        for i in 0..self.bit_pattern.len()
+        let (rs, ac) = self.decode_symbol(bsreader);
+        let n_zero_run = if rs != 0
         {
-            if self.bit_pattern[i] > bh
-            {
-                n_bits_ssss = self.encoding[i - 1];               
-                n_zero_run = if n_bits_ssss != 0
-                {
-                    n_bits_ssss >> 4    // upper 4bit
-                }
-                else
-                {
-                    255 // special case (fill remaining blocks with zero)
-                };
-                n_bits_ssss = n_bits_ssss & 0xF;    // lower 4bit
-                n_bits_huff = self.bit_length[i - 1];
-                if self.is_log_enabled(JPEG_DHT_LOG_DETAIL)
-                {
-                    println!("AC: Match {} @ {:016b} {:016b} {} -> {}",
-                            i, bh, self.bit_pattern[i-1], n_bits_huff, n_bits_ssss);
-                }
-                break;
-            }
+            rs >> 4     // upper 4bit
         }
-        bsreader.move_bitpos(n_bits_huff as usize);
-        let mut bs: u16 = 0;
-        let mut dc_diff: i16 = 0;
-        // AC coding table conversion
-        if n_bits_ssss > 0
+        else
         {
-            bs = bsreader.read_bits16();
-            bsreader.move_bitpos(n_bits_ssss as usize);
-            dc_diff = self.unpack_coefficient(n_bits_ssss, bs);
-        }
+            255 // special case (fill remaining blocks with zero)
+        };
         if self.is_log_enabled(JPEG_DHT_LOG_DETAIL)
         {
             println!("Zero run-length: {}", n_zero_run);
-            println!("SSSS Unpacked data: {:016b} @ {} -> [{}]", bs, n_bits_ssss, dc_diff);
         }
-        (dc_diff, n_zero_run as usize)
+        (ac, n_zero_run as usize)
     }
 
     // 構造体内容のダンプ
@@ -241,31 +528,67 @@ impl JpegDhtManager
     {
         JpegDhtManager
         {
-            ac: [JpegHuffmanTable::new(), JpegHuffmanTable::new()],
-            dc: [JpegHuffmanTable::new(), JpegHuffmanTable::new()],
+            ac: std::array::from_fn(|_| JpegHuffmanTable::new()),
+            dc: std::array::from_fn(|_| JpegHuffmanTable::new()),
             previous_dc: [0; JPEG_MAX_NUM_OF_COMPONENTS],
         }
     }
 
     // セグメント内容の parse と読み込み
-    pub fn parse_segment(&mut self, reader: &mut JpegReader)
+    //
+    // A segment can hold several tables. The tables before an invalid one are kept,
+    // and the slot of the invalid one is left unchanged.
+    pub fn parse_segment(&mut self, reader: &mut JpegReader) -> Result<(), JpegDhtError>
     {
-        // どのテーブルを使用するかのため ID を先読み
-        let id = reader.read_u8();
-        // 一旦セクションの先頭に巻き戻し
-        reader.move_pos(-3);
- 
-        let idx = (id & 0x03) as usize;
-        if id & 0x10 == 0
+        // Segment size (rewinds to the beginning of the section)
+        reader.move_pos(-2);
+        let seg_size = reader.read_u16be() as usize;
+
+        let mut remaining = seg_size.saturating_sub(2);
+        while remaining > 0
         {
-           self.dc[idx].parse_segment(reader);
+            let mut table = JpegHuffmanTable::new();
+            remaining -= table.parse_table(reader, remaining)?;
+
+            let idx = (table.table_id & 0x0F) as usize;
+            let slot = if table.is_ac { &mut self.ac[idx] } else { &mut self.dc[idx] };
+            table.set_log_control(slot.log_control);
+            *slot = table;
         }
-        else
+        Ok(())
+    }
+
+    // Loads the Annex K table into the slot if no DHT defined it
+    //
+    // Slot 0 gets the luminance table and slot 1 the chrominance one (as the usual
+    // Motion-JPEG streams). Returns false if the slot is still undefined.
+    pub fn load_default_table(&mut self, is_ac: bool, table_id: usize) -> bool
+    {
+        let slot = if is_ac { &mut self.ac[table_id] } else { &mut self.dc[table_id] };
+        if slot.is_defined
         {
-           self.ac[idx].parse_segment(reader);
-        }       
+            return true;
+        }
+        let (tree, encoding): (&[u8; JPEG_NUM_DHT_TREE_BITS], &[u8]) = match (is_ac, table_id)
+        {
+            (false, 0) => (&JPEG_DHT_DEFAULT_DC_LUMINANCE_BITS, &JPEG_DHT_DEFAULT_DC_VALUES),
+            (false, 1) => (&JPEG_DHT_DEFAULT_DC_CHROMINANCE_BITS, &JPEG_DHT_DEFAULT_DC_VALUES),
+            (true, 0) => (&JPEG_DHT_DEFAULT_AC_LUMINANCE_BITS, &JPEG_DHT_DEFAULT_AC_LUMINANCE_VALUES),
+            (true, 1) => (&JPEG_DHT_DEFAULT_AC_CHROMINANCE_BITS, &JPEG_DHT_DEFAULT_AC_CHROMINANCE_VALUES),
+            _ => return false,
+        };
+        let mut table = JpegHuffmanTable::from_spec(((is_ac as u8) << 4) | table_id as u8, tree, encoding);
+        table.set_log_control(slot.log_control);
+        *slot = table;
+        true
+    }
+
+    // Whether the table in the slot is a default one
+    pub fn is_default_table(&self, is_ac: bool, table_id: usize) -> bool
+    {
+        if is_ac { self.ac[table_id].is_default } else { self.dc[table_id].is_default }
     }
-    
+
     // Decode
     pub fn decode_dc(&self, table_id: usize, bsreader: &mut JpegBitStreamReader) -> i16
     {
@@ -280,24 +603,85 @@ impl JpegDhtManager
     // Set log control
     pub fn set_log_control(&mut self, flag: u8)
     {
-        self.dc[0].set_log_control(flag);
-        self.dc[1].set_log_control(flag);
-        self.ac[0].set_log_control(flag);
-        self.ac[1].set_log_control(flag);
+        for table in self.dc.iter_mut().chain(self.ac.iter_mut())
+        {
+            table.set_log_control(flag);
+        }
     }
 
     // 読み込み済の全 DHT テーブルのダンプ
     pub fn dump(&self)
     {
-        println!("[DHT DC Table 0]");
-        self.dc[0].dump();
-        println!("[DHT DC Table 1]");
-        self.dc[1].dump();
-        println!("[DHT AC Table 0]");
-        self.ac[0].dump();
-        println!("[DHT AC Table 1]");
-        self.ac[1].dump();
+        for i in 0..JPEG_NUM_DHT
+        {
+            println!("[DHT DC Table {}]", i);
+            self.dc[i].dump();
+        }
+        for i in 0..JPEG_NUM_DHT
+        {
+            println!("[DHT AC Table {}]", i);
+            self.ac[i].dump();
+        }
     }
 }
 
 //========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Bytes of a bit string ("0"/"1", padded with 1s), with 0xFF stuffed
+    fn to_bytes(bits: &str) -> Vec<u8>
+    {
+        let mut bits = bits.to_string();
+        while !bits.len().is_multiple_of(8)
+        {
+            bits.push('1');
+        }
+        let mut data = Vec::new();
+        for i in (0..bits.len()).step_by(8)
+        {
+            let b = u8::from_str_radix(&bits[i..i + 8], 2).unwrap();
+            data.push(b);
+            if b == 0xFF
+            {
+                data.push(0x00);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn annex_k_ac_codes()
+    {
+        let table = JpegHuffmanTable::from_spec(0x10, &JPEG_DHT_DEFAULT_AC_LUMINANCE_BITS, &JPEG_DHT_DEFAULT_AC_LUMINANCE_VALUES);
+
+        // 0x01 (00) with its magnitude bit fits in the lookup, 0xFA (15/10) is a 16-bit code.
+        let entry = table.lookup[0b000_111111];
+        assert!(entry.length == 2 && entry.symbol == 0x01 && entry.total_length == 3 && entry.value == -1);
+        let entry = table.lookup[0b111111111];
+        assert!(entry.length == 0);
+
+        // Code + magnitude bits -> (coefficient, zero run)
+        let codes = [
+            ("00", "0", (-1, 0)),                           // 0x01
+            ("100", "101", (5, 0)),                         // 0x03
+            ("1111111111111110", "1000000001", (513, 15)),  // 0xFA
+            ("11111111001", "", (0, 15)),                   // 0xF0 (ZRL)
+            ("111111111000000", "01", (-2, 8)),             // 0x82
+            ("111010", "1", (1, 3)),                        // 0x31
+            ("1100", "1", (1, 1)),                          // 0x11
+            ("1010", "", (0, 255)),                         // 0x00 (EOB)
+        ];
+        let bits: String = codes.iter().map(|(code, magnitude, _)| format!("{}{}", code, magnitude)).collect();
+        let data = to_bytes(&bits);
+        let mut reader = JpegBitStreamReader::from_slice(&data);
+        for (code, magnitude, expected) in codes
+        {
+            assert!(table.decode_ac(&mut reader) == expected, "{} {}", code, magnitude);
+        }
+        assert!(!reader.is_overrun());
+    }
+}
@@ -4,6 +4,231 @@
 //========================================================
 //use std::f32::consts::PI;
 use crate::jpeg_constants::JPEG_SAMPLE_BLOCK_SIZE;
+use crate::jpeg_simd;
+use std::sync::Arc;
+
+// Fixed-point precision of the integer IDCT (same as libjpeg jidctint.c)
+pub(crate) const JPEG_IDCT_CONST_BITS: i32 = 13;
+pub(crate) const JPEG_IDCT_PASS1_BITS: i32 = 2;
+
+// FIX(x) = x * (1 << CONST_BITS), rounded
+pub(crate) const FIX_0_298631336: i32 = 2446;
+pub(crate) const FIX_0_390180644: i32 = 3196;
+pub(crate) const FIX_0_541196100: i32 = 4433;
+pub(crate) const FIX_0_765366865: i32 = 6270;
+pub(crate) const FIX_0_899976223: i32 = 7373;
+pub(crate) const FIX_1_175875602: i32 = 9633;
+pub(crate) const FIX_1_501321110: i32 = 12299;
+pub(crate) const FIX_1_847759065: i32 = 15137;
+pub(crate) const FIX_1_961570560: i32 = 16069;
+pub(crate) const FIX_2_053119869: i32 = 16819;
+pub(crate) const FIX_2_562915447: i32 = 20995;
+pub(crate) const FIX_3_072711026: i32 = 25172;
+
+// Constants of the reduced-size IDCT (same as libjpeg jidctred.c)
+const FIX_0_211164243: i32 = 1730;
+const FIX_0_509795579: i32 = 4176;
+const FIX_0_601344887: i32 = 4926;
+const FIX_0_720959822: i32 = 5906;
+const FIX_0_850430095: i32 = 6967;
+const FIX_1_061594337: i32 = 8697;
+const FIX_1_272758580: i32 = 10426;
+const FIX_1_451774981: i32 = 11893;
+const FIX_2_172734803: i32 = 17799;
+const FIX_3_624509785: i32 = 29692;
+
+// Constants of the fast integer IDCT (same as libjpeg jidctfst.c)
+const JPEG_IDCT_IFAST_CONST_BITS: i32 = 8;
+const JPEG_IDCT_IFAST_SCALE_BITS: i32 = 2;
+const FIX_IFAST_1_082392200: i32 = 277;
+const FIX_IFAST_1_414213562: i32 = 362;
+const FIX_IFAST_1_847759065: i32 = 473;
+const FIX_IFAST_2_613125930: i32 = 669;
+
+// AAN scale factors (cos(k*PI/16) * sqrt(2) for k > 0, scaled up by 14 bits) folded into the
+// quantization table of the fast integer IDCT
+const JPEG_IDCT_AAN_SCALES: [i32; JPEG_SAMPLE_BLOCK_SIZE] =
+[
+    16384, 22725, 21407, 19266, 16384, 12873,  8867,  4520,
+    22725, 31521, 29692, 26722, 22725, 17855, 12299,  6270,
+    21407, 29692, 27969, 25172, 21407, 16819, 11585,  5906,
+    19266, 26722, 25172, 22654, 19266, 15137, 10426,  5315,
+    16384, 22725, 21407, 19266, 16384, 12873,  8867,  4520,
+    12873, 17855, 16819, 15137, 12873, 10114,  6967,  3552,
+     8867, 12299, 11585, 10426,  8867,  6967,  4799,  2446,
+     4520,  6270,  5906,  5315,  4520,  3552,  2446,  1247,
+];
+
+// Last zigzag index of the non-zero coefficients for the sparse IDCT kernels
+// (the first 3 coefficients are inside the top-left 2x2, and the first 10 inside the 4x4)
+const JPEG_IDCT_LAST_INDEX_DC: usize = 0;
+const JPEG_IDCT_LAST_INDEX_2X2: usize = 2;
+const JPEG_IDCT_LAST_INDEX_4X4: usize = 9;
+
+// 1D IDCT of 8 values (even part and odd part of the LL&M algorithm, as jidctint.c)
+//
+// The value type is given by its operations: `$add`/`$sub`, `$mul` (by a constant) and
+// `$shl` (by CONST_BITS), all wrapping on overflow, so that the scalar version and the SIMD
+// versions perform exactly the same calculation. Returns the outputs before descaling.
+macro_rules! idct_islow_1d
+{
+    ($v:expr, $add:path, $sub:path, $mul:path, $shl:path) =>
+    {{
+        use $crate::jpeg_idct::*;
+        let v = $v;
+
+        // Even part
+        let z1 = $mul($add(v[2], v[6]), FIX_0_541196100);
+        let tmp2 = $add(z1, $mul(v[6], -FIX_1_847759065));
+        let tmp3 = $add(z1, $mul(v[2], FIX_0_765366865));
+
+        let tmp0 = $shl($add(v[0], v[4]));
+        let tmp1 = $shl($sub(v[0], v[4]));
+
+        let tmp10 = $add(tmp0, tmp3);
+        let tmp13 = $sub(tmp0, tmp3);
+        let tmp11 = $add(tmp1, tmp2);
+        let tmp12 = $sub(tmp1, tmp2);
+
+        // Odd part
+        let (tmp0, tmp1, tmp2, tmp3) = (v[7], v[5], v[3], v[1]);
+        let z1 = $add(tmp0, tmp3);
+        let z2 = $add(tmp1, tmp2);
+        let z3 = $add(tmp0, tmp2);
+        let z4 = $add(tmp1, tmp3);
+        let z5 = $mul($add(z3, z4), FIX_1_175875602);
+
+        let tmp0 = $mul(tmp0, FIX_0_298631336);
+        let tmp1 = $mul(tmp1, FIX_2_053119869);
+        let tmp2 = $mul(tmp2, FIX_3_072711026);
+        let tmp3 = $mul(tmp3, FIX_1_501321110);
+        let z1 = $mul(z1, -FIX_0_899976223);
+        let z2 = $mul(z2, -FIX_2_562915447);
+        let z3 = $add($mul(z3, -FIX_1_961570560), z5);
+        let z4 = $add($mul(z4, -FIX_0_390180644), z5);
+
+        let tmp0 = $add($add(tmp0, z1), z3);
+        let tmp1 = $add($add(tmp1, z2), z4);
+        let tmp2 = $add($add(tmp2, z2), z3);
+        let tmp3 = $add($add(tmp3, z1), z4);
+
+        [
+            $add(tmp10, tmp3), $add(tmp11, tmp2), $add(tmp12, tmp1), $add(tmp13, tmp0),
+            $sub(tmp13, tmp0), $sub(tmp12, tmp1), $sub(tmp11, tmp2), $sub(tmp10, tmp3),
+        ]
+    }};
+}
+pub(crate) use idct_islow_1d;
+
+// IDCT algorithm
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegIdctMethod
+{
+    Integer,            // Separable fixed-point version (libjpeg ISLOW)
+    IntegerFast,        // AAN fixed-point version (libjpeg IFAST; faster, less accurate)
+    FloatReference,     // Straight-forward 2D sum (slow; for validation)
+}
+
+// Inverse DCT of a block (pluggable implementation)
+pub trait JpegIdct: Send + Sync
+{
+    // Transforms the quantized coefficients of a block (in natural order) with the
+    // quantization table into 8x8 samples, level shifted and clamped to 0..255, in place.
+    //
+    // `last_index` is the zigzag index of the last non-zero coefficient (0 for DC only).
+    fn transform(&self, coef: &mut [i16], qt: &[u16], last_index: usize);
+
+    // Same as transform(), but the samples are not level shifted and clamped to -256..255
+    // (the output domain of the IEEE 1180 accuracy test).
+    //
+    // The default is derived from transform(), so the samples outside -128..127 are clamped.
+    fn transform_unshifted(&self, coef: &mut [i16], qt: &[u16], last_index: usize)
+    {
+        self.transform(coef, qt, last_index);
+        for c in coef[..JPEG_SAMPLE_BLOCK_SIZE].iter_mut()
+        {
+            *c -= 128;
+        }
+    }
+}
+
+// Built-in IDCTs
+pub struct JpegIdctIslow;
+
+pub struct JpegIdctIfast;
+
+pub struct JpegIdctFloatReference;
+
+impl JpegIdctMethod
+{
+    // Built-in implementation of the algorithm
+    pub fn create_idct(&self) -> Arc<dyn JpegIdct>
+    {
+        match self
+        {
+            JpegIdctMethod::Integer => Arc::new(JpegIdctIslow),
+            JpegIdctMethod::IntegerFast => Arc::new(JpegIdctIfast),
+            JpegIdctMethod::FloatReference => Arc::new(JpegIdctFloatReference),
+        }
+    }
+}
+
+impl JpegIdct for JpegIdctIslow
+{
+    fn transform(&self, coef: &mut [i16], qt: &[u16], last_index: usize)
+    {
+        JpegIdctManager::idct_islow_sparse(coef, qt, last_index);
+    }
+
+    fn transform_unshifted(&self, coef: &mut [i16], qt: &[u16], _last_index: usize)
+    {
+        JpegIdctManager::idct_islow_limited(coef, qt, JpegIdctManager::unshifted_limit);
+    }
+}
+
+impl JpegIdct for JpegIdctIfast
+{
+    fn transform(&self, coef: &mut [i16], qt: &[u16], _last_index: usize)
+    {
+        JpegIdctManager::idct_ifast(coef, qt);
+    }
+
+    fn transform_unshifted(&self, coef: &mut [i16], qt: &[u16], _last_index: usize)
+    {
+        JpegIdctManager::idct_ifast_limited(coef, qt, JpegIdctManager::unshifted_limit);
+    }
+}
+
+impl JpegIdct for JpegIdctFloatReference
+{
+    fn transform(&self, coef: &mut [i16], qt: &[u16], _last_index: usize)
+    {
+        JpegIdctManager::new().idct_limited(coef, qt, JpegIdctManager::offset_and_clamp);
+    }
+
+    fn transform_unshifted(&self, coef: &mut [i16], qt: &[u16], _last_index: usize)
+    {
+        JpegIdctManager::new().idct_limited(coef, qt, |val| (val + 0.5_f32).floor().clamp(-256.0, 255.0) as i16);
+    }
+}
+
+// Output scale (size of the IDCT output for an 8x8 block)
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegScale
+{
+    Full,       // 1/1 (8x8)
+    Half,       // 1/2 (4x4)
+    Quarter,    // 1/4 (2x2)
+    Eighth,     // 1/8 (1x1, DC only)
+}
 
 #[allow(dead_code)]
 pub struct JpegIdctManager
@@ -11,6 +236,28 @@ pub struct JpegIdctManager
     tmp: [f32; JPEG_SAMPLE_BLOCK_SIZE],
 }
 
+#[allow(dead_code)]
+impl JpegScale
+{
+    // Width/height of the IDCT output in pixels
+    pub fn get_block_size(&self) -> usize
+    {
+        match self
+        {
+            JpegScale::Full => 8,
+            JpegScale::Half => 4,
+            JpegScale::Quarter => 2,
+            JpegScale::Eighth => 1,
+        }
+    }
+
+    // Scaled size of `size` pixels (rounded up as libjpeg does)
+    pub fn get_scaled_size(&self, size: usize) -> usize
+    {
+        (size * self.get_block_size()).div_ceil(8)
+    }
+}
+
 #[allow(dead_code)]
 impl JpegIdctManager
 {
@@ -31,7 +278,7 @@ impl JpegIdctManager
     const COS_TABLE: [f32; Self::COS_TABLE_SIZE] =
     [
          1.0,         0.98078525,   0.92387955,  0.83146963,
-         0.70710677,  0.55557028,   0.38268348,  0.19509028,
+         0.70710677,  0.555_570_3,  0.382_683_5,  0.19509028,
     ];   
 
     // Constructor
@@ -47,18 +294,7 @@ impl JpegIdctManager
     fn offset_and_clamp(val: f32) -> i16
     {
         let i: i16 = (val + 128.5_f32) as i16;
-        if i < 0
-        {
-            0
-        }
-        else if i > 255
-        {
-            255
-        } 
-        else
-        {
-            i
-        }
+        i.clamp(0, 255)
     }
 
     // Tabled version of discrete cos(i * PI /16)
@@ -83,6 +319,14 @@ impl JpegIdctManager
 
     // Non-optimized straight-forward implementation
     pub fn idct(&mut self, coef: &mut [i16])
+    {
+        self.idct_limited(coef, &[1; JPEG_SAMPLE_BLOCK_SIZE], Self::offset_and_clamp);
+    }
+
+    // Straight-forward implementation with dequantization by `qt` (in f32, as the products
+    // may exceed i16) and the output conversion given by `limit`
+    #[allow(clippy::needless_range_loop)]
+    pub fn idct_limited(&mut self, coef: &mut [i16], qt: &[u16], limit: impl Fn(f32) -> i16)
     {
         for y in 0..8
         {
@@ -95,7 +339,7 @@ impl JpegIdctManager
                     for u in 0..8
                     {
                         let cu = if u == 0 { Self::FRAC_1_2SQRT2 } else { Self::FRAC_1_2 };
-                        val += cu * cv * (coef[v*8 + u] as f32)
+                        val += cu * cv * (coef[v*8 + u] as f32 * qt[v*8 + u] as f32)
                             * self.lookup_tabled_cos( (x * 2 + 1) * u)
                             * self.lookup_tabled_cos( (y * 2 + 1) * v);
                     }
@@ -106,7 +350,388 @@ impl JpegIdctManager
 
         for i in 0..JPEG_SAMPLE_BLOCK_SIZE
         {
-            coef[i] = Self::offset_and_clamp(self.tmp[i]);
+            coef[i] = limit(self.tmp[i]);
+        }
+    }
+
+    // Arithmetic of the scalar integer IDCT (wrapping on overflow as the SIMD versions)
+    fn add(a: i32, b: i32) -> i32
+    {
+        a.wrapping_add(b)
+    }
+
+    fn sub(a: i32, b: i32) -> i32
+    {
+        a.wrapping_sub(b)
+    }
+
+    fn mul(a: i32, k: i32) -> i32
+    {
+        a.wrapping_mul(k)
+    }
+
+    fn shl(a: i32) -> i32
+    {
+        a << JPEG_IDCT_CONST_BITS
+    }
+
+    // Rounded right shift
+    fn descale(x: i32, n: i32) -> i32
+    {
+        x.wrapping_add(1 << (n - 1)) >> n
+    }
+
+    // Offset and clamp of the integer IDCT output
+    //
+    // The value is wrapped into 10 bits before clamping, as the range limit table of libjpeg does.
+    fn range_limit(x: i32) -> i16
+    {
+        let v = (x << 22) >> 22;
+        (v + 128).clamp(0, 255) as i16
+    }
+
+    // Clamp of the integer IDCT output without the offset (for the accuracy test)
+    fn unshifted_limit(x: i32) -> i16
+    {
+        x.clamp(-256, 255) as i16
+    }
+
+    // Separable fixed-point IDCT with dequantization (columns, then rows)
+    //
+    // Same arithmetic as jpeg_idct_islow() of libjpeg, so the output is bit-exact with it.
+    // The SIMD version is used if available.
+    pub fn idct_islow(coef: &mut [i16], qt: &[u16])
+    {
+        if !jpeg_simd::idct_islow(coef, qt)
+        {
+            Self::idct_islow_scalar(coef, qt);
+        }
+    }
+
+    // Integer IDCT choosing a kernel by the last non-zero coefficient (zigzag index)
+    //
+    // The DC-only, 2x2 and 4x4 kernels skip the zero coefficients but keep the arithmetic
+    // of idct_islow(), so the output is the same whichever is chosen.
+    pub fn idct_islow_sparse(coef: &mut [i16], qt: &[u16], last_index: usize)
+    {
+        match last_index
+        {
+            JPEG_IDCT_LAST_INDEX_DC => Self::idct_islow_dc(coef, qt),
+            i if i <= JPEG_IDCT_LAST_INDEX_2X2 => Self::idct_islow_reduced::<2>(coef, qt),
+            i if i <= JPEG_IDCT_LAST_INDEX_4X4 => Self::idct_islow_reduced::<4>(coef, qt),
+            _ => Self::idct_islow(coef, qt),
+        }
+    }
+
+    // Only the DC coefficient: all the samples have the same value.
+    fn idct_islow_dc(coef: &mut [i16], qt: &[u16])
+    {
+        let dc = Self::mul(coef[0] as i32, qt[0] as i32);
+        let dc = Self::descale(Self::shl(dc), JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS);
+        let val = Self::range_limit(
+            Self::descale(Self::shl(dc), JPEG_IDCT_CONST_BITS + JPEG_IDCT_PASS1_BITS + 3)
+        );
+        coef[..JPEG_SAMPLE_BLOCK_SIZE].fill(val);
+    }
+
+    // Non-zero coefficients only in the top-left NxN: the column pass is done for N columns,
+    // and the zero inputs of both passes are constants to be folded by the compiler.
+    fn idct_islow_reduced<const N: usize>(coef: &mut [i16], qt: &[u16])
+    {
+        let mut ws = [[0_i32; N]; 8];
+
+        // Pass 1: the first N columns (the others are all zero)
+        for x in 0..N
+        {
+            let v: [i32; 8] = std::array::from_fn(|y|
+                if y < N { Self::mul(coef[y * 8 + x] as i32, qt[y * 8 + x] as i32) } else { 0 }
+            );
+            let out = idct_islow_1d!(v, Self::add, Self::sub, Self::mul, Self::shl);
+            for y in 0..8
+            {
+                ws[y][x] = Self::descale(out[y], JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS);
+            }
+        }
+
+        // Pass 2: rows with N inputs
+        for y in 0..8
+        {
+            let v: [i32; 8] = std::array::from_fn(|x| if x < N { ws[y][x] } else { 0 });
+            let out = idct_islow_1d!(v, Self::add, Self::sub, Self::mul, Self::shl);
+            for x in 0..8
+            {
+                coef[y * 8 + x] = Self::range_limit(
+                    Self::descale(out[x], JPEG_IDCT_CONST_BITS + JPEG_IDCT_PASS1_BITS + 3)
+                );
+            }
+        }
+    }
+
+    // Reduced-size integer IDCT with dequantization (same as libjpeg jidctred.c)
+    //
+    // The output is the top-left `size` x `size` (4, 2 or 1) of the block,
+    // stored row by row with the stride of `size`.
+    pub fn idct_reduced(coef: &mut [i16], qt: &[u16], size: usize, last_index: usize)
+    {
+        match size
+        {
+            4 if last_index != JPEG_IDCT_LAST_INDEX_DC => Self::idct_reduced_4x4(coef, qt),
+            2 if last_index != JPEG_IDCT_LAST_INDEX_DC => Self::idct_reduced_2x2(coef, qt),
+            _ =>
+            {
+                // Only the DC coefficient: all the samples have the same value.
+                let dc = Self::mul(coef[0] as i32, qt[0] as i32);
+                let val = Self::range_limit(Self::descale(dc, 3));
+                coef[..size * size].fill(val);
+            }
+        }
+    }
+
+    // 4-point 1D IDCT of the coefficients 0, 1, 2, 3, 5, 6 and 7
+    // (the coefficient 4 does not affect the 4-point output)
+    fn idct_reduced_4_1d(v: [i32; 8]) -> [i32; 4]
+    {
+        // Even part
+        let tmp0 = v[0] << (JPEG_IDCT_CONST_BITS + 1);
+        let tmp2 = Self::add(Self::mul(v[2], FIX_1_847759065), Self::mul(v[6], -FIX_0_765366865));
+        let tmp10 = Self::add(tmp0, tmp2);
+        let tmp12 = Self::sub(tmp0, tmp2);
+
+        // Odd part
+        let (z1, z2, z3, z4) = (v[7], v[5], v[3], v[1]);
+        let tmp0 = Self::add(
+            Self::add(Self::mul(z1, -FIX_0_211164243), Self::mul(z2, FIX_1_451774981)),
+            Self::add(Self::mul(z3, -FIX_2_172734803), Self::mul(z4, FIX_1_061594337))
+        );
+        let tmp2 = Self::add(
+            Self::add(Self::mul(z1, -FIX_0_509795579), Self::mul(z2, -FIX_0_601344887)),
+            Self::add(Self::mul(z3, FIX_0_899976223), Self::mul(z4, FIX_2_562915447))
+        );
+
+        [
+            Self::add(tmp10, tmp2), Self::add(tmp12, tmp0),
+            Self::sub(tmp12, tmp0), Self::sub(tmp10, tmp2),
+        ]
+    }
+
+    // 2-point 1D IDCT of the coefficients 0, 1, 3, 5 and 7
+    fn idct_reduced_2_1d(v: [i32; 8]) -> [i32; 2]
+    {
+        let tmp10 = v[0] << (JPEG_IDCT_CONST_BITS + 2);
+        let tmp0 = Self::add(
+            Self::add(Self::mul(v[7], -FIX_0_720959822), Self::mul(v[5], FIX_0_850430095)),
+            Self::add(Self::mul(v[3], -FIX_1_272758580), Self::mul(v[1], FIX_3_624509785))
+        );
+        [Self::add(tmp10, tmp0), Self::sub(tmp10, tmp0)]
+    }
+
+    fn idct_reduced_4x4(coef: &mut [i16], qt: &[u16])
+    {
+        let mut ws = [[0_i32; 8]; 4];
+
+        // Pass 1: columns (the column 4 is not used by pass 2)
+        for x in (0..8).filter(|x| *x != 4)
+        {
+            let v: [i32; 8] = std::array::from_fn(|y| Self::mul(coef[y * 8 + x] as i32, qt[y * 8 + x] as i32));
+            if [1, 2, 3, 5, 6, 7].iter().all(|y| v[*y] == 0)
+            {
+                for row in ws.iter_mut()
+                {
+                    row[x] = v[0] << JPEG_IDCT_PASS1_BITS;
+                }
+                continue;
+            }
+            let out = Self::idct_reduced_4_1d(v);
+            for y in 0..4
+            {
+                ws[y][x] = Self::descale(out[y], JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS + 1);
+            }
+        }
+
+        // Pass 2: rows
+        for y in 0..4
+        {
+            let out = Self::idct_reduced_4_1d(ws[y]);
+            for x in 0..4
+            {
+                coef[y * 4 + x] = Self::range_limit(
+                    Self::descale(out[x], JPEG_IDCT_CONST_BITS + JPEG_IDCT_PASS1_BITS + 3 + 1)
+                );
+            }
+        }
+    }
+
+    fn idct_reduced_2x2(coef: &mut [i16], qt: &[u16])
+    {
+        let mut ws = [[0_i32; 8]; 2];
+
+        // Pass 1: columns (the even columns except 0 are not used by pass 2)
+        for x in [0, 1, 3, 5, 7]
+        {
+            let v: [i32; 8] = std::array::from_fn(|y| Self::mul(coef[y * 8 + x] as i32, qt[y * 8 + x] as i32));
+            if [1, 3, 5, 7].iter().all(|y| v[*y] == 0)
+            {
+                for row in ws.iter_mut()
+                {
+                    row[x] = v[0] << JPEG_IDCT_PASS1_BITS;
+                }
+                continue;
+            }
+            let out = Self::idct_reduced_2_1d(v);
+            for y in 0..2
+            {
+                ws[y][x] = Self::descale(out[y], JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS + 2);
+            }
+        }
+
+        // Pass 2: rows
+        for y in 0..2
+        {
+            let out = Self::idct_reduced_2_1d(ws[y]);
+            for x in 0..2
+            {
+                coef[y * 2 + x] = Self::range_limit(
+                    Self::descale(out[x], JPEG_IDCT_CONST_BITS + JPEG_IDCT_PASS1_BITS + 3 + 2)
+                );
+            }
+        }
+    }
+
+    // Multiplication of the fast integer IDCT (truncated as libjpeg)
+    fn mul_ifast(a: i32, k: i32) -> i32
+    {
+        a.wrapping_mul(k) >> JPEG_IDCT_IFAST_CONST_BITS
+    }
+
+    // 1D IDCT of the fast integer IDCT (AAN algorithm, as jidctfst.c)
+    fn idct_ifast_1d(v: [i32; 8]) -> [i32; 8]
+    {
+        // Even part
+        let tmp10 = Self::add(v[0], v[4]);
+        let tmp11 = Self::sub(v[0], v[4]);
+        let tmp13 = Self::add(v[2], v[6]);
+        let tmp12 = Self::sub(Self::mul_ifast(Self::sub(v[2], v[6]), FIX_IFAST_1_414213562), tmp13);
+
+        let tmp0 = Self::add(tmp10, tmp13);
+        let tmp3 = Self::sub(tmp10, tmp13);
+        let tmp1 = Self::add(tmp11, tmp12);
+        let tmp2 = Self::sub(tmp11, tmp12);
+
+        // Odd part
+        let z13 = Self::add(v[5], v[3]);
+        let z10 = Self::sub(v[5], v[3]);
+        let z11 = Self::add(v[1], v[7]);
+        let z12 = Self::sub(v[1], v[7]);
+
+        let tmp7 = Self::add(z11, z13);
+        let tmp11 = Self::mul_ifast(Self::sub(z11, z13), FIX_IFAST_1_414213562);
+        let z5 = Self::mul_ifast(Self::add(z10, z12), FIX_IFAST_1_847759065);
+        let tmp10 = Self::sub(Self::mul_ifast(z12, FIX_IFAST_1_082392200), z5);
+        let tmp12 = Self::add(Self::mul_ifast(z10, -FIX_IFAST_2_613125930), z5);
+
+        let tmp6 = Self::sub(tmp12, tmp7);
+        let tmp5 = Self::sub(tmp11, tmp6);
+        let tmp4 = Self::add(tmp10, tmp5);
+
+        [
+            Self::add(tmp0, tmp7), Self::add(tmp1, tmp6), Self::add(tmp2, tmp5), Self::sub(tmp3, tmp4),
+            Self::add(tmp3, tmp4), Self::sub(tmp2, tmp5), Self::sub(tmp1, tmp6), Self::sub(tmp0, tmp7),
+        ]
+    }
+
+    // Fast integer IDCT with dequantization (same arithmetic as jpeg_idct_ifast() of libjpeg)
+    //
+    // The AAN scale factors are folded into the quantization table, and the descaling
+    // is truncated instead of rounded, which trades accuracy for speed.
+    pub fn idct_ifast(coef: &mut [i16], qt: &[u16])
+    {
+        Self::idct_ifast_limited(coef, qt, Self::range_limit);
+    }
+
+    // Fast integer IDCT with the output conversion given by `limit`
+    fn idct_ifast_limited(coef: &mut [i16], qt: &[u16], limit: impl Fn(i32) -> i16)
+    {
+        let mut ws = [0_i32; JPEG_SAMPLE_BLOCK_SIZE];
+        let shift = 14 - JPEG_IDCT_IFAST_SCALE_BITS;
+        let dequantize = |i: usize|
+        {
+            let q = ((qt[i] as i32 * JPEG_IDCT_AAN_SCALES[i] + (1 << (shift - 1))) >> shift) as i16;
+            Self::mul(coef[i] as i32, q as i32)
+        };
+
+        // Pass 1: columns (the results are scaled up by 1 << SCALE_BITS from the table)
+        for x in 0..8
+        {
+            if (1..8).all(|y| coef[y * 8 + x] == 0)
+            {
+                let dc = dequantize(x);
+                for y in 0..8
+                {
+                    ws[y * 8 + x] = dc;
+                }
+                continue;
+            }
+            let out = Self::idct_ifast_1d(std::array::from_fn(|y| dequantize(y * 8 + x)));
+            for y in 0..8
+            {
+                ws[y * 8 + x] = out[y];
+            }
+        }
+
+        // Pass 2: rows
+        for y in 0..8
+        {
+            let out = Self::idct_ifast_1d(std::array::from_fn(|x| ws[y * 8 + x]));
+            for x in 0..8
+            {
+                coef[y * 8 + x] = limit(out[x] >> (JPEG_IDCT_IFAST_SCALE_BITS + 3));
+            }
+        }
+    }
+
+    pub fn idct_islow_scalar(coef: &mut [i16], qt: &[u16])
+    {
+        Self::idct_islow_limited(coef, qt, Self::range_limit);
+    }
+
+    // Integer IDCT with the output conversion given by `limit`
+    fn idct_islow_limited(coef: &mut [i16], qt: &[u16], limit: impl Fn(i32) -> i16)
+    {
+        let mut ws = [0_i32; JPEG_SAMPLE_BLOCK_SIZE];
+
+        // Pass 1: columns (the results are scaled up by 1 << PASS1_BITS)
+        for x in 0..8
+        {
+            if (1..8).all(|y| coef[y * 8 + x] == 0)
+            {
+                // AC terms all zero: the column is flat.
+                let dc = Self::mul(coef[x] as i32, qt[x] as i32);
+                let dc = Self::descale(Self::shl(dc), JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS);
+                for y in 0..8
+                {
+                    ws[y * 8 + x] = dc;
+                }
+                continue;
+            }
+            let v: [i32; 8] = std::array::from_fn(|y| Self::mul(coef[y * 8 + x] as i32, qt[y * 8 + x] as i32));
+            let out = idct_islow_1d!(v, Self::add, Self::sub, Self::mul, Self::shl);
+            for y in 0..8
+            {
+                ws[y * 8 + x] = Self::descale(out[y], JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS);
+            }
+        }
+
+        // Pass 2: rows (the scaling by PASS1_BITS and the factor 8 are removed)
+        for y in 0..8
+        {
+            let row = &ws[y * 8..y * 8 + 8];
+            let out = idct_islow_1d!(std::array::from_fn::<i32, 8, _>(|x| row[x]), Self::add, Self::sub, Self::mul, Self::shl);
+            for x in 0..8
+            {
+                coef[y * 8 + x] = limit(
+                    Self::descale(out[x], JPEG_IDCT_CONST_BITS + JPEG_IDCT_PASS1_BITS + 3)
+                );
+            }
         }
     }
 
@@ -122,3 +747,23 @@ impl JpegIdctManager
 }
 
 //========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The dequantized coefficient 400 * 99 exceeds i16 (valid for 8-bit streams).
+    #[test]
+    fn float_reference_dequantizes_without_overflow()
+    {
+        let mut coef = [0i16; JPEG_SAMPLE_BLOCK_SIZE];
+        coef[1] = 400;
+        let qt = [99u16; JPEG_SAMPLE_BLOCK_SIZE];
+        JpegIdctMethod::FloatReference.create_idct().transform(&mut coef, &qt, 1);
+        for y in 0..8
+        {
+            assert!(coef[y * 8] == 255 && coef[y * 8 + 7] == 0, "{:?}", &coef[y * 8..y * 8 + 8]);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//========================================================
+//  jpeg_idct_checker.rs
+//
+//========================================================
+use crate::jpeg_constants::JPEG_REV_ZIGZAG_TABLE;
+use crate::jpeg_constants::JPEG_SAMPLE_BLOCK_SIZE;
+use crate::jpeg_idct::JpegIdct;
+use std::f64::consts::PI;
+
+const JPEG_IDCT_CHECK_NUM_BLOCKS: usize = 10000;
+
+// Input sample ranges (L, H) of the IEEE 1180 / ISO/IEC 23002-1 accuracy test
+const JPEG_IDCT_CHECK_RANGES: [(i32, i32); 3] = [(256, 255), (5, 5), (300, 300)];
+
+// Accuracy limits of the standard
+const JPEG_IDCT_PEAK_ERROR_LIMIT: i32 = 1;
+const JPEG_IDCT_PEAK_MSE_LIMIT: f64 = 0.06;
+const JPEG_IDCT_OVERALL_MSE_LIMIT: f64 = 0.02;
+const JPEG_IDCT_PEAK_MEAN_ERROR_LIMIT: f64 = 0.015;
+const JPEG_IDCT_OVERALL_MEAN_ERROR_LIMIT: f64 = 0.0015;
+
+// Result of one accuracy test (one input range and sign)
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct JpegIdctAccuracy
+{
+    pub low: i32,
+    pub high: i32,
+    pub sign: i32,
+    pub peak_error: i32,
+    pub peak_mse: f64,
+    pub overall_mse: f64,
+    pub peak_mean_error: f64,
+    pub overall_mean_error: f64,
+    pub zero_input_ok: bool,
+}
+
+#[allow(dead_code)]
+impl JpegIdctAccuracy
+{
+    // Whether the result is within the limits of the standard
+    pub fn is_compliant(&self) -> bool
+    {
+        self.peak_error <= JPEG_IDCT_PEAK_ERROR_LIMIT
+            && self.peak_mse <= JPEG_IDCT_PEAK_MSE_LIMIT
+            && self.overall_mse <= JPEG_IDCT_OVERALL_MSE_LIMIT
+            && self.peak_mean_error.abs() <= JPEG_IDCT_PEAK_MEAN_ERROR_LIMIT
+            && self.overall_mean_error.abs() <= JPEG_IDCT_OVERALL_MEAN_ERROR_LIMIT
+            && self.zero_input_ok
+    }
+
+    pub fn dump(&self)
+    {
+        println!("Range= [-{}, {}] / Sign= {:+} / {}",
+                 self.low, self.high, self.sign,
+                 if self.is_compliant() { "PASS" } else { "FAIL" });
+        println!("  Peak error= {} / Peak MSE= {:.6} / Overall MSE= {:.6}",
+                 self.peak_error, self.peak_mse, self.overall_mse);
+        println!("  Peak mean error= {:.6} / Overall mean error= {:.6} / Zero input= {}",
+                 self.peak_mean_error, self.overall_mean_error, self.zero_input_ok);
+    }
+}
+
+// Random number generator of the standard (values in [-low, high])
+struct JpegIdctRandom
+{
+    randx: u32,
+}
+
+#[allow(clippy::needless_return)]
+impl JpegIdctRandom
+{
+    fn new() -> Self
+    {
+        JpegIdctRandom
+        {
+            randx: 1,
+        }
+    }
+
+    fn next(&mut self, low: i32, high: i32) -> i32
+    {
+        self.randx = self.randx.wrapping_mul(1103515245).wrapping_add(12345);
+        let i = self.randx & 0x7ffffffe;
+        let x = i as f64 / 0x7fffffff as f64 * (low + high + 1) as f64;
+        return x as i32 - low;
+    }
+}
+
+// Runs the accuracy test on an IDCT for every input range and sign of the standard.
+//
+// The IDCT under test is given the coefficients with an all-ones quantization table and
+// its output before the level shift (JpegIdct::transform_unshifted()) is compared with
+// the reference output in [-256, 255] as the standard.
+#[allow(dead_code)]
+#[allow(clippy::needless_return)]
+pub fn check_idct_accuracy(idct: &dyn JpegIdct) -> Vec<JpegIdctAccuracy>
+{
+    let mut results = Vec::new();
+    for &(low, high) in JPEG_IDCT_CHECK_RANGES.iter()
+    {
+        for sign in [1, -1]
+        {
+            results.push(check_range(idct, low, high, sign));
+        }
+    }
+    return results;
+}
+
+// Accuracy test for one input range and sign
+fn check_range(idct: &dyn JpegIdct, low: i32, high: i32, sign: i32) -> JpegIdctAccuracy
+{
+    let cos_table = make_cos_table();
+    let qt = [1u16; JPEG_SAMPLE_BLOCK_SIZE];
+    let mut rand = JpegIdctRandom::new();
+    let mut peak_error = 0;
+    let mut sum_error = [0i64; JPEG_SAMPLE_BLOCK_SIZE];
+    let mut sum_square_error = [0i64; JPEG_SAMPLE_BLOCK_SIZE];
+
+    for _ in 0..JPEG_IDCT_CHECK_NUM_BLOCKS
+    {
+        let mut block = [0f64; JPEG_SAMPLE_BLOCK_SIZE];
+        for v in block.iter_mut()
+        {
+            *v = (rand.next(low, high) * sign) as f64;
+        }
+
+        // Reference coefficients, rounded and clamped to the 12-bit range
+        let mut coef = [0i16; JPEG_SAMPLE_BLOCK_SIZE];
+        for (c, v) in coef.iter_mut().zip(forward_dct(&block, &cos_table).iter())
+        {
+            *c = v.round().clamp(-2048.0, 2047.0) as i16;
+        }
+
+        let reference = reference_idct(&coef, &cos_table);
+        let last_index = get_last_index(&coef);
+        idct.transform_unshifted(&mut coef, &qt, last_index);
+
+        for i in 0..JPEG_SAMPLE_BLOCK_SIZE
+        {
+            let error = coef[i] as i32 - reference[i];
+            peak_error = peak_error.max(error.abs());
+            sum_error[i] += error as i64;
+            sum_square_error[i] += (error * error) as i64;
+        }
+    }
+
+    let n = JPEG_IDCT_CHECK_NUM_BLOCKS as f64;
+    let mut peak_mse: f64 = 0.0;
+    let mut peak_mean_error: f64 = 0.0;
+    for i in 0..JPEG_SAMPLE_BLOCK_SIZE
+    {
+        let mse = sum_square_error[i] as f64 / n;
+        let mean_error = sum_error[i] as f64 / n;
+        peak_mse = peak_mse.max(mse);
+        if mean_error.abs() > peak_mean_error.abs()
+        {
+            peak_mean_error = mean_error;
+        }
+    }
+    let total = n * JPEG_SAMPLE_BLOCK_SIZE as f64;
+
+    JpegIdctAccuracy
+    {
+        low,
+        high,
+        sign,
+        peak_error,
+        peak_mse,
+        overall_mse: sum_square_error.iter().sum::<i64>() as f64 / total,
+        peak_mean_error,
+        overall_mean_error: sum_error.iter().sum::<i64>() as f64 / total,
+        zero_input_ok: check_zero_input(idct),
+    }
+}
+
+// All-zero coefficients must give all-zero samples
+#[allow(clippy::needless_return)]
+fn check_zero_input(idct: &dyn JpegIdct) -> bool
+{
+    let qt = [1u16; JPEG_SAMPLE_BLOCK_SIZE];
+    let mut coef = [0i16; JPEG_SAMPLE_BLOCK_SIZE];
+    idct.transform_unshifted(&mut coef, &qt, 0);
+    return coef.iter().all(|&v| v == 0);
+}
+
+// Zigzag index of the last non-zero coefficient
+fn get_last_index(coef: &[i16]) -> usize
+{
+    (0..JPEG_SAMPLE_BLOCK_SIZE).rev()
+        .find(|&i| coef[JPEG_REV_ZIGZAG_TABLE[i] as usize] != 0)
+        .unwrap_or(0)
+}
+
+// c(u) * cos((2x + 1) * u * pi / 16) / 2, indexed [u][x]
+#[allow(clippy::needless_return)]
+fn make_cos_table() -> [[f64; 8]; 8]
+{
+    let mut table = [[0f64; 8]; 8];
+    for (u, row) in table.iter_mut().enumerate()
+    {
+        let cu = if u == 0 { 0.5f64.sqrt() } else { 1.0 };
+        for (x, v) in row.iter_mut().enumerate()
+        {
+            *v = cu * ((2 * x + 1) as f64 * u as f64 * PI / 16.0).cos() / 2.0;
+        }
+    }
+    return table;
+}
+
+// Double-precision 2D forward DCT (rows, then columns)
+#[allow(clippy::needless_return)]
+fn forward_dct(block: &[f64; JPEG_SAMPLE_BLOCK_SIZE], c: &[[f64; 8]; 8]) -> [f64; JPEG_SAMPLE_BLOCK_SIZE]
+{
+    let mut tmp = [0f64; JPEG_SAMPLE_BLOCK_SIZE];
+    for y in 0..8
+    {
+        for u in 0..8
+        {
+            tmp[y * 8 + u] = (0..8).map(|x| c[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+    let mut out = [0f64; JPEG_SAMPLE_BLOCK_SIZE];
+    for v in 0..8
+    {
+        for u in 0..8
+        {
+            out[v * 8 + u] = (0..8).map(|y| c[v][y] * tmp[y * 8 + u]).sum();
+        }
+    }
+    return out;
+}
+
+// Double-precision 2D inverse DCT (rows, then columns), rounded and clamped to [-256, 255]
+#[allow(clippy::needless_return)]
+fn reference_idct(coef: &[i16; JPEG_SAMPLE_BLOCK_SIZE], c: &[[f64; 8]; 8]) -> [i32; JPEG_SAMPLE_BLOCK_SIZE]
+{
+    let mut tmp = [0f64; JPEG_SAMPLE_BLOCK_SIZE];
+    for v in 0..8
+    {
+        for x in 0..8
+        {
+            tmp[v * 8 + x] = (0..8).map(|u| c[u][x] * coef[v * 8 + u] as f64).sum();
+        }
+    }
+    let mut out = [0i32; JPEG_SAMPLE_BLOCK_SIZE];
+    for y in 0..8
+    {
+        for x in 0..8
+        {
+            let sum: f64 = (0..8).map(|v| c[v][y] * tmp[v * 8 + x]).sum();
+            out[y * 8 + x] = (sum.round() as i32).clamp(-256, 255);
+        }
+    }
+    return out;
+}
+
+//========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::jpeg_idct::JpegIdctMethod;
+
+    #[test]
+    fn integer_idct_is_compliant()
+    {
+        let idct = JpegIdctMethod::Integer.create_idct();
+        for result in check_idct_accuracy(idct.as_ref())
+        {
+            assert!(result.is_compliant(), "{:?}", result);
+        }
+    }
+
+    // The fast integer IDCT is not compliant (as libjpeg's jidctfst.c): with the all-ones
+    // quantization table of the test, the AAN scale factors folded with 2 fractional bits
+    // round the multipliers of the high frequencies down to 0 or 1.
+    #[test]
+    fn ifast_idct_is_not_compliant()
+    {
+        let idct = JpegIdctMethod::IntegerFast.create_idct();
+        for result in check_idct_accuracy(idct.as_ref())
+        {
+            assert!(!result.is_compliant(), "{:?}", result);
+            assert!(result.peak_error > JPEG_IDCT_PEAK_ERROR_LIMIT);
+            assert!(result.zero_input_ok);
+        }
+    }
+
+    #[test]
+    fn float_reference_idct_is_compliant()
+    {
+        let idct = JpegIdctMethod::FloatReference.create_idct();
+        for result in check_idct_accuracy(idct.as_ref())
+        {
+            assert!(result.is_compliant(), "{:?}", result);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//========================================================
+//  jpeg_info.rs
+//
+//========================================================
+use crate::jpeg_constants::JpegMarker;
+use crate::jpeg_raw_data::JpegReader;
+use crate::jpeg_frame_info::JpegFrameHeaderInfo;
+use crate::jpeg_frame_info::JpegScanHeaderInfo;
+use crate::jpeg_frame_info::JpegSamplingFactor;
+
+const JPEG_DHT_NUM_SLOTS: usize = 4;
+const JPEG_DHT_NUM_COUNTS: usize = 16;
+
+// Process type indicated by the SOFn marker
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegFrameType
+{
+    Baseline,
+    ExtendedSequential,
+    Progressive,
+    Lossless,
+}
+
+// Color space guessed from the component count and the APP markers (JFIF/Adobe)
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegColorSpace
+{
+    Grayscale,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    Ycck,
+    Unknown,
+}
+
+pub struct JpegInfo
+{
+    frame_header_info: JpegFrameHeaderInfo,
+    frame_type: JpegFrameType,
+    is_arithmetic: bool,
+    is_hierarchical: bool,
+    has_jfif: bool,
+    adobe_transform: Option<u8>,
+    has_exif: bool,
+    has_icc: bool,
+    uses_default_dht: bool,
+}
+
+#[allow(dead_code)]
+impl JpegInfo
+{
+    // Constructor
+    fn new() -> Self
+    {
+        JpegInfo
+        {
+            frame_header_info: JpegFrameHeaderInfo::new(),
+            frame_type: JpegFrameType::Baseline,
+            is_arithmetic: false,
+            is_hierarchical: false,
+            has_jfif: false,
+            adobe_transform: None,
+            has_exif: false,
+            has_icc: false,
+            uses_default_dht: false,
+        }
+    }
+
+    // Sets the frame type from the marker code (0xC0-0xCF except DHT/JPG/DAC)
+    fn set_frame_type(&mut self, m: u16)
+    {
+        let n = m & 0x0F;
+        self.frame_type = match n & 0x03
+        {
+            0 => JpegFrameType::Baseline,
+            1 => JpegFrameType::ExtendedSequential,
+            2 => JpegFrameType::Progressive,
+            _ => JpegFrameType::Lossless,
+        };
+        self.is_arithmetic = n & 0x08 != 0;
+        self.is_hierarchical = n & 0x04 != 0;
+    }
+
+    // Width/Height
+    pub fn get_dimension(&self) -> (usize, usize)
+    {
+        self.frame_header_info.get_dimension()
+    }
+
+    // Sample precision in bits
+    pub fn get_precision(&self) -> usize
+    {
+        self.frame_header_info.get_precision()
+    }
+
+    pub fn get_frame_type(&self) -> JpegFrameType
+    {
+        self.frame_type
+    }
+
+    pub fn is_arithmetic(&self) -> bool
+    {
+        self.is_arithmetic
+    }
+
+    // Differential (hierarchical) frame
+    pub fn is_hierarchical(&self) -> bool
+    {
+        self.is_hierarchical
+    }
+
+    // Number of components
+    pub fn get_num_components(&self) -> usize
+    {
+        self.frame_header_info.get_num_components()
+    }
+
+    // Sampling factor
+    pub fn get_sampling_factor(&self, index: usize) -> JpegSamplingFactor
+    {
+        self.frame_header_info.get_sampling_factor(index)
+    }
+
+    pub fn get_color_space(&self) -> JpegColorSpace
+    {
+        let num_components = self.get_num_components();
+
+        // Adobe APP14 transform flag takes priority
+        if let Some(transform) = self.adobe_transform
+        {
+            return match (num_components, transform)
+            {
+                (3, 0) => JpegColorSpace::Rgb,
+                (3, _) => JpegColorSpace::YCbCr,
+                (4, 2) => JpegColorSpace::Ycck,
+                (4, _) => JpegColorSpace::Cmyk,
+                (1, _) => JpegColorSpace::Grayscale,
+                _ => JpegColorSpace::Unknown,
+            };
+        }
+
+        match num_components
+        {
+            1 => JpegColorSpace::Grayscale,
+            3 =>
+            {
+                // Component IDs 'R', 'G', 'B' are used by some encoders for RGB
+                let is_rgb = self.frame_header_info.get_component_id(0) == b'R'
+                          && self.frame_header_info.get_component_id(1) == b'G'
+                          && self.frame_header_info.get_component_id(2) == b'B';
+                if is_rgb && !self.has_jfif { JpegColorSpace::Rgb } else { JpegColorSpace::YCbCr }
+            }
+            4 => JpegColorSpace::Cmyk,
+            _ => JpegColorSpace::Unknown,
+        }
+    }
+
+    // EXIF (APP1) segment is present
+    pub fn has_exif(&self) -> bool
+    {
+        self.has_exif
+    }
+
+    // ICC profile (APP2) segment is present
+    pub fn has_icc(&self) -> bool
+    {
+        self.has_icc
+    }
+
+    // The first scan has no DHT segment for some of the Huffman tables it uses, so the
+    // default tables of Annex K are used (Motion-JPEG)
+    pub fn uses_default_huffman_tables(&self) -> bool
+    {
+        self.uses_default_dht
+    }
+
+    // 構造体内容のダンプ
+    pub fn dump(&self)
+    {
+        println!("\n---- JPEG Info. ----");
+        println!("Type= {:?} / Arithmetic= {} / Hierarchical= {}",
+                 self.frame_type, self.is_arithmetic, self.is_hierarchical);
+        println!("Color space= {:?} / EXIF= {} / ICC= {} / Default DHT= {}",
+                 self.get_color_space(), self.has_exif, self.has_icc, self.uses_default_dht);
+        self.frame_header_info.dump();
+    }
+}
+
+// Reads header segments up to the first SOS and returns the image information.
+// Returns None if the data is not a JPEG file or ends before a frame header is found.
+#[allow(dead_code)]
+pub fn probe(data: &[u8]) -> Option<JpegInfo>
+{
+    let mut reader = JpegReader::from_slice(data);
+    if reader.get_remaining_size() < 2 || reader.read_u16be() != JpegMarker::SOI as u16
+    {
+        return None;
+    }
+
+    let mut info = JpegInfo::new();
+    let mut has_frame_header = false;
+    let mut dht_defined = [[false; JPEG_DHT_NUM_SLOTS]; 2];   // [DC/AC][slot]
+    while reader.get_remaining_size() >= 2
+    {
+        // Skips garbage bytes and fill bytes (0xFF) preceding the marker code
+        if reader.read_u8() != 0xFF
+        {
+            continue;
+        }
+        let mut code = reader.read_u8();
+        while code == 0xFF && !reader.is_end()
+        {
+            code = reader.read_u8();
+        }
+        let m = 0xFF00 | code as u16;
+
+        if m == JpegMarker::SOS as u16
+        {
+            info.uses_default_dht = !info.is_arithmetic && uses_undefined_dht(&mut reader, &dht_defined);
+            break;
+        }
+        if m == JpegMarker::EOI as u16
+        {
+            break;
+        }
+        if code == 0x00 || code == 0x01 || (0xD0..=0xD7).contains(&code)
+        {
+            // Stuffed byte / TEM / RSTn have no segment
+            continue;
+        }
+
+        // Segment size (including the size field itself)
+        if reader.get_remaining_size() < 2
+        {
+            break;
+        }
+        let seg_size = reader.read_u16be() as usize;
+        if seg_size < 2 || reader.get_remaining_size() < seg_size - 2
+        {
+            break;
+        }
+
+        let mut reader2 = reader.copy();
+        let is_sof = (JpegMarker::SOF0 as u16..=JpegMarker::SOF15 as u16).contains(&m)
+                  && m != JpegMarker::DHT as u16
+                  && m != JpegMarker::JPG as u16
+                  && m != JpegMarker::DAC as u16;
+        if is_sof && !has_frame_header
+        {
+            if info.frame_header_info.parse_segment(&mut reader2, seg_size - 2).is_err()
+            {
+                return None;
+            }
+            info.set_frame_type(m);
+            has_frame_header = true;
+        }
+        else if m == JpegMarker::DHT as u16
+        {
+            mark_dht_slots(&mut reader2, seg_size - 2, &mut dht_defined);
+        }
+        else if m == JpegMarker::APP0 as u16
+        {
+            info.has_jfif |= reader2.has_signature(b"JFIF\0");
+        }
+        else if m == JpegMarker::APP1 as u16
+        {
+            info.has_exif |= reader2.has_signature(b"Exif\0\0");
+        }
+        else if m == JpegMarker::APP2 as u16
+        {
+            info.has_icc |= reader2.has_signature(b"ICC_PROFILE\0");
+        }
+        else if m == JpegMarker::APP14 as u16 && seg_size >= 14 && reader2.has_signature(b"Adobe")
+        {
+            // "Adobe", version(2), flags0(2), flags1(2), transform(1)
+            reader2.move_pos(11);
+            info.adobe_transform = Some(reader2.read_u8());
+        }
+        reader.move_pos(seg_size as isize - 2);
+    }
+
+    if has_frame_header
+    {
+        Some(info)
+    }
+    else
+    {
+        None
+    }
+}
+
+// Marks the table slots defined by the tables of a DHT segment
+fn mark_dht_slots(reader: &mut JpegReader, seg_size: usize, defined: &mut [[bool; JPEG_DHT_NUM_SLOTS]; 2])
+{
+    let mut remaining = seg_size;
+    while remaining > JPEG_DHT_NUM_COUNTS
+    {
+        let id = reader.read_u8() as usize;
+        let num_symbols = (0..JPEG_DHT_NUM_COUNTS).map(|_| reader.read_u8() as usize).sum::<usize>();
+        if (id >> 4) > 1 || (id & 0x0F) >= JPEG_DHT_NUM_SLOTS || remaining < 1 + JPEG_DHT_NUM_COUNTS + num_symbols
+        {
+            return;
+        }
+        defined[id >> 4][id & 0x0F] = true;
+        reader.move_pos(num_symbols as isize);
+        remaining -= 1 + JPEG_DHT_NUM_COUNTS + num_symbols;
+    }
+}
+
+// Whether the scan header refers to a Huffman table slot which no DHT segment defined
+// (only the tables used by the scan are checked)
+fn uses_undefined_dht(reader: &mut JpegReader, defined: &[[bool; JPEG_DHT_NUM_SLOTS]; 2]) -> bool
+{
+    if reader.get_remaining_size() < 2
+    {
+        return false;
+    }
+    let seg_size = reader.read_u16be() as usize;
+    if seg_size < 2 || reader.get_remaining_size() < seg_size - 2
+    {
+        return false;
+    }
+    let mut scan = JpegScanHeaderInfo::new();
+    if !scan.parse_segment(reader, seg_size - 2)
+    {
+        return false;
+    }
+    (0..scan.get_num_components()).any(|i|
+        (scan.uses_dc_table() && !defined[0][scan.get_dc_table_id(i)])
+            || (scan.uses_ac_table() && !defined[1][scan.get_ac_table_id(i)])
+    )
+}
+
+//========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const P420: &[u8] = include_bytes!("../testdata/p420.jpg");
+
+    // The data up to the end of the SOF0 segment, with the SOF0 segment modified
+    fn modify_sof(modify: impl Fn(&mut [u8])) -> Vec<u8>
+    {
+        let pos = P420.windows(2).position(|m| m == [0xFF, 0xC0]).unwrap();
+        let seg_size = (P420[pos + 2] as usize) << 8 | P420[pos + 3] as usize;
+        let mut data = P420[..pos + 2 + seg_size].to_vec();
+        modify(&mut data[pos..]);
+        data
+    }
+
+    #[test]
+    fn probe_rejects_malformed_sof()
+    {
+        assert!(probe(&modify_sof(|_| {})).is_some());
+        // Number of components: 0 and 5
+        assert!(probe(&modify_sof(|sof| sof[9] = 0)).is_none());
+        assert!(probe(&modify_sof(|sof| sof[9] = 5)).is_none());
+        // Sampling factor with H = 0
+        assert!(probe(&modify_sof(|sof| sof[11] = 0x02)).is_none());
+        // Segment without the component info at the end of the data
+        let mut data = modify_sof(|sof| sof[3] = 8);
+        data.truncate(data.len() - 9);
+        assert!(probe(&data).is_none());
+    }
+
+    // The data without the DHT segments before the first SOS
+    fn remove_dht(data: &[u8]) -> Vec<u8>
+    {
+        let mut out = data[..2].to_vec();
+        let mut pos = 2;
+        while data[pos + 1] != 0xDA
+        {
+            let end = pos + 2 + ((data[pos + 2] as usize) << 8 | data[pos + 3] as usize);
+            if data[pos + 1] != 0xC4
+            {
+                out.extend_from_slice(&data[pos..end]);
+            }
+            pos = end;
+        }
+        out.extend_from_slice(&data[pos..]);
+        out
+    }
+
+    #[test]
+    fn default_huffman_tables()
+    {
+        assert!(!probe(P420).unwrap().uses_default_huffman_tables());
+        assert!(probe(&remove_dht(P420)).unwrap().uses_default_huffman_tables());
+
+        // The first scan of a progressive file has only the DC coefficients and
+        // the AC table slot is not defined.
+        let progressive: &[u8] = include_bytes!("../testdata/p420_progressive.jpg");
+        let info = probe(progressive).unwrap();
+        assert!(info.get_frame_type() == JpegFrameType::Progressive);
+        assert!(!info.uses_default_huffman_tables());
+    }
+}
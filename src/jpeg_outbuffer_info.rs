@@ -2,12 +2,59 @@
 //  jpeg_outbuffer_info.rs
 //
 //========================================================
+use crate::jpeg_color_converter::JpegColorConversion;
+use crate::jpeg_sampler::JpegUpsampling;
+use crate::jpeg_idct::JpegScale;
+
+// Pixel format of the output buffer
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegPixelFormat
+{
+    Rgb,
+    Rgba,       // Alpha is always opaque (0xFF)
+    Bgr,
+    Bgra,       // Alpha is always opaque (0xFF)
+    Gray8,
+    Rgb565,     // 16-bit little endian (R:15-11, G:10-5, B:4-0)
+}
 
 pub struct JpegOutBufferInfo
 {
     width: u16,
     height: u16,
     bpp: u8,
+    pixel_format: JpegPixelFormat,
+    dithering: bool,
+    color_conversion: JpegColorConversion,
+    upsampling: JpegUpsampling,
+    scale: JpegScale,
+    origin: usize,
+    row_pitch: Option<isize>,
+    crop: Option<(usize, usize, usize, usize)>,
+    offset_x: usize,
+    offset_y: usize,
+    image_width: usize,
+    image_height: usize,
+}
+
+#[allow(dead_code)]
+impl JpegPixelFormat
+{
+    // Bytes per pixel
+    pub fn get_bpp(&self) -> usize
+    {
+        match self
+        {
+            JpegPixelFormat::Rgb | JpegPixelFormat::Bgr => 3,
+            JpegPixelFormat::Rgba | JpegPixelFormat::Bgra => 4,
+            JpegPixelFormat::Gray8 => 1,
+            JpegPixelFormat::Rgb565 => 2,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -21,17 +68,84 @@ impl JpegOutBufferInfo
             width: 0,
             height: 0,
             bpp: 3,
+            pixel_format: JpegPixelFormat::Rgb,
+            dithering: false,
+            color_conversion: JpegColorConversion::new(),
+            upsampling: JpegUpsampling::Fancy,
+            scale: JpegScale::Full,
+            origin: 0,
+            row_pitch: None,
+            crop: None,
+            offset_x: 0,
+            offset_y: 0,
+            image_width: 0,
+            image_height: 0,
         }
     }
 
     // Sets parmeters
+    //
+    // `width`/`height` are the size of the whole image, and the buffer gets the
+    // size of the crop rectangle (clipped to the image).
     pub fn set_parameters(&mut self, width: usize, height: usize, bpp: usize)
     {
-        self.width = width as u16;
-        self.height = height as u16;
+        let (x, y, crop_width, crop_height) = self.crop.unwrap_or((0, 0, width, height));
+        self.offset_x = x.min(width);
+        self.offset_y = y.min(height);
+        self.width = crop_width.min(width - self.offset_x) as u16;
+        self.height = crop_height.min(height - self.offset_y) as u16;
+        self.image_width = width;
+        self.image_height = height;
         self.bpp = bpp as u8;
     }
 
+    // Sets the crop rectangle (x, y, width, height) in pixels of the scaled image
+    pub fn set_crop(&mut self, crop: Option<(usize, usize, usize, usize)>)
+    {
+        self.crop = crop;
+    }
+
+    // Sets the pixel format (bpp follows the format)
+    pub fn set_pixel_format(&mut self, pixel_format: JpegPixelFormat)
+    {
+        self.pixel_format = pixel_format;
+        self.bpp = pixel_format.get_bpp() as u8;
+    }
+
+    // Enables ordered dithering (for the formats with less than 8 bits per channel)
+    pub fn set_dithering(&mut self, dithering: bool)
+    {
+        self.dithering = dithering;
+    }
+
+    // Sets the YCbCr -> RGB conversion (matrix, range and precision)
+    pub fn set_color_conversion(&mut self, conversion: JpegColorConversion)
+    {
+        self.color_conversion = conversion;
+    }
+
+    // Sets the chroma upsampling method
+    pub fn set_upsampling(&mut self, upsampling: JpegUpsampling)
+    {
+        self.upsampling = upsampling;
+    }
+
+    // Sets the output scale (1/1, 1/2, 1/4 or 1/8)
+    pub fn set_scale(&mut self, scale: JpegScale)
+    {
+        self.scale = scale;
+    }
+
+    // Sets the layout of the caller's buffer
+    //
+    // origin:    byte offset of the first pixel of the top row
+    // row_pitch: byte offset between rows (negative for bottom-up buffers)
+    pub fn set_layout(&mut self, origin: usize, row_pitch: isize)
+    {
+        self.origin = origin;
+        self.row_pitch = Some(row_pitch);
+    }
+
     // Gets width
     pub fn get_width(&self) -> usize
     {
@@ -50,16 +164,107 @@ impl JpegOutBufferInfo
         (self.width as usize, self.height as usize)
     }
 
+    // Gets position of the crop rectangle in the image
+    pub fn get_crop_offset(&self) -> (usize, usize)
+    {
+        (self.offset_x, self.offset_y)
+    }
+
+    // Gets width/height of the whole image
+    pub fn get_image_dimension(&self) -> (usize, usize)
+    {
+        (self.image_width, self.image_height)
+    }
+
     // Gets bpp
     pub fn get_bpp(&self) -> usize
     {
         self.bpp as usize
     }
 
+    // Gets pixel format
+    pub fn get_pixel_format(&self) -> JpegPixelFormat
+    {
+        self.pixel_format
+    }
+
+    // Gets dithering flag
+    pub fn is_dithering_enabled(&self) -> bool
+    {
+        self.dithering
+    }
+
+    // Gets YCbCr -> RGB conversion
+    pub fn get_color_conversion(&self) -> &JpegColorConversion
+    {
+        &self.color_conversion
+    }
+
+    // Gets chroma upsampling method
+    pub fn get_upsampling(&self) -> JpegUpsampling
+    {
+        self.upsampling
+    }
+
+    // Gets output scale
+    pub fn get_scale(&self) -> JpegScale
+    {
+        self.scale
+    }
+
+    // Gets size of a row of pixels (without padding)
+    pub fn get_row_size(&self) -> usize
+    {
+        self.width as usize * self.bpp as usize
+    }
+
+    // Gets origin
+    pub fn get_origin(&self) -> usize
+    {
+        self.origin
+    }
+
+    // Gets row pitch (tightly packed rows by default)
+    pub fn get_row_pitch(&self) -> isize
+    {
+        match self.row_pitch
+        {
+            Some(pitch) => pitch,
+            None => self.get_row_size() as isize,
+        }
+    }
+
+    // Gets byte offset of the row
+    pub fn get_row_offset(&self, y: usize) -> usize
+    {
+        (self.origin as isize + y as isize * self.get_row_pitch()) as usize
+    }
+
+    // Checks the layout (rows must not overlap nor go below offset 0)
+    pub fn is_valid_layout(&self) -> bool
+    {
+        let pitch = self.get_row_pitch();
+        let last_row = self.height.saturating_sub(1) as isize;
+        pitch.unsigned_abs() >= self.get_row_size()
+            && self.origin as isize + last_row * pitch >= 0
+    }
+
     // Gets total buffer size
     pub fn get_total_buffer_size(&self) -> usize
     {
-        self.width as usize * self.height as usize * self.bpp as usize
+        if self.height == 0
+        {
+            return 0;
+        }
+        let last_row = self.height as usize - 1;
+        if self.get_row_pitch() >= 0
+        {
+            self.get_row_offset(last_row) + self.get_row_size()
+        }
+        else
+        {
+            self.origin + self.get_row_size()
+        }
     }
 
 }
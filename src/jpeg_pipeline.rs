@@ -0,0 +1,172 @@
+//========================================================
+//  jpeg_pipeline.rs
+//
+//========================================================
+use crate::jpeg_sample_block::JpegSampleBlock;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+// Entropy-decoded coefficient blocks of one MCU row (the blocks of each MCU in order)
+pub type JpegCoefficientRow = Arc<Vec<JpegSampleBlock>>;
+
+struct JpegCoefficientSlot
+{
+    mcu_y: usize,
+    blocks: Option<JpegCoefficientRow>,
+    num_uses: usize,
+}
+
+// Ring buffer of MCU rows between the entropy decoding thread and the IDCT threads
+//
+// The MCU row `mcu_y` is stored in the slot `mcu_y % size`, which is released
+// after the row is taken `num_uses` times (some rows are also the context rows
+// of the neighbouring bands).
+//
+// The ring is closed when a thread of the pipeline panics, and the waiting
+// threads give up instead of waiting for the rows which never come.
+pub struct JpegCoefficientRing
+{
+    slots: Mutex<Vec<JpegCoefficientSlot>>,
+    changed: Condvar,
+    closed: AtomicBool,
+}
+
+// Closes the ring if the thread panics while this is alive
+pub struct JpegRingPanicGuard<'r>
+{
+    ring: &'r JpegCoefficientRing,
+}
+
+#[allow(dead_code)]
+impl JpegCoefficientRing
+{
+    // Constructor
+    pub fn new(size: usize) -> Self
+    {
+        let size = size.max(1);
+        let mut slots = Vec::with_capacity(size);
+        for _ in 0..size
+        {
+            slots.push(JpegCoefficientSlot { mcu_y: 0, blocks: None, num_uses: 0 });
+        }
+        JpegCoefficientRing
+        {
+            slots: Mutex::new(slots),
+            changed: Condvar::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    // Stores an MCU row (waits until its slot is released)
+    //
+    // Returns false if the ring has been closed.
+    pub fn put(&self, mcu_y: usize, blocks: Vec<JpegSampleBlock>, num_uses: usize) -> bool
+    {
+        let mut slots = self.slots.lock().unwrap();
+        let index = mcu_y % slots.len();
+        while slots[index].blocks.is_some()
+        {
+            if self.is_closed()
+            {
+                return false;
+            }
+            slots = self.changed.wait(slots).unwrap();
+        }
+        slots[index] = JpegCoefficientSlot { mcu_y, blocks: Some(Arc::new(blocks)), num_uses };
+        self.changed.notify_all();
+        true
+    }
+
+    // Takes an MCU row (waits until it is stored)
+    //
+    // Returns None if the ring has been closed.
+    pub fn take(&self, mcu_y: usize) -> Option<JpegCoefficientRow>
+    {
+        let mut slots = self.slots.lock().unwrap();
+        let index = mcu_y % slots.len();
+        loop
+        {
+            if self.is_closed()
+            {
+                return None;
+            }
+            let slot = &mut slots[index];
+            if slot.mcu_y == mcu_y
+            {
+                if let Some(blocks) = slot.blocks.clone()
+                {
+                    slot.num_uses -= 1;
+                    if slot.num_uses == 0
+                    {
+                        slot.blocks = None;
+                        self.changed.notify_all();
+                    }
+                    return Some(blocks);
+                }
+            }
+            slots = self.changed.wait(slots).unwrap();
+        }
+    }
+
+    // Wakes up all the waiting threads to give up
+    pub fn close(&self)
+    {
+        // The flag is set under the lock not to be missed between the check and the wait.
+        let _slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        self.closed.store(true, Ordering::Release);
+        self.changed.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool
+    {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // Guard to be held by each thread of the pipeline
+    pub fn panic_guard(&self) -> JpegRingPanicGuard<'_>
+    {
+        JpegRingPanicGuard { ring: self }
+    }
+}
+
+impl Drop for JpegRingPanicGuard<'_>
+{
+    fn drop(&mut self)
+    {
+        if std::thread::panicking()
+        {
+            self.ring.close();
+        }
+    }
+}
+
+//========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn panic_releases_waiting_threads()
+    {
+        let ring = JpegCoefficientRing::new(2);
+        let result = std::thread::scope(|scope|
+        {
+            let consumer = scope.spawn(|| ring.take(1));
+            let producer = scope.spawn(||
+            {
+                let _guard = ring.panic_guard();
+                assert!(ring.put(0, Vec::new(), 1));
+                panic!("entropy decoding failed");
+            });
+            (producer.join().is_err(), consumer.join().unwrap())
+        });
+        assert!(result.0);
+        assert!(result.1.is_none());
+        assert!(!ring.put(2, Vec::new(), 1));
+    }
+}
@@ -0,0 +1,123 @@
+//========================================================
+//  jpeg_planar_image.rs
+//
+//========================================================
+
+// Resolution of the chroma planes for the planar output
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegPlanarLayout
+{
+    Native,         // As stored in the file (I420 for 4:2:0, I422 for 4:2:2, I444 for 4:4:4, ...)
+    FullResolution, // Chroma planes upsampled to the size of the luma plane
+}
+
+// One plane of 8-bit samples (Y, Cb or Cr)
+pub struct JpegPlane
+{
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+// Component planes of a decoded image (without color conversion)
+pub struct JpegPlanarImage
+{
+    planes: Vec<JpegPlane>,
+    layout: JpegPlanarLayout,
+}
+
+#[allow(dead_code)]
+impl JpegPlane
+{
+    // Constructor
+    pub fn new(width: usize, height: usize) -> Self
+    {
+        JpegPlane
+        {
+            data: vec![0; width * height],
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    pub fn get_width(&self) -> usize
+    {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize
+    {
+        self.height
+    }
+
+    // Distance between rows in bytes
+    pub fn get_stride(&self) -> usize
+    {
+        self.stride
+    }
+
+    pub fn get_data(&self) -> &[u8]
+    {
+        &self.data
+    }
+
+    pub fn get_row(&self, y: usize) -> &[u8]
+    {
+        let pos = y * self.stride;
+        &self.data[pos..pos + self.width]
+    }
+
+    pub fn get_row_mut(&mut self, y: usize) -> &mut [u8]
+    {
+        let pos = y * self.stride;
+        &mut self.data[pos..pos + self.width]
+    }
+}
+
+#[allow(dead_code)]
+impl JpegPlanarImage
+{
+    // Constructor
+    pub fn new(layout: JpegPlanarLayout) -> Self
+    {
+        JpegPlanarImage
+        {
+            planes: Vec::new(),
+            layout,
+        }
+    }
+
+    pub fn add_plane(&mut self, plane: JpegPlane)
+    {
+        self.planes.push(plane);
+    }
+
+    pub fn get_layout(&self) -> JpegPlanarLayout
+    {
+        self.layout
+    }
+
+    // Number of planes (1 for grayscale, 3 for YCbCr)
+    pub fn get_num_planes(&self) -> usize
+    {
+        self.planes.len()
+    }
+
+    pub fn get_plane(&self, index: usize) -> &JpegPlane
+    {
+        &self.planes[index]
+    }
+
+    pub fn get_plane_mut(&mut self, index: usize) -> &mut JpegPlane
+    {
+        &mut self.planes[index]
+    }
+}
+
+//========================================================
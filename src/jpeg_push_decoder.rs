@@ -0,0 +1,211 @@
+//========================================================
+//  jpeg_push_decoder.rs
+//
+//========================================================
+use crate::jpeg_control::JpegControl;
+use crate::jpeg_control::JpegMcuRowBuffer;
+use crate::jpeg_sample_block::JpegMinimumCodedUnit;
+use crate::jpeg_row_sink::JpegBufferSink;
+use crate::jpeg_huffman_table::JpegDhtError;
+use crate::jpeg_frame_info::JpegFrameError;
+
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegPushStatus
+{
+    NeedMoreData,
+    Finished,
+    InvalidData,    // The headers can't be decoded (see get_dht_error() and get_frame_error())
+}
+
+// Decoder fed with data piece by piece (streaming upload, slow network, etc.)
+//
+// The decoding state (bit position, DC predictors and MCU index) is kept
+// between the calls of feed(), and an MCU which needs more data than
+// available is decoded again when the next data arrives.
+pub struct JpegPushDecoder
+{
+    control: JpegControl<'static>,
+    mcu: JpegMinimumCodedUnit,
+    row_buf: JpegMcuRowBuffer,
+    out_buf: Vec<u8>,
+    is_header_parsed: bool,
+    read_pos: usize,
+    read_bitpos: usize,
+    mcu_index: usize,
+    num_mcus: usize,
+    num_completed_rows: usize,
+}
+
+#[allow(dead_code)]
+impl JpegPushDecoder
+{
+    // Constructor
+    pub fn new() -> Self
+    {
+        JpegPushDecoder
+        {
+            control: JpegControl::new(),
+            mcu: JpegMinimumCodedUnit::new(),
+            row_buf: JpegMcuRowBuffer::new(),
+            out_buf: Vec::new(),
+            is_header_parsed: false,
+            read_pos: 0,
+            read_bitpos: 0,
+            mcu_index: 0,
+            num_mcus: 0,
+            num_completed_rows: 0,
+        }
+    }
+
+    // Adds the data which arrived and decodes as many MCUs as possible
+    pub fn feed(&mut self, data: &[u8]) -> JpegPushStatus
+    {
+        self.control.append_data(data);
+        if !self.is_header_parsed
+        {
+            if !self.control.parse_headers()
+            {
+                if self.control.get_dht_error().is_some() || self.control.get_frame_error().is_some()
+                {
+                    return JpegPushStatus::InvalidData;
+                }
+                return JpegPushStatus::NeedMoreData;
+            }
+            self.start_scan();
+        }
+        self.decode_mcus(false)
+    }
+
+    // Decodes the remaining MCUs assuming zeros for missing data (truncated stream)
+    pub fn finish(&mut self) -> JpegPushStatus
+    {
+        if !self.is_header_parsed
+        {
+            return JpegPushStatus::NeedMoreData;
+        }
+        self.decode_mcus(true)
+    }
+
+    // Image width/height (available after the header is parsed)
+    pub fn get_dimension(&self) -> (usize, usize)
+    {
+        self.control.get_dimension()
+    }
+
+    // Error of the DHT segment when feed() returned InvalidData
+    pub fn get_dht_error(&self) -> Option<JpegDhtError>
+    {
+        self.control.get_dht_error()
+    }
+
+    // Error of the SOF segment when feed() returned InvalidData
+    pub fn get_frame_error(&self) -> Option<JpegFrameError>
+    {
+        self.control.get_frame_error()
+    }
+
+    // Number of pixel rows which are completely decoded
+    pub fn get_num_completed_rows(&self) -> usize
+    {
+        self.num_completed_rows
+    }
+
+    // Output buffer (only the first get_num_completed_rows() rows are valid)
+    pub fn get_output(&self) -> &[u8]
+    {
+        &self.out_buf
+    }
+
+    fn start_scan(&mut self)
+    {
+        self.mcu = self.control.create_mcu();
+        self.num_mcus = self.control.get_num_mcus(&self.mcu);
+        self.row_buf = self.control.create_mcu_row_buffer(&self.mcu);
+        self.out_buf = vec![0; self.control.get_total_buffer_size()];
+        let bsreader = self.control.get_bit_stream_reader();
+        self.read_pos = bsreader.get_pos();
+        self.read_bitpos = bsreader.get_bitpos();
+        self.mcu_index = 0;
+        self.num_completed_rows = 0;
+        self.is_header_parsed = true;
+    }
+
+    fn decode_mcus(&mut self, allows_overrun: bool) -> JpegPushStatus
+    {
+        let mut bsreader = self.control.get_bit_stream_reader();
+        bsreader.set_pos(self.read_pos, self.read_bitpos);
+        let num_mcus_h = self.control.get_num_mcus_h(&self.mcu);
+        let (_, height) = self.control.get_dimension();
+        let mut sink = JpegBufferSink::new(&mut self.out_buf);
+        let control = &self.control;
+        let mcu_height = self.mcu.get_height();
+        let num_completed_rows = &mut self.num_completed_rows;
+        let mut output = |row_buf: &mut JpegMcuRowBuffer, mcu_y: usize|
+        {
+            control.write_mcu_row(row_buf, mcu_y, &mut sink);
+            *num_completed_rows = height.min((mcu_y + 1) * mcu_height);
+        };
+
+        while self.mcu_index < self.num_mcus
+        {
+            let mcu_x = self.mcu_index % num_mcus_h;
+            let last_dc = self.mcu.get_dc_predictors();
+            if self.mcu_index > 0 && control.is_restart_mcu(self.mcu_index)
+                && !control.process_restart(&mut self.mcu, &mut bsreader) && !allows_overrun
+            {
+                // The RSTn marker has not arrived yet.
+                return JpegPushStatus::NeedMoreData;
+            }
+            control.decode_mcu(&mut self.mcu, &mut bsreader, &mut self.row_buf, mcu_x);
+            if bsreader.is_overrun() && !allows_overrun
+            {
+                // Rewinds to the beginning of this MCU and waits for more data
+                self.mcu.set_dc_predictors(last_dc);
+                return JpegPushStatus::NeedMoreData;
+            }
+            if mcu_x == num_mcus_h - 1
+            {
+                let mcu_y = self.mcu_index / num_mcus_h;
+                control.complete_mcu_row(&self.mcu, &mut self.row_buf, mcu_y, &mut output);
+            }
+            self.read_pos = bsreader.get_pos();
+            self.read_bitpos = bsreader.get_bitpos();
+            self.mcu_index += 1;
+        }
+        JpegPushStatus::Finished
+    }
+}
+
+//========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn byte_by_byte_equals_whole_data()
+    {
+        let data: &[u8] = include_bytes!("../testdata/p420.jpg");
+        let mut jpeg = JpegControl::new();
+        jpeg.read_from_slice(data);
+        assert!(jpeg.parse_headers());
+        let mut expected = vec![0; jpeg.get_total_buffer_size()];
+        jpeg.decode_image(&mut expected);
+
+        let mut decoder = JpegPushDecoder::new();
+        let mut status = JpegPushStatus::NeedMoreData;
+        for byte in data.chunks(1)
+        {
+            assert!(status != JpegPushStatus::InvalidData);
+            status = decoder.feed(byte);
+        }
+        assert!(status == JpegPushStatus::Finished);
+        assert!(decoder.get_num_completed_rows() == decoder.get_dimension().1);
+        assert!(decoder.get_output() == expected);
+    }
+}
@@ -6,7 +6,7 @@ use crate::jpeg_constants::JPEG_SAMPLE_BLOCK_SIZE;
 use crate::jpeg_constants::JPEG_REV_ZIGZAG_TABLE;
 use crate::jpeg_raw_data::JpegReader;
 
-const JPEG_NUM_DQT: usize = 4;
+pub const JPEG_NUM_DQT: usize = 4;
 
 #[derive(Copy)]
 #[derive(Clone)]
@@ -33,6 +33,7 @@ impl JpegQuantizationTable
     }
 
     // セグメント内容の parse と読み込み
+    #[allow(clippy::needless_range_loop)]
     fn read_table(&mut self, reader: &mut JpegReader)
     {
         // Quantization table info
@@ -2,87 +2,138 @@
 //  jpeg_raw_data.rs
 //
 //========================================================
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::prelude::*;
 
-pub struct JpegRawData
+// Holds the whole JPEG stream, either owned or borrowed from the caller
+pub struct JpegRawData<'a>
 {
-    data: Vec<u8>,
-    size: usize,
+    data: Cow<'a, [u8]>,
 }
 
 pub struct JpegReader<'a>
 {
-    data_ref: &'a JpegRawData,
+    data_ref: &'a [u8],
     read_pos: usize,
 }
 
+// Bit reader of the entropy-coded data
+//
+// The bits are loaded byte by byte into a 64-bit accumulator (left aligned), with the
+// stuffed 0x00 after 0xFF removed. Loading stops at a marker or at the end of data,
+// after which zero bits are fed.
 pub struct JpegBitStreamReader<'a>
 {
-    data_ref: &'a JpegRawData,
-    read_pos: usize,
-    read_bitpos: usize,
-    needs_escape: usize,
+    data_ref: &'a [u8],
+    read_pos: usize,        // Next byte to be loaded
+    bit_buffer: u64,
+    bit_count: usize,       // Number of bits in the accumulator
+    fill_count: usize,      // Number of zero bits at the end of the accumulator which are not data
+    data_end: usize,        // End of the last data byte loaded
+    marker: Option<u8>,     // Marker where the loading stopped
+    is_overrun: bool,
+}
+
+#[allow(clippy::needless_return)]
+fn read_u8_at(data: &[u8], pos: usize) -> Option<u8>
+{
+    if pos >= data.len()
+    {
+        return None;
+    }
+    else
+    {
+        return Some(data[pos]);
+    }
+}
+
+#[allow(clippy::needless_return)]
+fn read_u16be_at(data: &[u8], pos: usize) -> Option<u16>
+{
+    if pos + 1 >= data.len()
+    {
+        return None;
+    }
+    else
+    {
+        let val: u16 = (data[pos] as u16) << 8
+                     | data[pos+1] as u16;
+        return Some(val);
+    }
 }
 
 #[allow(dead_code)]
-impl JpegRawData
+#[allow(clippy::needless_return)]
+impl<'a> JpegRawData<'a>
 {
     // Constructor
     pub fn new() -> Self
     {
-        JpegRawData { data: Vec::new(), size: 0 }
+        JpegRawData { data: Cow::Borrowed(&[]) }
+    }
+
+    // Borrows the caller's buffer (no copy)
+    pub fn from_slice(data: &'a [u8]) -> Self
+    {
+        JpegRawData { data: Cow::Borrowed(data) }
+    }
+
+    // Takes the ownership of the buffer (no copy)
+    pub fn from_vec(data: Vec<u8>) -> Self
+    {
+        JpegRawData { data: Cow::Owned(data) }
+    }
+
+    // Reads the whole stream from any reader (stdin, archive entry, etc.)
+    pub fn from_reader<R: Read>(mut reader: R) -> std::io::Result<Self>
+    {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(Self::from_vec(data))
     }
 
     pub fn read_from_file(&mut self, infilename: &String)
     {
         // ファイルのオープン
-        let mut fp = File::open(infilename)
+        let fp = File::open(infilename)
             .expect("File not found.");
 
         // ファイルからバイナリを読み込み
-        self.data = Vec::new();
-        fp.read_to_end(&mut self.data)
+        *self = Self::from_reader(fp)
             .expect("File read error.");
-        
-        self.size = self.data.len();
+    }
+
+    // Adds data at the end (the buffer becomes owned)
+    pub fn append(&mut self, data: &[u8])
+    {
+        self.data.to_mut().extend_from_slice(data);
     }
 
     pub fn get_size(&self) -> usize
     {
-        return self.size;
+        return self.data.len();
+    }
+
+    pub fn get_slice(&self) -> &[u8]
+    {
+        &self.data
     }
 
     pub fn read_u8(&self, pos: usize) -> Option<u8>
     {
-        if pos >= self.size
-        {
-            return None;
-        }
-        else
-        {
-            return Some(self.data[pos]);
-        }
+        read_u8_at(&self.data, pos)
     }
 
     pub fn read_u16be(&self, pos: usize) -> Option<u16>
     {
-        if pos + 1 >= self.size
-        {
-            return None; 
-        }
-        else
-        {
-            let val: u16 = (self.data[pos] as u16) << 8
-                         | self.data[pos+1] as u16;
-            return Some(val);
-        }
+        read_u16be_at(&self.data, pos)
     }
 
     pub fn dump_binary(&self)
     {
         // 16進ダンプ
-        for a in 0..self.size
+        for a in 0..self.data.len()
         {
             print!("{:02x} ", &self.data[a]);
             if a % 16 == 15
@@ -94,9 +145,16 @@ impl JpegRawData
 }
 
 #[allow(dead_code)]
+#[allow(clippy::needless_return)]
 impl<'a> JpegReader<'a>
 {
     pub fn new(data: &'a JpegRawData) -> Self
+    {
+        Self::from_slice(data.get_slice())
+    }
+
+    // Reads directly from a byte slice
+    pub fn from_slice(data: &'a [u8]) -> Self
     {
         JpegReader{ data_ref: data, read_pos: 0 }
     }
@@ -132,37 +190,68 @@ impl<'a> JpegReader<'a>
 
     pub fn is_end(&self) -> bool
     {
-        return self.read_pos >= self.data_ref.get_size();
+        return self.read_pos >= self.data_ref.len();
+    }
+
+    // Number of bytes left after the current position
+    pub fn get_remaining_size(&self) -> usize
+    {
+        return self.data_ref.len().saturating_sub(self.read_pos);
     }
 
     pub fn read_u16be(&mut self) -> u16
     {
-        let r = self.data_ref.read_u16be(self.read_pos);
-        assert!(r.is_some());
+        assert!(self.read_pos + 1 < self.data_ref.len());
+        let val: u16 = (self.data_ref[self.read_pos] as u16) << 8
+                     | self.data_ref[self.read_pos + 1] as u16;
         self.read_pos += 2;
-        return r.unwrap();
+        return val;
     }
 
     pub fn read_u8(&mut self) -> u8
     {
-        let r = self.data_ref.read_u8(self.read_pos);
-        assert!(r.is_some());
+        assert!(self.read_pos < self.data_ref.len());
+        let val = self.data_ref[self.read_pos];
         self.read_pos += 1;
-        return r.unwrap();
+        return val;
+    }
+
+    // Checks whether the following bytes match the given signature (does not move)
+    pub fn has_signature(&self, signature: &[u8]) -> bool
+    {
+        match self.data_ref.get(self.read_pos..)
+        {
+            Some(s) => s.starts_with(signature),
+            None => false,
+        }
     }
 }
 
 #[allow(dead_code)]
+#[allow(clippy::needless_return)]
 impl<'a> JpegBitStreamReader<'a>
 {
+    // Number of bits loaded up to by a refill
+    const BIT_BUFFER_REFILL_BITS: usize = 56;
+
     pub fn new(data: &'a JpegRawData) -> Self
+    {
+        Self::from_slice(data.get_slice())
+    }
+
+    // Reads directly from a byte slice
+    pub fn from_slice(data: &'a [u8]) -> Self
     {
         JpegBitStreamReader
         {
             data_ref: data,
             read_pos: 0,
-            read_bitpos: 0,
-            needs_escape: 0,
+            bit_buffer: 0,
+            bit_count: 0,
+            fill_count: 0,
+            data_end: 0,
+            marker: None,
+            is_overrun: false,
         }
     }
 
@@ -172,121 +261,267 @@ impl<'a> JpegBitStreamReader<'a>
         {
             data_ref: self.data_ref,
             read_pos: self.read_pos,
-            read_bitpos: self.read_bitpos,
-            needs_escape: self.needs_escape,
+            bit_buffer: self.bit_buffer,
+            bit_count: self.bit_count,
+            fill_count: self.fill_count,
+            data_end: self.data_end,
+            marker: self.marker,
+            is_overrun: self.is_overrun,
         }
     }
 
+    // Position of the byte which holds the next bit
     pub fn get_pos(&self) -> usize
     {
-        return self.read_pos;
+        self.get_pos_and_bitpos().0
     }
 
-    pub fn set_pos(&mut self, pos: usize, bitpos: usize)
+    // Bit position (from MSB) of the next bit in the byte at get_pos()
+    pub fn get_bitpos(&self) -> usize
     {
-        self.read_pos = pos;
-        self.read_bitpos = bitpos;
+        self.get_pos_and_bitpos().1
     }
 
-    pub fn move_bitpos(&mut self, offset_bits: usize)
+    // The bytes which still have bits in the accumulator are found by walking back
+    // from the loading position, counting a stuffed 0xFF 0x00 as one byte.
+    fn get_pos_and_bitpos(&self) -> (usize, usize)
     {
-        let mut i = self.read_bitpos + offset_bits;
-        // Escape the 0x00 after 0xFF.
-        if self.needs_escape > 0 && i >= self.needs_escape * 8
+        let num_data_bits = self.bit_count.saturating_sub(self.fill_count);
+        let mut pos = self.data_end;
+        for _ in 0..num_data_bits.div_ceil(8)
         {
-            i += 8;
+            pos -= 1;
+            if pos > 0 && self.data_ref[pos] == 0x00 && self.data_ref[pos - 1] == 0xFF
+            {
+                pos -= 1;
+            }
         }
-        let j = i / 8 + self.read_pos;
-        i = i & 7;
-        self.read_pos = j;
-        self.read_bitpos = i as usize;
-        
-        /*
-        println!("Pos:{} Bit:{} {:08b} {:08b} {:08b}",
-                  self.read_pos,
-                  self.read_bitpos,
-                  self.data_ref.read_u8(self.read_pos).unwrap(),
-                  self.data_ref.read_u8(self.read_pos + 1).unwrap(),
-                  self.data_ref.read_u8(self.read_pos + 2).unwrap(),
-                );
-        */
+        (pos, (8 - num_data_bits % 8) % 8)
     }
 
-    pub fn is_end(&self) -> bool
+    // Restarts reading at the given byte and bit position
+    pub fn set_pos(&mut self, pos: usize, bitpos: usize)
     {
-        return self.read_pos >= self.data_ref.get_size();
+        self.read_pos = pos;
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+        self.fill_count = 0;
+        self.data_end = pos;
+        self.marker = None;
+        self.is_overrun = false;
+        self.move_bitpos(bitpos);
     }
 
-    pub fn read_bits16(&mut self) -> u16
+    // Loads bytes into the accumulator until it holds more than 56 bits
+    fn fill_bit_buffer(&mut self)
     {
-        let r0 = self.data_ref.read_u16be(self.read_pos);
-        let r1 = self.data_ref.read_u8(self.read_pos + 2);
-        assert!(r1.is_some());
-        let mut b0 = r0.unwrap();
-        let mut b1 = r1.unwrap();
+        while self.bit_count <= Self::BIT_BUFFER_REFILL_BITS
+        {
+            let b = match self.load_byte()
+            {
+                Some(b) => b,
+                None =>
+                {
+                    self.fill_count += 8;
+                    0
+                }
+            };
+            self.bit_buffer |= (b as u64) << (Self::BIT_BUFFER_REFILL_BITS - self.bit_count);
+            self.bit_count += 8;
+        }
+    }
 
-        // Checking marker escape sequence
-        self.needs_escape = 0;
-        if b0 & 0xFF00 == 0xFF00
+    // Next data byte with the stuffing removed
+    //
+    // Returns None at a marker or at the end of data (the loading position stays there).
+    fn load_byte(&mut self) -> Option<u8>
+    {
+        while self.marker.is_none() && self.fill_count == 0
         {
-            if b0 == 0xFF00 // Normal case where the stream contain 0xFF.
+            let b = *self.data_ref.get(self.read_pos)?;
+            if b != 0xFF
             {
-                b0 = b0 | b1 as u16;
-                self.needs_escape = 1;
+                self.read_pos += 1;
+                self.data_end = self.read_pos;
+                return Some(b);
             }
-            else
+            match self.data_ref.get(self.read_pos + 1)
             {
-                println!("An unexpected marker detected: {:4x}", b0);
-                // ToDo: exceptional sequence
+                // Stuffed byte
+                Some(0x00) =>
+                {
+                    self.read_pos += 2;
+                    self.data_end = self.read_pos;
+                    return Some(b);
+                }
+                // Fill byte before a marker
+                Some(0xFF) => self.read_pos += 1,
+                Some(&m) => self.marker = Some(m),
+                // The second byte is not available yet
+                None => return None,
             }
         }
-        else if ( b0 & 0x00FF == 0x00FF ) && b1 == 0x00
-        {
-            self.needs_escape = 2;
-        }
+        None
+    }
 
-        if self.needs_escape > 0
+    // Consumes bits
+    pub fn move_bitpos(&mut self, offset_bits: usize)
+    {
+        let mut n = offset_bits;
+        while n > 0
         {
-            //println!("**** Detected 0xFF followed by 0x00. Reading one more byte.");
-            let r2 = self.data_ref.read_u8(self.read_pos + 3);
-            assert!(r2.is_some());
-            b1 = r2.unwrap();
+            if self.bit_count < n.min(32)
+            {
+                self.fill_bit_buffer();
+            }
+            let m = n.min(32);
+            let num_data_bits = self.bit_count - self.fill_count.min(self.bit_count);
+            if m > num_data_bits
+            {
+                // Zeros fed after the data were consumed.
+                if self.marker.is_none()
+                {
+                    self.is_overrun = true;
+                }
+                self.fill_count = self.bit_count - m;
+            }
+            self.bit_buffer <<= m;
+            self.bit_count -= m;
+            n -= m;
         }
+    }
+
+    pub fn is_end(&self) -> bool
+    {
+        return self.read_pos >= self.data_ref.len() && self.bit_count <= self.fill_count;
+    }
+
+    // True if the consumed bits went beyond the end of data (zeros were decoded)
+    pub fn is_overrun(&self) -> bool
+    {
+        return self.is_overrun;
+    }
+
+    // Marker where the entropy-coded data ended, once the bits up to it are loaded
+    pub fn get_marker(&self) -> Option<u8>
+    {
+        return self.marker;
+    }
 
-        // Align to the current bit position.
-        b0 = b0 << self.read_bitpos;
-        b1 = if self.read_bitpos == 0
+    // Peeks the next 16 bits (without consuming them)
+    pub fn read_bits16(&mut self) -> u16
+    {
+        if self.bit_count < 16
         {
-            0
+            self.fill_bit_buffer();
         }
-        else
-        {
-            b1 >> (8 - self.read_bitpos)
-        };
-
-        b0 | b1 as u16
+        (self.bit_buffer >> 48) as u16
     }
 
-    pub fn check_marker(&mut self)
+    // Discards the bits up to the next marker, skips the marker and returns it
+    //
+    // Returns None if the data ended without a marker.
+    pub fn read_marker(&mut self) -> Option<u8>
     {
-        let offset = if self.read_bitpos == 0 { 0 } else { 1 };
-        let r0 = self.data_ref.read_u16be(self.read_pos + offset);
-        assert!(r0.is_some());
-        let b0 = r0.unwrap();
-        if b0 & 0xFF00 == 0xFF00
+        while self.marker.is_none()
         {
-            if b0 == 0xFF00
+            self.bit_count = 0;
+            self.fill_count = 0;
+            self.fill_bit_buffer();
+            if self.marker.is_none() && self.fill_count > 0
             {
-                println!("**** Detected 0x00 after 0xFF.");
-            }
-            else
-            {   
-                println!("**** Detected a marker {:04x}", b0);
-                self.read_pos += 2 + offset;
-                self.read_bitpos = 0;
+                // End of data
+                return None;
             }
         }
+        let marker = self.marker?;
+        self.set_pos(self.read_pos + 2, 0);
+        Some(marker)
     }
 }
 
 //========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn stuffed_bytes_in_one_fill()
+    {
+        // Both 0xFF 0x00 are unstuffed by the first fill (7 bytes are loaded at once).
+        let data = [0x12, 0xFF, 0x00, 0x34, 0xFF, 0x00, 0x56, 0x78, 0x9A, 0xBC, 0xDE];
+        let mut reader = JpegBitStreamReader::from_slice(&data);
+        for (bits, pos) in [(0x12FF, 0), (0x34FF, 3), (0x5678, 6), (0x9ABC, 8)]
+        {
+            assert!(reader.read_bits16() == bits);
+            assert!(reader.get_pos() == pos && reader.get_bitpos() == 0);
+            reader.move_bitpos(16);
+        }
+        assert!(!reader.is_overrun());
+    }
+
+    #[test]
+    fn fill_bytes_before_marker()
+    {
+        let data = [0x12, 0x34, 0xFF, 0xFF, 0xFF, 0xD9];
+        let mut reader = JpegBitStreamReader::from_slice(&data);
+        assert!(reader.read_bits16() == 0x1234);
+        reader.move_bitpos(16);
+        // The fill bytes are not data, and zeros are fed from the marker.
+        assert!(reader.read_bits16() == 0x0000);
+        assert!(reader.get_marker() == Some(0xD9));
+        assert!(reader.get_pos() == 2);
+        assert!(reader.read_marker() == Some(0xD9));
+        assert!(reader.get_pos() == data.len());
+    }
+
+    #[test]
+    fn stops_at_restart_marker()
+    {
+        let data = [0xAB, 0xCF, 0xFF, 0xD3, 0x12, 0x34];
+        let mut reader = JpegBitStreamReader::from_slice(&data);
+        reader.move_bitpos(4);
+        assert!(reader.read_bits16() == 0xBCF0);
+        assert!(reader.get_marker() == Some(0xD3));
+
+        // Zeros past the marker are the end of the interval (not an overrun).
+        reader.move_bitpos(12 + 24);
+        assert!(reader.read_bits16() == 0x0000);
+        assert!(!reader.is_overrun());
+
+        // The next interval
+        assert!(reader.read_marker() == Some(0xD3));
+        assert!(reader.get_marker().is_none());
+        assert!(reader.read_bits16() == 0x1234);
+
+        // Zeros past the end of data are an overrun.
+        reader.move_bitpos(17);
+        assert!(reader.is_overrun());
+        assert!(reader.is_end());
+    }
+
+    #[test]
+    fn position_after_unstuffing()
+    {
+        let data = [0x5A, 0xFF, 0x00, 0xFF, 0x00, 0xA5, 0x0F, 0xFF, 0xD0];
+        let mut reader = JpegBitStreamReader::from_slice(&data);
+        // Bit offset -> byte position (in the stuffed data) and bit position
+        for (offset, pos_and_bitpos) in [(0, (0, 0)), (8, (1, 0)), (11, (1, 3)), (16, (3, 0)),
+                                         (24, (5, 0)), (29, (5, 5)), (32, (6, 0)), (39, (6, 7))]
+        {
+            let mut r = reader.copy();
+            r.move_bitpos(offset);
+            assert!(r.get_pos_and_bitpos() == pos_and_bitpos, "{} {:?}", offset, r.get_pos_and_bitpos());
+
+            // Restarting there reads the same bits
+            let bits = r.read_bits16();
+            let (pos, bitpos) = pos_and_bitpos;
+            r.set_pos(pos, bitpos);
+            assert!(r.read_bits16() == bits);
+        }
+        reader.move_bitpos(40);
+        assert!(reader.get_pos() == 7);
+    }
+}
@@ -0,0 +1,78 @@
+//========================================================
+//  jpeg_row_sink.rs
+//
+//========================================================
+
+// Receiver of the decoded image.
+//
+// JpegControl calls write_rows() once per MCU row, so only one MCU row of
+// pixels has to be kept in memory during decoding.
+pub trait JpegRowSink
+{
+    // `rows` contains rows of `row_size` bytes each, starting from the row `y`.
+    fn write_rows(&mut self, y: usize, rows: &[u8], row_size: usize);
+}
+
+// Sink which writes into a whole-image buffer
+pub struct JpegBufferSink<'a>
+{
+    buf: &'a mut [u8],
+    origin: usize,
+    row_pitch: Option<isize>,
+    first_row: usize,
+}
+
+#[allow(dead_code)]
+impl<'a> JpegBufferSink<'a>
+{
+    // Constructor
+    pub fn new(buf: &'a mut [u8]) -> Self
+    {
+        JpegBufferSink
+        {
+            buf,
+            origin: 0,
+            row_pitch: None,
+            first_row: 0,
+        }
+    }
+
+    // Buffer with an origin and a row pitch (negative for bottom-up buffers)
+    pub fn with_layout(buf: &'a mut [u8], origin: usize, row_pitch: isize) -> Self
+    {
+        JpegBufferSink
+        {
+            buf,
+            origin,
+            row_pitch: Some(row_pitch),
+            first_row: 0,
+        }
+    }
+
+    // Part of a buffer which holds the rows from `first_row` (`origin` is the position of that row)
+    pub fn with_first_row(buf: &'a mut [u8], origin: usize, row_pitch: isize, first_row: usize) -> Self
+    {
+        JpegBufferSink
+        {
+            buf,
+            origin,
+            row_pitch: Some(row_pitch),
+            first_row,
+        }
+    }
+}
+
+impl JpegRowSink for JpegBufferSink<'_>
+{
+    fn write_rows(&mut self, y: usize, rows: &[u8], row_size: usize)
+    {
+        let pitch = self.row_pitch.unwrap_or(row_size as isize);
+        for (i, row) in rows.chunks_exact(row_size).enumerate()
+        {
+            let pos = (self.origin as isize + (y + i - self.first_row) as isize * pitch) as usize;
+            self.buf[pos..pos + row_size].copy_from_slice(row);
+        }
+    }
+}
+
+//========================================================
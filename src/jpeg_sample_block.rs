@@ -6,6 +6,7 @@ use crate::jpeg_constants::
 {
     JPEG_SAMPLE_BLOCK_SIZE,
     JPEG_MAX_NUM_OF_COMPONENTS,
+    JPEG_MCU_MAX_NUM_BLOCKS,
     JPEG_REV_ZIGZAG_TABLE,
 };
 use crate::jpeg_frame_info;
@@ -13,11 +14,12 @@ use crate::jpeg_raw_data::JpegBitStreamReader;
 use crate::jpeg_huffman_table::JpegDhtManager;
 use crate::jpeg_quantization_table::JpegDqtManager;
 use crate::jpeg_idct::JpegIdctManager;
-use crate::jpeg_sampler::JpegSampler;
-use crate::jpeg_sampler::JpegSampleMode;
-use crate::jpeg_outbuffer_info::JpegOutBufferInfo;
+use crate::jpeg_idct::JpegIdct;
+use crate::jpeg_idct::JpegIdctIslow;
+use crate::jpeg_idct::JpegScale;
+use crate::jpeg_sampler::JpegComponentPlane;
+use std::sync::Arc;
 
-const JPEG_MCU_MAX_NUM_BLOCKS: usize = 6;
 const JPEG_MCU_NUM_PIXELS_DEFAULT: u8 = 8;
 
 #[derive(Copy)]
@@ -26,17 +28,25 @@ pub struct JpegSampleBlock
 {
     sample: [i16; JPEG_SAMPLE_BLOCK_SIZE],
     index: usize,
+    last_index: usize,
 }
 
 pub struct JpegMinimumCodedUnit
 {
     blocks: [JpegSampleBlock; JPEG_MCU_MAX_NUM_BLOCKS],
     component_ids: [u8; JPEG_MCU_MAX_NUM_BLOCKS],
-    dht_ids: [u8; JPEG_MAX_NUM_OF_COMPONENTS],
+    block_pos: [(u8, u8); JPEG_MCU_MAX_NUM_BLOCKS],
+    qt_ids: [u8; JPEG_MAX_NUM_OF_COMPONENTS],
+    dht_ids: [(u8, u8); JPEG_MAX_NUM_OF_COMPONENTS],     // DC/AC Huffman table of each component
     sampling_factor: [jpeg_frame_info::JpegSamplingFactor; JPEG_MAX_NUM_OF_COMPONENTS],
+    num_blocks_hv: [(u8, u8); JPEG_MAX_NUM_OF_COMPONENTS],
+    block_sizes: [u8; JPEG_MAX_NUM_OF_COMPONENTS],
     last_dc: [i16; JPEG_MAX_NUM_OF_COMPONENTS],
-    sampler: JpegSampler,
+    idct: Arc<dyn JpegIdct>,
+    scale: JpegScale,
+    luma_only: bool,
     index: usize,
+    num_components: u8,
     width: u8,
     height: u8,
     num_blocks_in_mcu: u8,
@@ -52,12 +62,14 @@ impl JpegSampleBlock
         {
             sample: [0; JPEG_SAMPLE_BLOCK_SIZE],
             index: 0,
+            last_index: 0,
         }
     }
 
     fn reset_index(&mut self)
     {
         self.index = 0;
+        self.last_index = 0;
     }
 
     // Zigzag order index
@@ -77,33 +89,38 @@ impl JpegSampleBlock
             self.sample[self.get_zigzag_index()] = 0;
             count_zero -= 1;   
         }
+        // Zigzag index of the last non-zero coefficient (for the sparse IDCT)
+        if coef != 0
+        {
+            self.last_index = self.index;
+        }
         self.sample[self.get_zigzag_index()] = coef;
 
         self.index == JPEG_SAMPLE_BLOCK_SIZE
     }
 
-    // Scale coefficients for dequantization
-    fn scale_coefficients(&mut self, scale: &[u16])
+    // IDCT with dequantization through the selected implementation
+    fn transform(&mut self, idct: &dyn JpegIdct, scale: &[u16])
     {
-        assert!(scale.len() == JPEG_SAMPLE_BLOCK_SIZE);
-        for i in 0..JPEG_SAMPLE_BLOCK_SIZE
-        {
-            self.sample[i] = self.sample[i] * scale[i] as i16;
-        }
-        self.index = JPEG_SAMPLE_BLOCK_SIZE;
+        idct.transform(&mut self.sample, scale, self.last_index);
     }
 
-    fn transform(&mut self)
+    // Reduced-size IDCT with dequantization into `size` x `size` samples (4, 2 or 1)
+    fn transform_reduced(&mut self, scale: &[u16], size: usize)
     {
-        let mut tm = JpegIdctManager::new();
-        tm.idct(&mut self.sample);
+        JpegIdctManager::idct_reduced(&mut self.sample, scale, size, self.last_index);
     }
 
-    pub fn iter(&self) -> std::slice::Iter<i16>
+    pub fn iter(&self) -> std::slice::Iter<'_, i16>
     {
         self.sample.iter()
     }
 
+    pub fn get_samples(&self) -> &[i16]
+    {
+        &self.sample
+    }
+
     fn dump(&self)
     {
         for i in 0..JPEG_SAMPLE_BLOCK_SIZE
@@ -128,11 +145,18 @@ impl JpegMinimumCodedUnit
         {
             blocks: [JpegSampleBlock::new(); JPEG_MCU_MAX_NUM_BLOCKS],
             component_ids: [0; JPEG_MCU_MAX_NUM_BLOCKS],
-            dht_ids: [0; JPEG_MAX_NUM_OF_COMPONENTS],
+            block_pos: [(0, 0); JPEG_MCU_MAX_NUM_BLOCKS],
+            qt_ids: [0; JPEG_MAX_NUM_OF_COMPONENTS],
+            dht_ids: [(0, 0); JPEG_MAX_NUM_OF_COMPONENTS],
             sampling_factor: [jpeg_frame_info::JpegSamplingFactor::new(); JPEG_MAX_NUM_OF_COMPONENTS],
+            num_blocks_hv: [(1, 1); JPEG_MAX_NUM_OF_COMPONENTS],
+            block_sizes: [JPEG_MCU_NUM_PIXELS_DEFAULT; JPEG_MAX_NUM_OF_COMPONENTS],
             last_dc: [0; JPEG_MAX_NUM_OF_COMPONENTS],
-            sampler: JpegSampler::new(),
+            idct: Arc::new(JpegIdctIslow),
+            scale: JpegScale::Full,
+            luma_only: false,
             index: 0,
+            num_components: 1,
             width: JPEG_MCU_NUM_PIXELS_DEFAULT,
             height: JPEG_MCU_NUM_PIXELS_DEFAULT,
             num_blocks_in_mcu: JPEG_MCU_MAX_NUM_BLOCKS as u8,
@@ -155,7 +179,14 @@ impl JpegMinimumCodedUnit
 
     fn get_current_table_id(&self) -> usize
     {
-        self.dht_ids[self.get_current_component_id()] as usize
+        self.qt_ids[self.get_current_component_id()] as usize
+    }
+
+    // DC/AC Huffman tables of the current block
+    fn get_current_dht_ids(&self) -> (usize, usize)
+    {
+        let (dc, ac) = self.dht_ids[self.get_current_component_id()];
+        (dc as usize, ac as usize)
     }
 
     // Add coefficients from huffman-decoded stream
@@ -186,6 +217,17 @@ impl JpegMinimumCodedUnit
         self.add_coefficients(coef1, 0)
     }
 
+    // DC predictors (last DC value of each component)
+    pub fn get_dc_predictors(&self) -> [i16; JPEG_MAX_NUM_OF_COMPONENTS]
+    {
+        self.last_dc
+    }
+
+    pub fn set_dc_predictors(&mut self, last_dc: [i16; JPEG_MAX_NUM_OF_COMPONENTS])
+    {
+        self.last_dc = last_dc;
+    }
+
     // Fill coeffieients through an Huffman-encoded bitstream
     pub fn fill_coefficients(&mut self, dht: &JpegDhtManager, bsreader: &mut JpegBitStreamReader)
     {
@@ -193,44 +235,126 @@ impl JpegMinimumCodedUnit
 
         while self.index < self.num_blocks_in_mcu as usize
         {
-            let table_id = self.get_current_table_id();
-            let dc_decoded = dht.decode_dc(table_id, bsreader);
+            let (dc_table_id, ac_table_id) = self.get_current_dht_ids();
+            let dc_decoded = dht.decode_dc(dc_table_id, bsreader);
             self.add_coefficients_dc(dc_decoded);
             let mut is_end = false;
             while !is_end
             {
-                let ac_decoded = dht.decode_ac(table_id, bsreader);
+                let ac_decoded = dht.decode_ac(ac_table_id, bsreader);
                 is_end = self.add_coefficients(ac_decoded.0, ac_decoded.1);
             }
         }
     }
 
-    // Scale coefficients for dequantization
-    pub fn dequantize(&mut self, dqt: &JpegDqtManager)
+    // Dequantization and (inverse) discrete-cosine transform
+    //
+    // The reduced sizes of the scaled decoding are always done with the integer IDCT (as libjpeg).
+    pub fn transform(&mut self, dqt: &JpegDqtManager)
     {
-        self.reset();
-
+        self.index = 0;
         while self.index < self.num_blocks_in_mcu as usize
         {
-            let table_id = self.get_current_table_id();
-            self.blocks[self.index].scale_coefficients(dqt.get_qt_slice(table_id));
+            if self.luma_only && self.get_current_component_id() != 0
+            {
+                self.index += 1;
+                continue;
+            }
+            let qt = dqt.get_qt_slice(self.get_current_table_id());
+            let size = self.block_sizes[self.get_current_component_id()] as usize;
+            if size == JPEG_MCU_NUM_PIXELS_DEFAULT as usize
+            {
+                self.blocks[self.index].transform(self.idct.as_ref(), qt);
+            }
+            else
+            {
+                self.blocks[self.index].transform_reduced(qt, size);
+            }
             self.index += 1;
         }
     }
 
-    // (Inverse) discrete-cosine transform
-    pub fn transform(&mut self)
+    // IDCT implementation
+    pub fn set_idct(&mut self, idct: Arc<dyn JpegIdct>)
+    {
+        self.idct = idct;
+    }
+
+    // Only the blocks of the first component (Y) are transformed and stored
+    // (the others are entropy-decoded to advance the bit stream)
+    pub fn set_luma_only(&mut self, luma_only: bool)
+    {
+        self.luma_only = luma_only;
+    }
+
+    // Output scale (the MCU size and the block sizes are reduced)
+    pub fn set_scale(&mut self, scale: JpegScale)
+    {
+        self.scale = scale;
+        self.update_block_sizes();
+    }
+
+    // Stores the samples into the component planes of an MCU row at the horizontal MCU position
+    pub fn store_blocks(&self, planes: &mut [JpegComponentPlane], mcu_x: usize)
     {
         for i in 0..self.num_blocks_in_mcu as usize
         {
-            self.blocks[i].transform();
+            let cid = self.component_ids[i] as usize;
+            if self.luma_only && cid != 0
+            {
+                continue;
+            }
+            let (bx, by) = self.block_pos[i];
+            let (nh, _) = self.get_num_blocks_hv(cid);
+            let size = self.get_block_size(cid);
+            planes[cid].put_block(&self.blocks[i], (mcu_x * nh + bx as usize) * size, by as usize * size, size);
         }
     }
 
-    // Up-sampling
-    pub fn upsampling(&self, out_buf: &mut [u8], buf_info: &JpegOutBufferInfo, pos: usize)
+    // Number of blocks in the MCU
+    pub fn get_num_blocks(&self) -> usize
+    {
+        self.num_blocks_in_mcu as usize
+    }
+
+    // Appends the (entropy-decoded) blocks to `dst`
+    pub fn save_blocks(&self, dst: &mut Vec<JpegSampleBlock>)
+    {
+        dst.extend_from_slice(&self.blocks[0..self.num_blocks_in_mcu as usize]);
+    }
+
+    // Replaces the blocks with the ones saved by save_blocks()
+    pub fn load_blocks(&mut self, src: &[JpegSampleBlock])
+    {
+        let num_blocks = self.num_blocks_in_mcu as usize;
+        self.blocks[0..num_blocks].copy_from_slice(&src[0..num_blocks]);
+    }
+
+    // Number of components in the scan
+    pub fn get_num_components(&self) -> usize
+    {
+        self.num_components as usize
+    }
+
+    // Number of blocks of a component in horizontal/vertical direction
+    pub fn get_num_blocks_hv(&self, cid: usize) -> (usize, usize)
+    {
+        let (nh, nv) = self.num_blocks_hv[cid];
+        (nh as usize, nv as usize)
+    }
+
+    // Width/height of the IDCT output of a component block in pixels
+    pub fn get_block_size(&self, cid: usize) -> usize
     {
-        self.sampler.upsampling(&self.blocks, out_buf, buf_info, pos);
+        self.block_sizes[cid] as usize
+    }
+
+    // Upsampling ratio of a component to the full resolution (horizontal, vertical)
+    pub fn get_upsampling_ratio(&self, cid: usize) -> (usize, usize)
+    {
+        let (nh, nv) = self.get_num_blocks_hv(cid);
+        let size = self.get_block_size(cid);
+        (self.get_width() / (nh * size), self.get_height() / (nv * size))
     }
 
     // Width/height of MCU in pixels
@@ -251,34 +375,66 @@ impl JpegMinimumCodedUnit
         for j in 0..fh.get_num_components()
         {
             self.sampling_factor[j] = fh.get_sampling_factor(j);
-            self.dht_ids[j] = fh.get_table_id(j) as u8;
-            let num_blocks = self.sampling_factor[j].get_num_blocks();
-            for _k in 0..num_blocks
+            self.qt_ids[j] = fh.get_table_id(j) as u8;
+            // A non-interleaved (single component) scan has one block per MCU.
+            let (nh, nv) = if fh.get_num_components() == 1
+            {
+                (1, 1)
+            }
+            else
+            {
+                (self.sampling_factor[j].get_num_h(), self.sampling_factor[j].get_num_v())
+            };
+            self.num_blocks_hv[j] = (nh as u8, nv as u8);
+            for k in 0..nh * nv
             {
                 self.component_ids[i] = j as u8;
+                self.block_pos[i] = ((k % nh) as u8, (k / nh) as u8);
                 i += 1;
             }
         }
         self.num_blocks_in_mcu = i as u8;
+        self.num_components = fh.get_num_components() as u8;
+        self.update_block_sizes();
+    }
+
+    // Sets the Huffman tables of the components from the scan header
+    pub fn set_scan(&mut self, fh: &jpeg_frame_info::JpegFrameHeaderInfo, sh: &jpeg_frame_info::JpegScanHeaderInfo)
+    {
+        for j in 0..fh.get_num_components()
+        {
+            if let Some((dc, ac)) = sh.get_table_ids(fh.get_component_id(j))
+            {
+                self.dht_ids[j] = (dc as u8, ac as u8);
+            }
+        }
+    }
 
-        // Picks an adequate sampling mode from the sampling factor of component 0
-        if fh.get_num_components() == 3
+    // Sets the block sizes and the MCU size in pixels for the scale
+    //
+    // As libjpeg does, the subsampled components are scaled up by the IDCT rather than
+    // upsampled as far as possible (e.g. the chroma blocks of 4:2:0 are 8x8 at 1/2 scale).
+    fn update_block_sizes(&mut self)
+    {
+        let min_size = self.scale.get_block_size();
+        let num_components = self.num_components as usize;
+        let max_h = self.num_blocks_hv[..num_components].iter().map(|hv| hv.0 as usize).max().unwrap_or(1);
+        let max_v = self.num_blocks_hv[..num_components].iter().map(|hv| hv.1 as usize).max().unwrap_or(1);
+        for cid in 0..num_components
         {
-            let factor = self.sampling_factor[0].get_raw();
-            let mode =
-                match factor
-                {
-                    0x11 => JpegSampleMode::JpegSampleMode444,
-                    0x21 => JpegSampleMode::JpegSampleMode422,
-                    0x12 => JpegSampleMode::JpegSampleMode440,
-                    0x22 => JpegSampleMode::JpegSampleMode420,
-                    _ => JpegSampleMode::JpegSampleModeNone, // Default
-                };
-            self.sampler.set_sampling_mode(mode);
-
-            self.width = self.sampling_factor[0].get_num_mcu_pixels_h() as u8;
-            self.height = self.sampling_factor[0].get_num_mcu_pixels_v() as u8;
+            let (nh, nv) = self.get_num_blocks_hv(cid);
+            let mut size = min_size;
+            while size < JPEG_MCU_NUM_PIXELS_DEFAULT as usize
+                && (max_h * min_size).is_multiple_of(nh * size * 2)
+                && (max_v * min_size).is_multiple_of(nv * size * 2)
+            {
+                size *= 2;
+            }
+            self.block_sizes[cid] = size as u8;
         }
+        // MCU size is given by the largest sampling factor.
+        self.width = (max_h * min_size) as u8;
+        self.height = (max_v * min_size) as u8;
     }
 
     pub fn dump(&self)
@@ -286,7 +442,7 @@ impl JpegMinimumCodedUnit
         for i in 0..self.num_blocks_in_mcu as usize
         {
             let cid = self.component_ids[i] as usize;
-            println!("Block {} (ComponentID={}, TableID={}):", i, cid, self.dht_ids[cid]);
+            println!("Block {} (ComponentID={}, TableID={}, DHT={:?}):", i, cid, self.qt_ids[cid], self.dht_ids[cid]);
             self.blocks[i].dump();
         }
     }
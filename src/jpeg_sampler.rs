@@ -2,43 +2,162 @@
 //  jpeg_sampler.rs
 //
 //========================================================
+use crate::jpeg_constants::JPEG_MAX_NUM_OF_COMPONENTS;
 use crate::jpeg_sample_block::JpegSampleBlock;
-use crate::jpeg_color_converter::ColorConvertFunc;
-use crate::jpeg_color_converter;
-use crate::jpeg_outbuffer_info::JpegOutBufferInfo;
+use std::sync::Arc;
+use crate::jpeg_color_converter::JpegColorConvert;
+use crate::jpeg_color_converter::JpegColorConversion;
+use crate::jpeg_color_converter::JpegYCbCrConverter;
+use crate::jpeg_simd;
 
+// Chroma upsampling method
 #[allow(dead_code)]
-pub enum JpegSampleMode
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum JpegUpsampling
 {
-    JpegSampleMode444,
-    JpegSampleMode422,
-    JpegSampleMode440,
-    JpegSampleMode420,
-    JpegSampleModeNone,
+    Box,        // Replication of each sample (fast)
+    Fancy,      // Triangle filter with centered chroma siting (do_fancy_upsampling of libjpeg)
 }
 
-type UpsamplerFunc = fn(
-    &[JpegSampleBlock],
-    ColorConvertFunc,
-    &mut[u8],
-    &JpegOutBufferInfo,
-    usize
-);
+// Samples of one component in an MCU row (IDCT output before upsampling)
+//
+// The buffer has a context row above and below the MCU row, which are the
+// adjacent rows of the neighbouring MCU rows (or replicated at the image edges).
+pub struct JpegComponentPlane
+{
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+    ratio_h: usize,
+    ratio_v: usize,
+    valid_width: usize,
+    valid_height: usize,
+    bottom: usize,
+}
 
 #[allow(dead_code)]
 pub struct JpegSampler
 {
-    upsampling_func: UpsamplerFunc,
-    color_convert_func: ColorConvertFunc,
+    converter: Arc<dyn JpegColorConvert>,
+    upsampling: JpegUpsampling,
+    rows: [Vec<u8>; JPEG_MAX_NUM_OF_COMPONENTS],
+    first_column: usize,
+    gray_levels: Option<[u8; 256]>,
 }
 
-macro_rules! put_pixel
+#[allow(dead_code)]
+impl JpegComponentPlane
 {
-    ($out_buf:expr, $i:expr, $ccv:expr, $stride:expr) =>
+    // Constructor
+    //
+    // `ratio_h`/`ratio_v` are the upsampling ratios to the full resolution
+    // (e.g. 2/2 for the chroma components of 4:2:0), and `valid_width`/`valid_height`
+    // are the size of the component in the whole image without the MCU padding.
+    pub fn new(
+        width: usize,
+        height: usize,
+        ratio_h: usize,
+        ratio_v: usize,
+        valid_width: usize,
+        valid_height: usize
+    ) -> Self
     {
-        ($out_buf[$i], $out_buf[$i+1], $out_buf[$i+2]) = $ccv;
-        $i += $stride;
-    };
+        JpegComponentPlane
+        {
+            data: vec![0; width * (height + 2)],
+            width,
+            height,
+            ratio_h,
+            ratio_v,
+            valid_width,
+            valid_height,
+            bottom: height,
+        }
+    }
+
+    // Width/height in samples (rounded up to the MCU size)
+    pub fn get_width(&self) -> usize
+    {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize
+    {
+        self.height
+    }
+
+    // Upsampling ratio (horizontal, vertical)
+    pub fn get_ratio(&self) -> (usize, usize)
+    {
+        (self.ratio_h, self.ratio_v)
+    }
+
+    pub fn get_row(&self, y: usize) -> &[u8]
+    {
+        self.get_context_row(y as isize)
+    }
+
+    // Row including the context rows (-1: above, height: below)
+    //
+    // The position is clamped to the last row inside the image at the bottom edge.
+    pub fn get_context_row(&self, y: isize) -> &[u8]
+    {
+        let pos = (y.clamp(-1, self.bottom as isize) + 1) as usize * self.width;
+        &self.data[pos..pos + self.width]
+    }
+
+    fn copy_row(&mut self, dst_y: isize, src: &[u8])
+    {
+        let pos = (dst_y + 1) as usize * self.width;
+        self.data[pos..pos + self.width].copy_from_slice(src);
+    }
+
+    // Sets the context row above from the last row of the MCU row above
+    // (or replicates the first row for the top MCU row)
+    pub fn set_context_above(&mut self, above: Option<&JpegComponentPlane>)
+    {
+        match above
+        {
+            Some(plane) => self.copy_row(-1, plane.get_row(plane.height - 1)),
+            None =>
+            {
+                let pos = self.width;
+                self.data.copy_within(pos..pos + self.width, 0);
+            }
+        }
+    }
+
+    // Sets the context row below from the first row of the MCU row below
+    pub fn set_context_below(&mut self, below: &JpegComponentPlane)
+    {
+        self.copy_row(self.height as isize, below.get_row(0));
+        self.bottom = self.height;
+    }
+
+    // Marks this MCU row as the bottom one (the rows below the image are not referred)
+    pub fn set_bottom_edge(&mut self, mcu_y: usize)
+    {
+        let num_rows = self.valid_height.saturating_sub(mcu_y * self.height).clamp(1, self.height);
+        self.bottom = num_rows - 1;
+    }
+
+    // Stores a block of `size` x `size` samples (8x8, or smaller for the scaled decoding) at (x, y)
+    pub fn put_block(&mut self, block: &JpegSampleBlock, x: usize, y: usize, size: usize)
+    {
+        let src = block.get_samples();
+        for i in 0..size
+        {
+            let pos = (y + i + 1) * self.width + x;
+            let dst = &mut self.data[pos..pos + size];
+            for (d, s) in dst.iter_mut().zip(&src[i * size..])
+            {
+                *d = *s as u8;
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -49,215 +168,226 @@ impl JpegSampler
     {
         JpegSampler
         {
-            upsampling_func: Self::upsampling1,
-            color_convert_func: jpeg_color_converter::ycbcr_to_rgb,
+            converter: Arc::new(JpegYCbCrConverter::new(&JpegColorConversion::new())),
+            upsampling: JpegUpsampling::Fancy,
+            rows: Default::default(),
+            first_column: 0,
+            gray_levels: None,
         }
     }
 
-    // Sets the sampler function
-    pub fn set_sampling_mode(&mut self, mode: JpegSampleMode)
+    // Sets the width of a row in pixels (rounded up to the MCU size)
+    pub fn set_row_width(&mut self, width: usize)
     {
-        match mode
+        for row in self.rows.iter_mut()
         {
-            JpegSampleMode::JpegSampleMode444 => self.upsampling_func = Self::upsampling444,
-            JpegSampleMode::JpegSampleMode422 => self.upsampling_func = Self::upsampling422,
-            JpegSampleMode::JpegSampleMode440 => self.upsampling_func = Self::upsampling440,
-            JpegSampleMode::JpegSampleMode420 => self.upsampling_func = Self::upsampling420,
-            _ => self.upsampling_func = Self::upsampling1,
+            *row = vec![0; width];
         }
     }
 
-    // Calling the current upsampler
-    pub fn upsampling(
-        &self,
-        blocks: &[JpegSampleBlock],
-        out_buf: &mut[u8],
-        buf_info: &JpegOutBufferInfo,
-        pos: usize
-    )
+    // Sets the column of the image where the output rows start
+    // (a multiple of the MCU width; the columns on the left are not referred)
+    pub fn set_first_column(&mut self, column: usize)
+    {
+        self.first_column = column;
+    }
+
+    pub fn get_first_column(&self) -> usize
     {
-        (self.upsampling_func)(blocks, self.color_convert_func, out_buf, buf_info, pos);
+        self.first_column
     }
 
-    // For mono component (no upsampling)
-    fn upsampling1(
-        blocks: &[JpegSampleBlock],
-        convert_func: ColorConvertFunc,
-        out_buf: &mut[u8],
-        buf_info: &JpegOutBufferInfo,
-        pos: usize
-    )
+    // Sets the color converter
+    pub fn set_color_converter(&mut self, converter: Arc<dyn JpegColorConvert>)
     {
-        let c_stride = buf_info.get_bpp();
-        let lf_stride = (buf_info.get_width() - 8) * c_stride;
-        let mut i = pos;
-        let mut t = 0;
-        for y in blocks[0].iter()
+        self.converter = converter;
+    }
+
+    // Luma-only output with convert_row_luma() (after the color converter is set)
+    //
+    // The gray level of each Y value is taken from the color converter, so the output
+    // is the same as the first channel of convert_row() for a grayscale image.
+    pub fn set_luma_only(&mut self, luma_only: bool)
+    {
+        self.gray_levels = None;
+        if luma_only
         {
-            put_pixel!(out_buf, i, convert_func(*y, 0x80, 0x80), c_stride);
-            t = (t + 1) & 7;
-            if t == 0
+            let y: Vec<u8> = (0..=255).collect();
+            let mut rgb = [0; 256 * 3];
+            self.converter.convert_row(&[&y], &mut rgb);
+            let mut levels = [0; 256];
+            for (level, pixel) in levels.iter_mut().zip(rgb.chunks_exact(3))
             {
-                i += lf_stride;
+                *level = pixel[0];
             }
+            self.gray_levels = Some(levels);
         }
     }
 
-    // For 4:4:4 (no upsampling)
-    fn upsampling444(
-        blocks: &[JpegSampleBlock],
-        convert_func: ColorConvertFunc,
-        out_buf: &mut[u8],
-        buf_info: &JpegOutBufferInfo,
-        pos: usize
-    )
+    pub fn is_luma_only(&self) -> bool
     {
-        let c_stride = buf_info.get_bpp();
-        let lf_stride = (buf_info.get_width() - 8) * c_stride;
-        let mut i = pos;
-        let mut t = 0;
-        let iter_y = blocks[0].iter();
-        let iter_cb = blocks[1].iter();
-        let iter_cr = blocks[2].iter();
-        for (y, (cb, cr)) in iter_y.zip(iter_cb.zip(iter_cr))
-        {
-            put_pixel!(out_buf, i, convert_func(*y, *cb, *cr), c_stride);
-            t = (t + 1) & 7;
-            if t == 0
+        self.gray_levels.is_some()
+    }
+
+    // Sets the upsampling method
+    pub fn set_upsampling(&mut self, upsampling: JpegUpsampling)
+    {
+        self.upsampling = upsampling;
+    }
+
+    // Whether the upsampling of the planes refers to the adjacent MCU rows
+    pub fn needs_context_rows(&self, planes: &[JpegComponentPlane]) -> bool
+    {
+        planes.iter().any(|plane| plane.ratio_v == 2 && self.is_fancy(plane))
+    }
+
+    // Whether the plane is upsampled with the fancy upsampling
+    //
+    // As libjpeg, the horizontal 2x is replicated if the component is only 1 or 2 samples wide.
+    fn is_fancy(&self, plane: &JpegComponentPlane) -> bool
+    {
+        self.upsampling == JpegUpsampling::Fancy
+            && match (plane.ratio_h, plane.ratio_v)
             {
-                i += lf_stride;
+                (2, 1) | (2, 2) => plane.valid_width > 2,
+                (1, 2) => true,
+                _ => false,
             }
+    }
+
+    // Up-sampling of a component row to the full resolution
+    //
+    // `out` starts at the first column, and may be shorter than the full row to crop the right edge.
+    // The fancy upsampling is done for 2x1, 1x2 and 2x2 ratios; the others (and the
+    // components 2 samples wide or less) are always replicated as libjpeg does.
+    //
+    // The fancy upsampling treats the first column as the left edge of the image,
+    // so the first pixel is not exact if the first column is not 0.
+    pub fn upsample_row(&self, plane: &JpegComponentPlane, y: usize, out: &mut [u8])
+    {
+        let sx = self.first_column / plane.ratio_h;
+        match (self.is_fancy(plane), plane.ratio_h, plane.ratio_v)
+        {
+            (_, 1, 1) => out.copy_from_slice(&plane.get_row(y)[sx..sx + out.len()]),
+            (true, 2, 1) => Self::upsample_row_h2v1_fancy(plane, y, sx, out),
+            (true, 1, 2) => Self::upsample_row_h1v2_fancy(plane, y, sx, out),
+            (true, 2, 2) => Self::upsample_row_h2v2_fancy(plane, y, sx, out),
+            _ => Self::upsample_row_box(plane, y, sx, out),
         }
     }
 
-    // Up-sampling for 4:2:2
-    fn upsampling422(
-        blocks: &[JpegSampleBlock],
-        convert_func: ColorConvertFunc,
-        out_buf: &mut[u8],
-        buf_info: &JpegOutBufferInfo,
-        pos: usize
-    )
+    // Each sample is simply replicated (box filter)
+    fn upsample_row_box(plane: &JpegComponentPlane, y: usize, sx: usize, out: &mut [u8])
     {
-        let c_stride = buf_info.get_bpp();
-        let lf_stride = (buf_info.get_width() - 16) * c_stride;
-        let mut i = pos;
-        let mut t = 0;
-        let mut iter_y0 = blocks[0].iter();
-        let mut iter_y1 = blocks[1].iter();
-        let iter_cb = blocks[2].iter();
-        let iter_cr = blocks[3].iter();
-        for (cb, cr) in iter_cb.zip(iter_cr)
+        let src = &plane.get_row(y / plane.ratio_v)[sx..];
+        for (d, s) in out.chunks_mut(plane.ratio_h).zip(src)
         {
-            if t < 4
-            {
-                let y0 = iter_y0.next().unwrap();
-                put_pixel!(out_buf, i, convert_func(*y0, *cb, *cr), c_stride);
-                let y0 = iter_y0.next().unwrap();
-                put_pixel!(out_buf, i, convert_func(*y0, *cb, *cr), c_stride);
-            }
-            else
-            {
-                let y1 = iter_y1.next().unwrap();
-                put_pixel!(out_buf, i, convert_func(*y1, *cb, *cr), c_stride);
-                let y1 = iter_y1.next().unwrap();
-                put_pixel!(out_buf, i, convert_func(*y1, *cb, *cr), c_stride);
-            }
-            t = (t + 1) & 7;
-            if t == 0
+            d.fill(*s);
+        }
+    }
+
+    // Horizontal 2x: 3/4 of the nearer sample and 1/4 of the further one
+    //
+    // The samples beyond the right edge of the image are replaced by the last one.
+    // The inner samples are processed with SIMD if available, and the edges here.
+    fn upsample_row_h2v1_fancy(plane: &JpegComponentPlane, y: usize, sx: usize, out: &mut [u8])
+    {
+        let src = &plane.get_row(y)[sx..plane.valid_width];
+        let last = src.len() - 1;
+        let next = jpeg_simd::upsample_h2v1_fancy(src, out);
+        for x in Self::get_scalar_range(next, src.len(), out.len())
+        {
+            let end = (x * 2 + 2).min(out.len());
+            let d = &mut out[x * 2..end];
+            let s = src[x] as u16 * 3;
+            d[0] = ((s + src[x.saturating_sub(1)] as u16 + 1) >> 2) as u8;
+            if d.len() > 1
             {
-                i += lf_stride;
+                d[1] = ((s + src[(x + 1).min(last)] as u16 + 2) >> 2) as u8;
             }
         }
     }
 
-    // Up-sampling for 440
-    fn upsampling440(
-        blocks: &[JpegSampleBlock],
-        convert_func: ColorConvertFunc,
-        out_buf: &mut[u8],
-        buf_info: &JpegOutBufferInfo,
-        pos: usize
-    )
+    // Vertical 2x: 3/4 of the nearer row and 1/4 of the further one
+    fn upsample_row_h1v2_fancy(plane: &JpegComponentPlane, y: usize, sx: usize, out: &mut [u8])
+    {
+        let (near, far, bias) = Self::get_vertical_pair(plane, y);
+        for (d, (n, f)) in out.iter_mut().zip(near[sx..].iter().zip(&far[sx..]))
+        {
+            *d = ((*n as u16 * 3 + *f as u16 + bias) >> 2) as u8;
+        }
+    }
+
+    // Vertical and horizontal 2x (triangle filter applied to the column sums)
+    fn upsample_row_h2v2_fancy(plane: &JpegComponentPlane, y: usize, sx: usize, out: &mut [u8])
     {
-        let c_stride = buf_info.get_bpp();
-        let lf_stride = (buf_info.get_width() - 8) * c_stride;
-        let mut i = pos;
-        let mut t = 0;
-        let mut iter_cb0 = blocks[2].iter();
-        let mut iter_cb1 = iter_cb0.clone();
-        let mut iter_cr0 = blocks[3].iter();
-        let mut iter_cr1 = iter_cr0.clone();
-        for x in 0..2
+        let (near, far, _) = Self::get_vertical_pair(plane, y);
+        let (near, far) = (&near[sx..plane.valid_width], &far[sx..plane.valid_width]);
+        let colsum = |x: usize| near[x] as u16 * 3 + far[x] as u16;
+        let last = near.len() - 1;
+        let next = jpeg_simd::upsample_h2v2_fancy(near, far, out);
+        for x in Self::get_scalar_range(next, near.len(), out.len())
         {
-            let iter_y = blocks[x].iter();
-            for y in iter_y
+            let end = (x * 2 + 2).min(out.len());
+            let d = &mut out[x * 2..end];
+            let s = colsum(x) * 3;
+            d[0] = ((s + colsum(x.saturating_sub(1)) + 8) >> 4) as u8;
+            if d.len() > 1
             {
-                if t < 8
-                {
-                    let cb = iter_cb0.next().unwrap();
-                    let cr = iter_cr0.next().unwrap();
-                    put_pixel!(out_buf, i, convert_func(*y, *cb, *cr), c_stride);
-                }
-                else
-                {
-                    let cb = iter_cb1.next().unwrap();
-                    let cr = iter_cr1.next().unwrap();
-                    put_pixel!(out_buf, i, convert_func(*y, *cb, *cr), c_stride);
-                }
-                t = (t + 1) & 15;
-                if t & 7 == 0
-                {
-                    i += lf_stride;
-                }   
+                d[1] = ((s + colsum((x + 1).min(last)) + 7) >> 4) as u8;
             }
         }
     }
 
-    // Up-sampling for 420
-    fn upsampling420(
-        blocks: &[JpegSampleBlock],
-        convert_func: ColorConvertFunc,
-        out_buf: &mut[u8],
-        buf_info: &JpegOutBufferInfo,
-        pos: usize
-    )
+    // Samples left to the scalar code of the horizontal 2x upsampling:
+    // the left edge and the rest from `next` (the ones before have been done with SIMD)
+    fn get_scalar_range(next: usize, width: usize, out_len: usize) -> impl Iterator<Item = usize>
+    {
+        std::iter::once(0).chain(next..width.min(out_len.div_ceil(2)))
+    }
+
+    // The nearer and further rows for the vertical 2x upsampling, and the rounding bias
+    fn get_vertical_pair(plane: &JpegComponentPlane, y: usize) -> (&[u8], &[u8], u16)
+    {
+        let cy = (y / 2) as isize;
+        if y & 1 == 0
+        {
+            (plane.get_context_row(cy), plane.get_context_row(cy - 1), 1)
+        }
+        else
+        {
+            (plane.get_context_row(cy), plane.get_context_row(cy + 1), 2)
+        }
+    }
+
+    // Up-sampling and color conversion of a row into RGB triplets
+    pub fn convert_row(&mut self, planes: &[JpegComponentPlane], y: usize, out: &mut [u8])
+    {
+        let width = out.len() / 3;
+        let mut rows = std::mem::take(&mut self.rows);
+        for (plane, row) in planes.iter().zip(rows.iter_mut())
+        {
+            self.upsample_row(plane, y, &mut row[..width]);
+        }
+
+        let mut components: [&[u8]; JPEG_MAX_NUM_OF_COMPONENTS] = [&[]; JPEG_MAX_NUM_OF_COMPONENTS];
+        for (c, row) in components.iter_mut().zip(&rows)
+        {
+            *c = &row[..width];
+        }
+        self.converter.convert_row(&components[..planes.len()], out);
+        self.rows = rows;
+    }
+
+    // Gray levels of a row from the Y plane only (1 byte per pixel)
+    pub fn convert_row_luma(&self, planes: &[JpegComponentPlane], y: usize, out: &mut [u8])
     {
-        let c_stride = buf_info.get_bpp();
-        let lf_stride = (buf_info.get_width() - 16) * c_stride;
-        let mut i = pos;
-        let mut iter_cb0 = blocks[4].iter();
-        let mut iter_cb1 = iter_cb0.clone();
-        let mut iter_cr0 = blocks[5].iter();
-        let mut iter_cr1 = iter_cr0.clone();
-        for x in 0..2
+        self.upsample_row(&planes[0], y, out);
+        if let Some(levels) = &self.gray_levels
         {
-            let mut iter_y0 = blocks[x*2].iter();
-            let mut iter_y1 = blocks[x*2+1].iter();
-            for t in 0..64
+            for d in out.iter_mut()
             {
-                let cb = (if t & 8 == 0 { iter_cb0.next() } else { iter_cb1.next() }).unwrap();
-                let cr = (if t & 8 == 0 { iter_cr0.next() } else { iter_cr1.next() }).unwrap();
-                
-                if t & 4 == 0
-                {
-                    let y0 = iter_y0.next().unwrap();
-                    put_pixel!(out_buf, i, convert_func(*y0, *cb, *cr), c_stride);
-                    let y0 = iter_y0.next().unwrap();
-                    put_pixel!(out_buf, i, convert_func(*y0, *cb, *cr), c_stride);
-                }
-                else
-                {
-                    let y1 = iter_y1.next().unwrap();
-                    put_pixel!(out_buf, i, convert_func(*y1, *cb, *cr), c_stride);
-                    let y1 = iter_y1.next().unwrap();
-                    put_pixel!(out_buf, i, convert_func(*y1, *cb, *cr), c_stride);
-                }
-
-                if t & 7 == 7
-                {
-                    i += lf_stride;
-                }   
+                *d = levels[*d as usize];
             }
         }
     }
@@ -0,0 +1,871 @@
+//========================================================
+//  jpeg_simd.rs
+//
+//========================================================
+// SIMD versions of the IDCT, the fancy upsampling and the color conversion (x86_64)
+//
+// The instruction set is detected at run time, and each function reports what it
+// processed so that the caller finishes the rest (or everything) with the scalar code.
+// All the calculations are the same as the scalar versions, so the results are bit-exact.
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+
+// Instruction set used by the decoder
+#[allow(dead_code)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(PartialOrd)]
+#[derive(Debug)]
+pub enum JpegSimdLevel
+{
+    Scalar,     // No SIMD (portable code only)
+    Sse2,       // 128-bit
+    Avx2,       // 256-bit
+}
+
+const JPEG_SIMD_LEVEL_UNKNOWN: u8 = u8::MAX;
+
+static JPEG_SIMD_LEVEL: AtomicU8 = AtomicU8::new(JPEG_SIMD_LEVEL_UNKNOWN);
+
+#[allow(dead_code)]
+impl JpegSimdLevel
+{
+    // Best level supported by the CPU
+    pub fn detect() -> Self
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2")
+            {
+                return JpegSimdLevel::Avx2;
+            }
+            if is_x86_feature_detected!("sse2")
+            {
+                return JpegSimdLevel::Sse2;
+            }
+        }
+        JpegSimdLevel::Scalar
+    }
+
+    fn from_u8(val: u8) -> Self
+    {
+        match val
+        {
+            2 => JpegSimdLevel::Avx2,
+            1 => JpegSimdLevel::Sse2,
+            _ => JpegSimdLevel::Scalar,
+        }
+    }
+}
+
+// Level in use (detected at the first call)
+pub fn get_simd_level() -> JpegSimdLevel
+{
+    let val = JPEG_SIMD_LEVEL.load(Ordering::Relaxed);
+    if val != JPEG_SIMD_LEVEL_UNKNOWN
+    {
+        return JpegSimdLevel::from_u8(val);
+    }
+    let level = JpegSimdLevel::detect();
+    JPEG_SIMD_LEVEL.store(level as u8, Ordering::Relaxed);
+    level
+}
+
+// Restricts the level in use (e.g. Scalar to validate the SIMD versions)
+//
+// A level beyond the CPU support is lowered to the detected one.
+#[allow(dead_code)]
+pub fn set_simd_level(level: JpegSimdLevel)
+{
+    let level = if level > JpegSimdLevel::detect() { JpegSimdLevel::detect() } else { level };
+    JPEG_SIMD_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// Integer IDCT with dequantization (same as JpegIdctManager::idct_islow_scalar)
+//
+// Returns false if no SIMD version is available.
+pub fn idct_islow(coef: &mut [i16], qt: &[u16]) -> bool
+{
+    #[cfg(target_arch = "x86_64")]
+    {
+        match get_simd_level()
+        {
+            // Safety: the instruction sets have been detected
+            JpegSimdLevel::Avx2 => unsafe { x86::idct_islow_avx2(coef, qt) },
+            JpegSimdLevel::Sse2 => unsafe { x86::idct_islow_sse2(coef, qt) },
+            JpegSimdLevel::Scalar => return false,
+        }
+        true
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (coef, qt);
+        false
+    }
+}
+
+// Horizontal 2x fancy upsampling of the samples not affected by the edges
+//
+// Processes the samples in [1, next) and returns next (1 if nothing was processed).
+// `src` has only the samples inside the image.
+pub fn upsample_h2v1_fancy(src: &[u8], out: &mut [u8]) -> usize
+{
+    #[cfg(target_arch = "x86_64")]
+    {
+        match get_simd_level()
+        {
+            // Safety: the instruction sets have been detected
+            JpegSimdLevel::Avx2 => unsafe { x86::upsample_h2v1_fancy_avx2(src, out) },
+            JpegSimdLevel::Sse2 => unsafe { x86::upsample_h2v1_fancy_sse2(src, out, 1) },
+            JpegSimdLevel::Scalar => 1,
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (src, out);
+        1
+    }
+}
+
+// Horizontal and vertical 2x fancy upsampling from the nearer and further rows
+//
+// Same range as upsample_h2v1_fancy().
+pub fn upsample_h2v2_fancy(near: &[u8], far: &[u8], out: &mut [u8]) -> usize
+{
+    #[cfg(target_arch = "x86_64")]
+    {
+        match get_simd_level()
+        {
+            // Safety: the instruction sets have been detected
+            JpegSimdLevel::Avx2 => unsafe { x86::upsample_h2v2_fancy_avx2(near, far, out) },
+            JpegSimdLevel::Sse2 => unsafe { x86::upsample_h2v2_fancy_sse2(near, far, out, 1) },
+            JpegSimdLevel::Scalar => 1,
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (near, far, out);
+        1
+    }
+}
+
+// Integer YCbCr -> RGB conversion of a row into triplets
+//
+// `coef` and `y_offset` are the fixed-point parameters of JpegYCbCrConverter.
+// Returns the number of pixels converted from the beginning of the row.
+pub fn ycc_to_rgb_row(coef: &[i32; 5], y_offset: i32, y: &[u8], cb: &[u8], cr: &[u8], out: &mut [u8]) -> usize
+{
+    #[cfg(target_arch = "x86_64")]
+    {
+        match get_simd_level()
+        {
+            // Safety: the instruction sets have been detected
+            JpegSimdLevel::Avx2 => unsafe { x86::ycc_to_rgb_row_avx2(coef, y_offset, y, cb, cr, out) },
+            JpegSimdLevel::Sse2 => unsafe { x86::ycc_to_rgb_row_sse2(coef, y_offset, y, cb, cr, out, 0) },
+            JpegSimdLevel::Scalar => 0,
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (coef, y_offset, y, cb, cr, out);
+        0
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86
+{
+    use std::arch::x86_64::*;
+    use crate::jpeg_idct::idct_islow_1d;
+    use crate::jpeg_idct::JPEG_IDCT_CONST_BITS;
+    use crate::jpeg_idct::JPEG_IDCT_PASS1_BITS;
+    use crate::jpeg_color_converter::JPEG_COLOR_SCALE_BITS;
+    use crate::jpeg_color_converter::JPEG_COLOR_ONE_HALF;
+
+    const PASS1_SHIFT: i32 = JPEG_IDCT_CONST_BITS - JPEG_IDCT_PASS1_BITS;
+    const PASS2_SHIFT: i32 = JPEG_IDCT_CONST_BITS + JPEG_IDCT_PASS1_BITS + 3;
+
+    //----------------------------------------------------
+    // SSE2 (4 x i32 lanes)
+    //----------------------------------------------------
+    #[target_feature(enable = "sse2")]
+    fn add_sse2(a: __m128i, b: __m128i) -> __m128i
+    {
+        _mm_add_epi32(a, b)
+    }
+
+    #[target_feature(enable = "sse2")]
+    fn sub_sse2(a: __m128i, b: __m128i) -> __m128i
+    {
+        _mm_sub_epi32(a, b)
+    }
+
+    // Low 32 bits of the products (SSE2 has no _mm_mullo_epi32)
+    #[target_feature(enable = "sse2")]
+    fn mullo_sse2(a: __m128i, b: __m128i) -> __m128i
+    {
+        let even = _mm_mul_epu32(a, b);
+        let odd = _mm_mul_epu32(_mm_srli_epi64::<32>(a), _mm_srli_epi64::<32>(b));
+        _mm_unpacklo_epi32(
+            _mm_shuffle_epi32::<0b00_00_10_00>(even),
+            _mm_shuffle_epi32::<0b00_00_10_00>(odd)
+        )
+    }
+
+    #[target_feature(enable = "sse2")]
+    fn mul_sse2(a: __m128i, k: i32) -> __m128i
+    {
+        mullo_sse2(a, _mm_set1_epi32(k))
+    }
+
+    #[target_feature(enable = "sse2")]
+    fn shl_sse2(a: __m128i) -> __m128i
+    {
+        _mm_slli_epi32::<JPEG_IDCT_CONST_BITS>(a)
+    }
+
+    #[target_feature(enable = "sse2")]
+    fn descale_sse2<const N: i32>(a: __m128i) -> __m128i
+    {
+        _mm_srai_epi32::<N>(_mm_add_epi32(a, _mm_set1_epi32(1 << (N - 1))))
+    }
+
+    #[target_feature(enable = "sse2")]
+    fn transpose4x4_sse2(r: [__m128i; 4]) -> [__m128i; 4]
+    {
+        let t0 = _mm_unpacklo_epi32(r[0], r[1]);
+        let t1 = _mm_unpacklo_epi32(r[2], r[3]);
+        let t2 = _mm_unpackhi_epi32(r[0], r[1]);
+        let t3 = _mm_unpackhi_epi32(r[2], r[3]);
+        [
+            _mm_unpacklo_epi64(t0, t1), _mm_unpackhi_epi64(t0, t1),
+            _mm_unpacklo_epi64(t2, t3), _mm_unpackhi_epi64(t2, t3),
+        ]
+    }
+
+    // Transposes the 8x8 matrix held as [row][half] vectors
+    #[target_feature(enable = "sse2")]
+    fn transpose8x8_sse2(m: &[[__m128i; 2]; 8]) -> [[__m128i; 2]; 8]
+    {
+        let mut t = [[_mm_setzero_si128(); 2]; 8];
+        for g in 0..2
+        {
+            for h in 0..2
+            {
+                let block = transpose4x4_sse2(std::array::from_fn(|i| m[g * 4 + i][h]));
+                for i in 0..4
+                {
+                    t[h * 4 + i][g] = block[i];
+                }
+            }
+        }
+        t
+    }
+
+    #[target_feature(enable = "sse2")]
+    #[allow(clippy::needless_range_loop)]
+    pub unsafe fn idct_islow_sse2(coef: &mut [i16], qt: &[u16])
+    {
+        assert!(coef.len() >= 64 && qt.len() >= 64);
+        let zero = _mm_setzero_si128();
+
+        // Dequantization: rows of [x 0..3, x 4..7]
+        let mut v = [[zero; 2]; 8];
+        for y in 0..8
+        {
+            // Safety: the ranges have been checked
+            let (c, q) = unsafe
+            {
+                (
+                    _mm_loadu_si128(coef.as_ptr().add(y * 8) as *const __m128i),
+                    _mm_loadu_si128(qt.as_ptr().add(y * 8) as *const __m128i),
+                )
+            };
+            let c_lo = _mm_srai_epi32::<16>(_mm_unpacklo_epi16(c, c));
+            let c_hi = _mm_srai_epi32::<16>(_mm_unpackhi_epi16(c, c));
+            v[y] = [
+                mullo_sse2(c_lo, _mm_unpacklo_epi16(q, zero)),
+                mullo_sse2(c_hi, _mm_unpackhi_epi16(q, zero)),
+            ];
+        }
+
+        // Pass 1: columns (4 columns at once)
+        let mut ws = [[zero; 2]; 8];
+        for h in 0..2
+        {
+            let out = idct_islow_1d!(std::array::from_fn::<__m128i, 8, _>(|y| v[y][h]),
+                add_sse2, sub_sse2, mul_sse2, shl_sse2);
+            for y in 0..8
+            {
+                ws[y][h] = descale_sse2::<PASS1_SHIFT>(out[y]);
+            }
+        }
+
+        // Pass 2: rows (4 rows at once on the transposed matrix)
+        let ws = transpose8x8_sse2(&ws);
+        let mut res = [[zero; 2]; 8];
+        for g in 0..2
+        {
+            let out = idct_islow_1d!(std::array::from_fn::<__m128i, 8, _>(|x| ws[x][g]),
+                add_sse2, sub_sse2, mul_sse2, shl_sse2);
+            for x in 0..8
+            {
+                // Wraps into 10 bits as JpegIdctManager::range_limit()
+                let val = descale_sse2::<PASS2_SHIFT>(out[x]);
+                res[x][g] = _mm_srai_epi32::<22>(_mm_slli_epi32::<22>(val));
+            }
+        }
+        let res = transpose8x8_sse2(&res);
+
+        let offset = _mm_set1_epi16(128);
+        let max = _mm_set1_epi16(255);
+        for y in 0..8
+        {
+            let row = _mm_add_epi16(_mm_packs_epi32(res[y][0], res[y][1]), offset);
+            let row = _mm_min_epi16(_mm_max_epi16(row, zero), max);
+            // Safety: the range has been checked
+            unsafe { _mm_storeu_si128(coef.as_mut_ptr().add(y * 8) as *mut __m128i, row) };
+        }
+    }
+
+    // 8 samples at `pos` extended to 16 bits
+    #[target_feature(enable = "sse2")]
+    unsafe fn load8_sse2(src: &[u8], pos: usize) -> __m128i
+    {
+        debug_assert!(pos + 8 <= src.len());
+        let val = unsafe { _mm_loadl_epi64(src.as_ptr().add(pos) as *const __m128i) };
+        _mm_unpacklo_epi8(val, _mm_setzero_si128())
+    }
+
+    // Interleaves the even and odd outputs of 8 samples and stores them at out[x * 2]
+    #[target_feature(enable = "sse2")]
+    unsafe fn store_pairs_sse2(out: &mut [u8], x: usize, even: __m128i, odd: __m128i)
+    {
+        debug_assert!(x * 2 + 16 <= out.len());
+        let res = _mm_packus_epi16(_mm_unpacklo_epi16(even, odd), _mm_unpackhi_epi16(even, odd));
+        unsafe { _mm_storeu_si128(out.as_mut_ptr().add(x * 2) as *mut __m128i, res) };
+    }
+
+    // The samples from `x` are processed 8 at a time while x - 1 and x + 8 are inside the row.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn upsample_h2v1_fancy_sse2(src: &[u8], out: &mut [u8], mut x: usize) -> usize
+    {
+        let three = _mm_set1_epi16(3);
+        while x + 9 <= src.len() && (x + 8) * 2 <= out.len()
+        {
+            // Safety: the ranges have been checked
+            unsafe
+            {
+                let s = _mm_mullo_epi16(load8_sse2(src, x), three);
+                let even = _mm_add_epi16(_mm_add_epi16(s, load8_sse2(src, x - 1)), _mm_set1_epi16(1));
+                let odd = _mm_add_epi16(_mm_add_epi16(s, load8_sse2(src, x + 1)), _mm_set1_epi16(2));
+                store_pairs_sse2(out, x, _mm_srli_epi16::<2>(even), _mm_srli_epi16::<2>(odd));
+            }
+            x += 8;
+        }
+        x
+    }
+
+    // Column sums (3 * near + far) of 8 samples
+    #[target_feature(enable = "sse2")]
+    unsafe fn colsum8_sse2(near: &[u8], far: &[u8], pos: usize) -> __m128i
+    {
+        let n = unsafe { load8_sse2(near, pos) };
+        let f = unsafe { load8_sse2(far, pos) };
+        _mm_add_epi16(_mm_mullo_epi16(n, _mm_set1_epi16(3)), f)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn upsample_h2v2_fancy_sse2(near: &[u8], far: &[u8], out: &mut [u8], mut x: usize) -> usize
+    {
+        let len = near.len().min(far.len());
+        while x + 9 <= len && (x + 8) * 2 <= out.len()
+        {
+            // Safety: the ranges have been checked
+            unsafe
+            {
+                let s = _mm_mullo_epi16(colsum8_sse2(near, far, x), _mm_set1_epi16(3));
+                let even = _mm_add_epi16(_mm_add_epi16(s, colsum8_sse2(near, far, x - 1)), _mm_set1_epi16(8));
+                let odd = _mm_add_epi16(_mm_add_epi16(s, colsum8_sse2(near, far, x + 1)), _mm_set1_epi16(7));
+                store_pairs_sse2(out, x, _mm_srli_epi16::<4>(even), _mm_srli_epi16::<4>(odd));
+            }
+            x += 8;
+        }
+        x
+    }
+
+    // Converts the pixels from `x` 8 at a time
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn ycc_to_rgb_row_sse2(
+        coef: &[i32; 5],
+        y_offset: i32,
+        y: &[u8],
+        cb: &[u8],
+        cr: &[u8],
+        out: &mut [u8],
+        mut x: usize
+    ) -> usize
+    {
+        let len = y.len().min(cb.len()).min(cr.len()).min(out.len() / 3);
+        let k: [__m128i; 5] = std::array::from_fn(|i| _mm_set1_epi32(coef[i]));
+        let zero = _mm_setzero_si128();
+        let half = _mm_set1_epi32(JPEG_COLOR_ONE_HALF);
+        let widen = |v: __m128i| [_mm_unpacklo_epi16(v, zero), _mm_unpackhi_epi16(v, zero)];
+        while x + 8 <= len
+        {
+            // Safety: the ranges have been checked
+            let (ly, lcb, lcr) = unsafe
+            {
+                (
+                    _mm_sub_epi16(load8_sse2(y, x), _mm_set1_epi16(y_offset as i16)),
+                    _mm_sub_epi16(load8_sse2(cb, x), _mm_set1_epi16(128)),
+                    _mm_sub_epi16(load8_sse2(cr, x), _mm_set1_epi16(128)),
+                )
+            };
+            // Sign extension of the 16-bit differences
+            let (ly, lcb, lcr) = (widen(ly), widen(lcb), widen(lcr));
+            let mut rgb = [[zero; 2]; 3];
+            for i in 0..2
+            {
+                let ly = _mm_add_epi32(mullo_sse2(_mm_srai_epi32::<16>(_mm_slli_epi32::<16>(ly[i])), k[0]), half);
+                let lcb = _mm_srai_epi32::<16>(_mm_slli_epi32::<16>(lcb[i]));
+                let lcr = _mm_srai_epi32::<16>(_mm_slli_epi32::<16>(lcr[i]));
+                let r = _mm_add_epi32(ly, mullo_sse2(k[1], lcr));
+                let g = _mm_sub_epi32(_mm_sub_epi32(ly, mullo_sse2(k[2], lcb)), mullo_sse2(k[3], lcr));
+                let b = _mm_add_epi32(ly, mullo_sse2(k[4], lcb));
+                rgb[0][i] = _mm_srai_epi32::<JPEG_COLOR_SCALE_BITS>(r);
+                rgb[1][i] = _mm_srai_epi32::<JPEG_COLOR_SCALE_BITS>(g);
+                rgb[2][i] = _mm_srai_epi32::<JPEG_COLOR_SCALE_BITS>(b);
+            }
+
+            // Saturation to 0..255
+            let mut bytes = [[0_u8; 16]; 3];
+            for (c, dst) in rgb.iter().zip(bytes.iter_mut())
+            {
+                let packed = _mm_packs_epi32(c[0], c[1]);
+                let packed = _mm_packus_epi16(packed, packed);
+                // Safety: 16 bytes are written into the array
+                unsafe { _mm_storeu_si128(dst.as_mut_ptr() as *mut __m128i, packed) };
+            }
+            for (i, d) in out[x * 3..(x + 8) * 3].chunks_exact_mut(3).enumerate()
+            {
+                d[0] = bytes[0][i];
+                d[1] = bytes[1][i];
+                d[2] = bytes[2][i];
+            }
+            x += 8;
+        }
+        x
+    }
+
+    //----------------------------------------------------
+    // AVX2 (8 x i32 lanes)
+    //----------------------------------------------------
+    #[target_feature(enable = "avx2")]
+    fn add_avx2(a: __m256i, b: __m256i) -> __m256i
+    {
+        _mm256_add_epi32(a, b)
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn sub_avx2(a: __m256i, b: __m256i) -> __m256i
+    {
+        _mm256_sub_epi32(a, b)
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn mul_avx2(a: __m256i, k: i32) -> __m256i
+    {
+        _mm256_mullo_epi32(a, _mm256_set1_epi32(k))
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn shl_avx2(a: __m256i) -> __m256i
+    {
+        _mm256_slli_epi32::<JPEG_IDCT_CONST_BITS>(a)
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn descale_avx2<const N: i32>(a: __m256i) -> __m256i
+    {
+        _mm256_srai_epi32::<N>(_mm256_add_epi32(a, _mm256_set1_epi32(1 << (N - 1))))
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn transpose8x8_avx2(r: &[__m256i; 8]) -> [__m256i; 8]
+    {
+        let t: [__m256i; 8] = std::array::from_fn(|i|
+        {
+            let (a, b) = (r[(i / 2) * 2], r[(i / 2) * 2 + 1]);
+            if i & 1 == 0 { _mm256_unpacklo_epi32(a, b) } else { _mm256_unpackhi_epi32(a, b) }
+        });
+        let u = [
+            _mm256_unpacklo_epi64(t[0], t[2]), _mm256_unpackhi_epi64(t[0], t[2]),
+            _mm256_unpacklo_epi64(t[1], t[3]), _mm256_unpackhi_epi64(t[1], t[3]),
+            _mm256_unpacklo_epi64(t[4], t[6]), _mm256_unpackhi_epi64(t[4], t[6]),
+            _mm256_unpacklo_epi64(t[5], t[7]), _mm256_unpackhi_epi64(t[5], t[7]),
+        ];
+        [
+            _mm256_permute2x128_si256::<0x20>(u[0], u[4]),
+            _mm256_permute2x128_si256::<0x20>(u[1], u[5]),
+            _mm256_permute2x128_si256::<0x20>(u[2], u[6]),
+            _mm256_permute2x128_si256::<0x20>(u[3], u[7]),
+            _mm256_permute2x128_si256::<0x31>(u[0], u[4]),
+            _mm256_permute2x128_si256::<0x31>(u[1], u[5]),
+            _mm256_permute2x128_si256::<0x31>(u[2], u[6]),
+            _mm256_permute2x128_si256::<0x31>(u[3], u[7]),
+        ]
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn idct_islow_avx2(coef: &mut [i16], qt: &[u16])
+    {
+        assert!(coef.len() >= 64 && qt.len() >= 64);
+
+        // Dequantization: one row per vector
+        let v: [__m256i; 8] = std::array::from_fn(|y|
+        {
+            // Safety: the ranges have been checked
+            let (c, q) = unsafe
+            {
+                (
+                    _mm_loadu_si128(coef.as_ptr().add(y * 8) as *const __m128i),
+                    _mm_loadu_si128(qt.as_ptr().add(y * 8) as *const __m128i),
+                )
+            };
+            _mm256_mullo_epi32(_mm256_cvtepi16_epi32(c), _mm256_cvtepu16_epi32(q))
+        });
+
+        // Pass 1: columns (all the columns at once)
+        let out = idct_islow_1d!(v, add_avx2, sub_avx2, mul_avx2, shl_avx2);
+        let ws = transpose8x8_avx2(&out.map(|val| descale_avx2::<PASS1_SHIFT>(val)));
+
+        // Pass 2: rows (all the rows at once on the transposed matrix)
+        let out = idct_islow_1d!(ws, add_avx2, sub_avx2, mul_avx2, shl_avx2);
+        let res = transpose8x8_avx2(&out.map(|val|
+        {
+            // Wraps into 10 bits as JpegIdctManager::range_limit()
+            let val = descale_avx2::<PASS2_SHIFT>(val);
+            let val = _mm256_add_epi32(_mm256_srai_epi32::<22>(_mm256_slli_epi32::<22>(val)), _mm256_set1_epi32(128));
+            _mm256_min_epi32(_mm256_max_epi32(val, _mm256_setzero_si256()), _mm256_set1_epi32(255))
+        }));
+
+        for y in (0..8).step_by(2)
+        {
+            let rows = _mm256_permute4x64_epi64::<0b11_01_10_00>(_mm256_packs_epi32(res[y], res[y + 1]));
+            // Safety: the range has been checked
+            unsafe { _mm256_storeu_si256(coef.as_mut_ptr().add(y * 8) as *mut __m256i, rows) };
+        }
+    }
+
+    // 16 samples at `pos` extended to 16 bits
+    #[target_feature(enable = "avx2")]
+    unsafe fn load16_avx2(src: &[u8], pos: usize) -> __m256i
+    {
+        debug_assert!(pos + 16 <= src.len());
+        _mm256_cvtepu8_epi16(unsafe { _mm_loadu_si128(src.as_ptr().add(pos) as *const __m128i) })
+    }
+
+    // Interleaves the even and odd outputs of 16 samples and stores them at out[x * 2]
+    //
+    // The unpacking and the packing are both per 128-bit lane, so the order is kept.
+    #[target_feature(enable = "avx2")]
+    unsafe fn store_pairs_avx2(out: &mut [u8], x: usize, even: __m256i, odd: __m256i)
+    {
+        debug_assert!(x * 2 + 32 <= out.len());
+        let res = _mm256_packus_epi16(_mm256_unpacklo_epi16(even, odd), _mm256_unpackhi_epi16(even, odd));
+        unsafe { _mm256_storeu_si256(out.as_mut_ptr().add(x * 2) as *mut __m256i, res) };
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn upsample_h2v1_fancy_avx2(src: &[u8], out: &mut [u8]) -> usize
+    {
+        let three = _mm256_set1_epi16(3);
+        let mut x = 1;
+        while x + 17 <= src.len() && (x + 16) * 2 <= out.len()
+        {
+            // Safety: the ranges have been checked
+            unsafe
+            {
+                let s = _mm256_mullo_epi16(load16_avx2(src, x), three);
+                let even = _mm256_add_epi16(_mm256_add_epi16(s, load16_avx2(src, x - 1)), _mm256_set1_epi16(1));
+                let odd = _mm256_add_epi16(_mm256_add_epi16(s, load16_avx2(src, x + 1)), _mm256_set1_epi16(2));
+                store_pairs_avx2(out, x, _mm256_srli_epi16::<2>(even), _mm256_srli_epi16::<2>(odd));
+            }
+            x += 16;
+        }
+        // Safety: AVX2 implies SSE2
+        unsafe { upsample_h2v1_fancy_sse2(src, out, x) }
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn colsum16_avx2(near: &[u8], far: &[u8], pos: usize) -> __m256i
+    {
+        let n = unsafe { load16_avx2(near, pos) };
+        let f = unsafe { load16_avx2(far, pos) };
+        _mm256_add_epi16(_mm256_mullo_epi16(n, _mm256_set1_epi16(3)), f)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn upsample_h2v2_fancy_avx2(near: &[u8], far: &[u8], out: &mut [u8]) -> usize
+    {
+        let len = near.len().min(far.len());
+        let mut x = 1;
+        while x + 17 <= len && (x + 16) * 2 <= out.len()
+        {
+            // Safety: the ranges have been checked
+            unsafe
+            {
+                let s = _mm256_mullo_epi16(colsum16_avx2(near, far, x), _mm256_set1_epi16(3));
+                let even = _mm256_add_epi16(_mm256_add_epi16(s, colsum16_avx2(near, far, x - 1)), _mm256_set1_epi16(8));
+                let odd = _mm256_add_epi16(_mm256_add_epi16(s, colsum16_avx2(near, far, x + 1)), _mm256_set1_epi16(7));
+                store_pairs_avx2(out, x, _mm256_srli_epi16::<4>(even), _mm256_srli_epi16::<4>(odd));
+            }
+            x += 16;
+        }
+        // Safety: AVX2 implies SSE2
+        unsafe { upsample_h2v2_fancy_sse2(near, far, out, x) }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn ycc_to_rgb_row_avx2(
+        coef: &[i32; 5],
+        y_offset: i32,
+        y: &[u8],
+        cb: &[u8],
+        cr: &[u8],
+        out: &mut [u8]
+    ) -> usize
+    {
+        let len = y.len().min(cb.len()).min(cr.len()).min(out.len() / 3);
+        let k: [__m256i; 5] = std::array::from_fn(|i| _mm256_set1_epi32(coef[i]));
+        let half = _mm256_set1_epi32(JPEG_COLOR_ONE_HALF);
+        let max = _mm256_set1_epi32(255);
+        let mut x = 0;
+        while x + 8 <= len
+        {
+            // Safety: the ranges have been checked
+            let (ly, lcb, lcr) = unsafe
+            {
+                (
+                    _mm256_cvtepu8_epi32(_mm_loadl_epi64(y.as_ptr().add(x) as *const __m128i)),
+                    _mm256_cvtepu8_epi32(_mm_loadl_epi64(cb.as_ptr().add(x) as *const __m128i)),
+                    _mm256_cvtepu8_epi32(_mm_loadl_epi64(cr.as_ptr().add(x) as *const __m128i)),
+                )
+            };
+            let ly = _mm256_add_epi32(_mm256_mullo_epi32(k[0], _mm256_sub_epi32(ly, _mm256_set1_epi32(y_offset))), half);
+            let lcb = _mm256_sub_epi32(lcb, _mm256_set1_epi32(128));
+            let lcr = _mm256_sub_epi32(lcr, _mm256_set1_epi32(128));
+            let r = _mm256_add_epi32(ly, _mm256_mullo_epi32(k[1], lcr));
+            let g = _mm256_sub_epi32(_mm256_sub_epi32(ly, _mm256_mullo_epi32(k[2], lcb)), _mm256_mullo_epi32(k[3], lcr));
+            let b = _mm256_add_epi32(ly, _mm256_mullo_epi32(k[4], lcb));
+
+            // Clamp to 0..255
+            let mut vals = [[0_i32; 8]; 3];
+            for (c, dst) in [r, g, b].iter().zip(vals.iter_mut())
+            {
+                let c = _mm256_srai_epi32::<JPEG_COLOR_SCALE_BITS>(*c);
+                let c = _mm256_min_epi32(_mm256_max_epi32(c, _mm256_setzero_si256()), max);
+                // Safety: 32 bytes are written into the array
+                unsafe { _mm256_storeu_si256(dst.as_mut_ptr() as *mut __m256i, c) };
+            }
+            for (i, d) in out[x * 3..(x + 8) * 3].chunks_exact_mut(3).enumerate()
+            {
+                d[0] = vals[0][i] as u8;
+                d[1] = vals[1][i] as u8;
+                d[2] = vals[2][i] as u8;
+            }
+            x += 8;
+        }
+        x
+    }
+}
+
+//========================================================
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::Mutex;
+    use crate::jpeg_idct::JpegIdctManager;
+    use crate::jpeg_color_converter::JpegColorConversion;
+    use crate::jpeg_color_converter::JpegColorMatrix;
+    use crate::jpeg_color_converter::JpegColorRange;
+    use crate::jpeg_color_converter::JpegYCbCrConverter;
+
+    // The level is global: the tests which change it run one at a time.
+    static LEVEL_LOCK: Mutex<()> = Mutex::new(());
+
+    // Row lengths around the 8/16/32 lanes (plus the edge samples)
+    const ROW_LENGTHS: [usize; 16] = [1, 2, 3, 7, 8, 9, 10, 15, 16, 17, 18, 31, 32, 33, 34, 70];
+
+    // xorshift64
+    struct Random(u64);
+
+    impl Random
+    {
+        fn next(&mut self) -> u64
+        {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8>
+        {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    // Runs `f` at every level supported by the CPU and checks the results against the scalar one
+    fn assert_same_at_each_level<T: PartialEq + std::fmt::Debug>(f: impl Fn() -> T)
+    {
+        let _lock = LEVEL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_simd_level(JpegSimdLevel::Scalar);
+        let scalar = f();
+        for level in [JpegSimdLevel::Sse2, JpegSimdLevel::Avx2]
+        {
+            if level > JpegSimdLevel::detect()
+            {
+                continue;
+            }
+            set_simd_level(level);
+            assert!(get_simd_level() == level);
+            let result = f();
+            assert!(result == scalar, "{:?}: {:?} / {:?}", level, result, scalar);
+        }
+        set_simd_level(JpegSimdLevel::detect());
+    }
+
+    #[test]
+    fn idct_islow_equals_scalar()
+    {
+        let mut random = Random(0x2545F4914F6CDD1D);
+        let mut blocks: Vec<([i16; 64], [u16; 64])> = Vec::new();
+        for i in 0..300
+        {
+            // Typical / full 16-bit range of the coefficients and the quantizers
+            let (coef_bits, qt_bits) = [(11, 8), (16, 8), (11, 16), (16, 16)][i % 4];
+            let coef = std::array::from_fn(|_| (random.next() as i16) >> (16 - coef_bits));
+            let qt = std::array::from_fn(|_| ((random.next() as u16) >> (16 - qt_bits)).max(1));
+            blocks.push((coef, qt));
+        }
+        // Extremes, the DC only and the columns without AC terms
+        for (c, q) in [(i16::MIN, u16::MAX), (i16::MAX, u16::MAX), (-2048, 255), (2047, 255)]
+        {
+            blocks.push(([c; 64], [q; 64]));
+            blocks.push((std::array::from_fn(|i| if i == 0 { c } else { 0 }), [q; 64]));
+            blocks.push((std::array::from_fn(|i| if i < 8 { c } else { 0 }), [q; 64]));
+            blocks.push((std::array::from_fn(|i| if i % 2 == 0 { c } else { c / -2 }), [q; 64]));
+        }
+
+        assert_same_at_each_level(||
+            blocks.iter().map(|(coef, qt)|
+            {
+                let mut coef = *coef;
+                JpegIdctManager::idct_islow(&mut coef, qt);
+                coef
+            }).collect::<Vec<_>>()
+        );
+    }
+
+    // Horizontal 2x fancy upsampling as JpegSampler (SIMD, then the rest with the scalar code)
+    fn upsample_row(near: &[u8], far: Option<&[u8]>, out: &mut [u8])
+    {
+        let colsum = |x: usize| match far
+        {
+            Some(far) => near[x] as u16 * 3 + far[x] as u16,
+            None => near[x] as u16,
+        };
+        let (scale, bias) = if far.is_some() { (4, [8, 7]) } else { (2, [1, 2]) };
+        let next = match far
+        {
+            Some(far) => upsample_h2v2_fancy(near, far, out),
+            None => upsample_h2v1_fancy(near, out),
+        };
+        let last = near.len() - 1;
+        for x in std::iter::once(0).chain(next..near.len().min(out.len().div_ceil(2)))
+        {
+            let s = colsum(x) * 3;
+            out[x * 2] = ((s + colsum(x.saturating_sub(1)) + bias[0]) >> scale) as u8;
+            if x * 2 + 1 < out.len()
+            {
+                out[x * 2 + 1] = ((s + colsum((x + 1).min(last)) + bias[1]) >> scale) as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn fancy_upsampling_equals_scalar()
+    {
+        let mut random = Random(0x9E3779B97F4A7C15);
+        let mut rows = Vec::new();
+        for len in ROW_LENGTHS.iter().map(|&len| len + 2)
+        {
+            rows.push((random.bytes(len), random.bytes(len)));
+            // Saturated samples
+            rows.push((vec![255; len], vec![255; len]));
+            rows.push((vec![255; len], vec![0; len]));
+        }
+
+        for is_h2v2 in [false, true]
+        {
+            assert_same_at_each_level(||
+            {
+                let mut results = Vec::new();
+                for (near, far) in rows.iter()
+                {
+                    // Even and odd output widths
+                    for out_len in [near.len() * 2, near.len() * 2 - 1]
+                    {
+                        let mut out = vec![0; out_len];
+                        upsample_row(near, if is_h2v2 { Some(far) } else { None }, &mut out);
+                        results.push(out);
+                    }
+                }
+                results
+            });
+        }
+    }
+
+    #[test]
+    fn ycc_to_rgb_equals_scalar()
+    {
+        let mut random = Random(0xD1B54A32D192ED03);
+        let mut rows = Vec::new();
+        for len in ROW_LENGTHS
+        {
+            rows.push([random.bytes(len), random.bytes(len), random.bytes(len)]);
+        }
+        // Out-of-gamut extremes (clamped)
+        for y in [0, 255]
+        {
+            for (cb, cr) in [(0, 0), (0, 255), (255, 0), (255, 255)]
+            {
+                rows.push([vec![y; 40], vec![cb; 40], vec![cr; 40]]);
+            }
+        }
+
+        for matrix in [JpegColorMatrix::Bt601, JpegColorMatrix::Bt709, JpegColorMatrix::Bt2020]
+        {
+            for range in [JpegColorRange::Full, JpegColorRange::Limited]
+            {
+                let mut conversion = JpegColorConversion::new();
+                conversion.set_matrix(matrix);
+                conversion.set_range(range);
+                let converter = JpegYCbCrConverter::new(&conversion);
+                assert_same_at_each_level(||
+                    rows.iter().map(|[y, cb, cr]|
+                    {
+                        let mut out = vec![0; y.len() * 3];
+                        converter.convert_row_ycc(y, cb, cr, &mut out);
+                        out
+                    }).collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+}
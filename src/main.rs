@@ -4,6 +4,8 @@
 //========================================================
 use std::env;
 use std::fs::File;
+use std::io;
+use std::io::Read;
 use std::io::Write;
 use std::io::Result;
 
@@ -13,11 +15,18 @@ mod jpeg_sample_block;
 mod jpeg_huffman_table;
 mod jpeg_quantization_table;
 mod jpeg_idct;
+mod jpeg_simd;
+mod jpeg_idct_checker;
 mod jpeg_color_converter;
 mod jpeg_sampler;
 mod jpeg_frame_info;
+mod jpeg_info;
 mod jpeg_outbuffer_info;
+mod jpeg_row_sink;
+mod jpeg_planar_image;
+mod jpeg_pipeline;
 mod jpeg_control;
+mod jpeg_push_decoder;
 
 fn main() -> Result<()>
 {
@@ -26,13 +35,35 @@ fn main() -> Result<()>
 
     // Get first argument as
     let infilename: &String = args.get(1)
-        .expect("Please give a input file name as argument.");
+        .expect("Please give a input file name as argument (\"-\" for stdin).");
     println!("Filename: {}", infilename);
 
+    // Reads the whole input into memory
+    let mut data = Vec::new();
+    if infilename == "-"
+    {
+        io::stdin().read_to_end(&mut data)?;
+    }
+    else
+    {
+        File::open(infilename)?.read_to_end(&mut data)?;
+    }
+
+    // Header-only information
+    match jpeg_info::probe(&data)
+    {
+        Some(info) => info.dump(),
+        None =>
+        {
+            println!("Not a JPEG file.");
+            return Ok(());
+        }
+    }
+
     // Initializes JpegFile structure
     let mut jpeg = jpeg_control::JpegControl::new();
 
-    jpeg.read_from_file(infilename);
+    jpeg.read_from_slice(&data);
     jpeg.parse_markers();
 
     // Image width/height